@@ -41,12 +41,13 @@ impl Renderer for VGRenderer {
 
     fn draw_element(&mut self, ctx: &mut Self::Context, tag: &str, style: &Style, x: f32, y: f32, width: f32, height: f32) {
         let color = style.background_color.unwrap_or([0, 80, 0, 255]);
+        let alpha = (color[3] as f32 * style.opacity.unwrap_or(1.0)) as u8;
         let mut p = Path::new();
         p.rect(x,y, width, height);
         p.close();
         ctx.fill_path(
             &p,
-            &Paint::color(Color::rgba(color[0], color[1], color[2], color[3]))
+            &Paint::color(Color::rgba(color[0], color[1], color[2], alpha))
         );
     }
 
@@ -0,0 +1,174 @@
+//! # Uhr
+//!
+//! Zeitquelle für Frames, `create_timer`, Zeitpläne und Animationen. `SystemClock` ist die
+//! echte Uhr, mit `ManualClock` laufen Tests und Wiederholungen ohne Warten und reproduzierbar.
+//! Dauern (Frames, Animationen, Timer, `set_interval`) misst `instant`, das nicht springt,
+//! wenn die Systemzeit verstellt wird, nur `every` und `at` richten sich nach `now`:
+//!
+//! ```ignore
+//! let clock = Rc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+//! let mut dynamite = Dynamite::with_clock(html, renderer, EngineOptions::default(), clock.clone())?;
+//! dynamite.run_frame(&mut ctx, size)?;
+//! clock.advance(Duration::from_millis(16));
+//! dynamite.run_frame(&mut ctx, size)?;   // on:update(0.016, 0.016)
+//! ```
+
+use std::{cell::Cell, time::{Duration, Instant, SystemTime}};
+
+pub trait Clock {
+    /// Uhrzeit für `every` und `at`.
+    fn now(&self) -> SystemTime;
+
+    /// Monotone Zeit für Dauern.
+    fn instant(&self) -> Instant;
+
+    /// Abstand der lokalen Zeit zu UTC in Sekunden zum Zeitpunkt `at`, gilt für `at` und
+    /// `every`. Je Zeitpunkt, damit Zeitpläne einer Umstellung auf Sommerzeit folgen.
+    fn utc_offset(&self, _at: SystemTime) -> i64 {
+        0
+    }
+}
+
+/// Die Systemzeit. Uhrzeiten gelten in der Zeitzone des Systems samt Sommerzeit,
+/// mit `utc_offset` stattdessen in einem festen Abstand zu UTC.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock {
+    pub utc_offset: Option<i64>,
+}
+
+impl SystemClock {
+    /// Uhrzeiten in UTC, unabhängig von der Zeitzone des Systems.
+    pub fn utc() -> Self {
+        Self { utc_offset: Some(0) }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc_offset(&self, at: SystemTime) -> i64 {
+        self.utc_offset.unwrap_or_else(|| local_utc_offset(at))
+    }
+}
+
+/// Abstand der Systemzeitzone zu UTC, aus `TZ` bzw. `/etc/localtime`.
+#[cfg(unix)]
+// `c_long` ist nicht auf allen Plattformen `i64`
+#[allow(clippy::useless_conversion)]
+fn local_utc_offset(at: SystemTime) -> i64 {
+    let secs = at.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let Ok(secs) = libc::time_t::try_from(secs) else {
+        return 0;
+    };
+    // SAFETY: `tm` ist ein reines C-Struct, `localtime_r` schreibt nur hinein
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return 0;
+    }
+    i64::from(tm.tm_gmtoff)
+}
+
+/// Ohne `localtime_r` gilt UTC.
+#[cfg(not(unix))]
+fn local_utc_offset(_at: SystemTime) -> i64 {
+    0
+}
+
+/// Steht still, bis sie mit `advance` oder `set` weitergestellt wird. Der Abstand zu UTC ist fest.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Cell<SystemTime>,
+    instant: Cell<Instant>,
+    utc_offset: i64,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self { now: Cell::new(start), instant: Cell::new(Instant::now()), utc_offset: 0 }
+    }
+
+    pub fn with_utc_offset(mut self, utc_offset: i64) -> Self {
+        self.utc_offset = utc_offset;
+        self
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+        self.instant.set(self.instant.get() + duration);
+    }
+
+    /// Verstellt nur die Uhrzeit, wie bei der Systemuhr laufen Dauern unverändert weiter.
+    pub fn set(&self, now: SystemTime) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.now.get()
+    }
+
+    fn instant(&self) -> Instant {
+        self.instant.get()
+    }
+
+    fn utc_offset(&self, _at: SystemTime) -> i64 {
+        self.utc_offset
+    }
+}
+
+/// Zeit eines Frames in Sekunden, wird an `on:update` übergeben.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameTime {
+    /// Seit dem ersten Frame.
+    pub time: f64,
+    /// Seit dem vorigen Frame, im ersten Frame 0.
+    pub delta: f64,
+}
+
+/// Berechnet die `FrameTime` aus den Zeitpunkten der Frames.
+#[derive(Debug, Default)]
+pub struct FrameTimer {
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl FrameTimer {
+    pub fn tick(&mut self, now: Instant) -> FrameTime {
+        let first = *self.first.get_or_insert(now);
+        let since = |earlier: Instant| now.saturating_duration_since(earlier).as_secs_f64();
+        let frame = FrameTime {
+            time: since(first),
+            delta: self.last.map(since).unwrap_or(0.0),
+        };
+        self.last = Some(now);
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_time_from_manual_clock() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let mut frames = FrameTimer::default();
+        assert_eq!(frames.tick(clock.instant()), FrameTime { time: 0.0, delta: 0.0 });
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(frames.tick(clock.instant()), FrameTime { time: 0.25, delta: 0.25 });
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(frames.tick(clock.instant()), FrameTime { time: 0.75, delta: 0.5 });
+
+        // die Uhrzeit zurückstellen ändert keine Dauern
+        clock.set(SystemTime::UNIX_EPOCH);
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(frames.tick(clock.instant()), FrameTime { time: 1.0, delta: 0.25 });
+    }
+}
@@ -0,0 +1,242 @@
+//! # Komponenten
+//!
+//! Templates können andere Templates einbinden:
+//!
+//! ```html
+//! <template id="badge">
+//!     <span class="badge {{ kind }}"><slot>?</slot> {{ label }}</span>
+//! </template>
+//!
+//! <template id="weather_card">
+//!     <div class="card">
+//!         <use template="badge" kind="hot" label="{{ temp }}°C">Temperatur</use>
+//!         <slot name="footer"></slot>
+//!     </div>
+//! </template>
+//!
+//! <use template="weather_card" id="berlin">
+//!     <p slot="footer">Berlin</p>
+//! </use>
+//! ```
+//!
+//! - Die übrigen Attribute von `<use>` sind die Props der Instanz. `{{ }}` und `:name` Props
+//!   werden mit den Werten außerhalb der Komponente ausgewertet.
+//! - Innerhalb der Instanz sind nur die Props sichtbar (`{{ label }}`, in Lua-Ausdrücken als
+//!   Variable `label`), Lua-Globals bleiben erreichbar.
+//! - Die Kinder von `<use>` ersetzen das `<slot>` mit passendem `name`, Kinder ohne `slot`
+//!   Attribut das `<slot>` ohne Namen. Ohne Inhalt bleiben die Kinder des `<slot>` stehen.
+//!   Eingesetzter Inhalt rendert mit den Werten außerhalb der Komponente.
+//! - `id`, `class`, `style`, `slot` sowie `for-each` und `if` samt Zusatzattributen gehen an
+//!   das Wurzelelement der Instanz.
+
+use std::{collections::HashMap, rc::Rc};
+
+use im::Vector;
+
+use crate::{template::{AttrBinding, LuaEval, TemplateValues}, vdom::{render_texts_in_subtree, ElementNode, VNode}};
+
+pub const USE_TAG: &str = "use";
+pub const TEMPLATE_ATTR: &str = "template";
+pub const SLOT_TAG: &str = "slot";
+pub const SLOT_ATTR: &str = "slot";
+
+/// Attribute von `<use>`, die an das Wurzelelement der Instanz gehen statt in die Props.
+const FORWARDED_ATTRS: &[&str] = &["id", "slot", "for-each", "as", "key", "if", "else-if", "else"];
+
+/// Schützt vor Templates, die sich gegenseitig einbinden.
+const MAX_COMPONENT_DEPTH: usize = 32;
+
+/// Props einer Komponenten-Instanz.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Props {
+    pub values: HashMap<String, String>,
+    /// Props mit `{{ }}` oder `:name`, ausgewertet mit den Werten außerhalb der Komponente.
+    pub bindings: HashMap<String, AttrBinding>,
+}
+
+impl Props {
+    /// Berechnet die Werte innerhalb der Komponente.
+    pub fn resolve(&self, outer: &TemplateValues, eval: Option<LuaEval>) -> TemplateValues {
+        let mut values: TemplateValues = self.values
+            .iter()
+            .map(|(name, value)| (name.clone(), value.as_str().into()))
+            .collect();
+        for (name, binding) in &self.bindings {
+            if let Some(Some(value)) = binding.evaluate(outer, eval) {
+                values.insert(name.clone(), value.into());
+            }
+        }
+        values
+    }
+
+    pub fn lua_exprs(&self) -> Vec<&str> {
+        self.bindings.values().flat_map(|binding| binding.lua_exprs()).collect()
+    }
+}
+
+/// Gültigkeitsbereich der Template-Werte an einem Element.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Scope {
+    /// Bei eingesetztem Slot-Inhalt die Anzahl der Komponenten, deren Werte übersprungen werden.
+    pub slot_depth: usize,
+    /// Gesetzt an der Wurzel einer Komponenten-Instanz.
+    pub props: Option<Rc<Props>>,
+}
+
+/// Ersetzt alle `<use>` Elemente im Teilbaum durch Instanzen ihres Templates.
+pub fn expand_components(node: &mut VNode, templates: &HashMap<String, VNode>) -> Result<(), String> {
+    expand(node, templates, 0)
+}
+
+fn expand(node: &mut VNode, templates: &HashMap<String, VNode>, depth: usize) -> Result<(), String> {
+    let VNode::Element(el) = node else {
+        return Ok(());
+    };
+    if el.tag == USE_TAG {
+        *node = instantiate(el.clone(), templates, depth)?;
+        return Ok(());
+    }
+    for child in el.children.iter_mut() {
+        expand(child, templates, depth)?;
+    }
+    Ok(())
+}
+
+fn instantiate(mut usage: ElementNode, templates: &HashMap<String, VNode>, depth: usize) -> Result<VNode, String> {
+    let name = usage.attrs.remove(TEMPLATE_ATTR).ok_or("<use> without template attribute")?;
+    if depth >= MAX_COMPONENT_DEPTH {
+        return Err(format!("components nested too deeply, is template '{}' recursive?", name));
+    }
+    let mut instance = templates.get(&name).cloned().ok_or_else(|| format!("unknown template '{}'", name))?;
+    instance.generate_new_ids();
+
+    // Slot-Inhalt gehört zur äußeren Ebene, seine Komponenten werden dort aufgelöst
+    let mut content: Vec<VNode> = std::mem::take(&mut usage.children).into_iter().collect();
+    for child in content.iter_mut() {
+        expand(child, templates, depth)?;
+    }
+    let mut assigned: HashMap<Option<String>, Vec<VNode>> = HashMap::new();
+    for mut child in content {
+        let slot = match &mut child {
+            VNode::Element(el) => el.attrs.remove(SLOT_ATTR),
+            VNode::Text(_) => None,
+        };
+        escape_slot(&mut child);
+        assigned.entry(slot).or_default().push(child);
+    }
+    fill_slots(&mut instance, &mut assigned);
+
+    let VNode::Element(root) = &mut instance else {
+        return Err(format!("template '{}' has no root element", name));
+    };
+    if usage.id.is_some() {
+        root.id = usage.id.take();
+    }
+    if let Some(class) = usage.attrs.remove("class") {
+        let classes = match root.attrs.get("class") {
+            Some(own) => format!("{} {}", own, class),
+            None => class,
+        };
+        root.attrs.insert("class".to_string(), classes);
+    }
+    if root.bindings.contains_key("style") {
+        root.static_style.extend(usage.inline_style.clone());
+    }
+    root.inline_style.extend(usage.inline_style.drain());
+    for attr in FORWARDED_ATTRS {
+        if let Some(value) = usage.attrs.remove(*attr) {
+            root.attrs.insert(attr.to_string(), value);
+        }
+    }
+    root.scope = Scope {
+        slot_depth: usage.scope.slot_depth,
+        props: Some(Rc::new(Props { values: usage.attrs, bindings: usage.bindings })),
+    };
+
+    // Komponenten im Template erst nach dem Einsetzen, weitergereichte Slots landen so im Inneren
+    if let VNode::Element(root) = &mut instance {
+        for child in root.children.iter_mut() {
+            expand(child, templates, depth + 1)?;
+        }
+    }
+
+    // vorläufig ohne äußere Werte, Lua-Ausdrücke folgen im ersten Frame
+    render_texts_in_subtree(&mut instance, &HashMap::new(), None);
+    Ok(instance)
+}
+
+/// Markiert Slot-Inhalt, damit er mit den Werten eine Komponente weiter außen rendert.
+fn escape_slot(node: &mut VNode) {
+    match node {
+        VNode::Element(el) => el.scope.slot_depth += 1,
+        VNode::Text(text) => text.slot_depth += 1,
+    }
+}
+
+/// Ersetzt die `<slot>` Elemente im Template durch den zugewiesenen Inhalt.
+/// Eingesetzter Inhalt wird nicht weiter durchsucht.
+fn fill_slots(node: &mut VNode, assigned: &mut HashMap<Option<String>, Vec<VNode>>) {
+    let VNode::Element(el) = node else {
+        return;
+    };
+    let mut children = Vector::new();
+    for mut child in std::mem::take(&mut el.children) {
+        match &child {
+            VNode::Element(slot) if slot.tag == SLOT_TAG => {
+                let name = slot.attrs.get("name").cloned();
+                match assigned.remove(&name) {
+                    Some(content) => children.extend(content),
+                    None => {
+                        // Platzhalter-Inhalt des Slots, er gehört zum Template
+                        for mut fallback in slot.children.clone() {
+                            fill_slots(&mut fallback, assigned);
+                            children.push_back(fallback);
+                        }
+                    }
+                }
+            }
+            _ => {
+                fill_slots(&mut child, assigned);
+                children.push_back(child);
+            }
+        }
+    }
+    el.children = children;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_html_to_vdom;
+    use crate::vdom::texts;
+
+    #[test]
+    fn test_props_and_slots() {
+        let html = r#"<html><body>
+            <template id="badge"><span class="badge">{{ label }} <slot>leer</slot></span></template>
+            <template id="card"><div class="card"><use template="badge" label="{{ city }}"><slot name="extra"></slot></use></div></template>
+            <use template="badge" id="a" label="Hot">Text</use>
+            <use template="badge" id="b" label="Cold"></use>
+            <use template="card" id="c" city="Berlin"><b slot="extra">{{ city }}</b></use>
+        </body></html>"#;
+        let vdom = parse_html_to_vdom(html).unwrap();
+
+        let out = texts(&vdom.root);
+        // der Slot-Inhalt in `card` sieht die Werte außerhalb, dort ist `city` unbekannt
+        assert_eq!(out, vec!["Hot", "Text", "Cold", "leer", "Berlin", ""]);
+
+        let Some(VNode::Element(a)) = vdom.find_element_by_id("a") else { panic!("a not found") };
+        assert_eq!(a.tag, "span");
+        assert_eq!(a.attrs.get("class").map(String::as_str), Some("badge"));
+    }
+
+    #[test]
+    fn test_recursive_templates_fail() {
+        let html = r#"<html><body>
+            <template id="loop"><div><use template="loop"></use></div></template>
+            <use template="loop"></use>
+        </body></html>"#;
+        assert!(parse_html_to_vdom(html).is_err());
+        assert!(parse_html_to_vdom(r#"<html><body><use template="missing"></use></body></html>"#).is_err());
+    }
+}
@@ -1,16 +1,310 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, rc::Rc};
 
+use im::Vector;
+use log::warn;
 use ulid::Ulid;
 
-use crate::{vdom::VNode, parse_html_to_vdom};
+use crate::{styles::{cascade::{self, CascadeContext, RestyleScope}, selector::{element_count, ElementPath, Selector}, stylesheet::StyleSheet}, template::{LuaEval, TemplateValues}, vdom::{self, render_texts_in_subtree, VNode}, parse_html_to_vdom};
 
+/// Ein interaktiver Zustand, auf den Selektoren per Pseudoklasse reagieren können.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFlag {
+    Hover,
+    Active,
+    Focus,
+}
+
+impl StateFlag {
+    pub fn from_str(name: &str) -> Option<Self> {
+        match name.trim().trim_start_matches(':').to_lowercase().as_str() {
+            "hover" => Some(StateFlag::Hover),
+            "active" => Some(StateFlag::Active),
+            "focus" => Some(StateFlag::Focus),
+            _ => None,
+        }
+    }
+}
+
+/// Zustands-Flags eines Elements für `:hover`, `:active` und `:focus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ElementState {
+    pub hover: bool,
+    pub active: bool,
+    pub focus: bool,
+}
+
+impl ElementState {
+    pub fn get(&self, flag: StateFlag) -> bool {
+        match flag {
+            StateFlag::Hover => self.hover,
+            StateFlag::Active => self.active,
+            StateFlag::Focus => self.focus,
+        }
+    }
+
+    pub fn set(&mut self, flag: StateFlag, value: bool) {
+        match flag {
+            StateFlag::Hover => self.hover = value,
+            StateFlag::Active => self.active = value,
+            StateFlag::Focus => self.focus = value,
+        }
+    }
+}
+
+
+/// Attribute, mit denen ein Element zur Listen-Vorlage wird.
+const FOR_EACH_ATTR: &str = "for-each";
+const AS_ATTR: &str = "as";
+const KEY_ATTR: &str = "key";
+
+/// Ein Eintrag einer `for-each` Liste, so wie er aus der Lua-Tabelle gelesen wurde.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListItem {
+    /// Identifiziert den Eintrag über Änderungen der Tabelle hinweg.
+    pub key: String,
+    /// Werte für die `{{ }}` Platzhalter der Instanz.
+    pub values: TemplateValues,
+}
+
+/// Eine im VDOM stehende Instanz einer Listen-Vorlage.
+#[derive(Debug, Clone)]
+pub struct ListInstance {
+    pub key: String,
+    pub internal_id: Ulid,
+    pub values: TemplateValues,
+}
+
+/// Ein Element mit `for-each="expr"`. Das Element selbst steht nicht im Baum, statt dessen
+/// wird für jeden Eintrag der Tabelle `expr` eine Kopie an seiner Stelle eingefügt.
+///
+/// Listen und bedingte Blöcke innerhalb der Vorlage entstehen für jede Instanz neu, ihre
+/// Ausdrücke sehen die Werte des Eintrags. Ein `if` auf demselben Element filtert die Einträge.
+#[derive(Debug, Clone)]
+pub struct ListBinding {
+    /// Interne ID der Vorlage, identifiziert die Liste.
+    pub internal_id: Ulid,
+    /// Lua-Ausdruck, der die Tabelle liefert.
+    pub expr: String,
+    /// Name eines Eintrags in den Platzhaltern (`as`), Standard `item`.
+    pub alias: String,
+    /// Feld, das einen Eintrag identifiziert (`key`), sonst `id` oder die Position.
+    pub key_field: Option<String>,
+    /// Lua-Ausdruck aus `if`, nur Einträge, für die er zutrifft, bekommen eine Instanz.
+    pub filter: Option<String>,
+    /// Element, in dem die Instanzen stehen.
+    pub parent: Ulid,
+    /// Geschwister direkt vor der Liste, `None` wenn sie am Anfang steht.
+    /// Kann auch die ID einer anderen Liste sein.
+    pub anchor: Option<Ulid>,
+    /// Vorlage ohne die `for-each` Attribute.
+    pub prototype: VNode,
+    /// Aktuelle Instanzen in Reihenfolge der Tabelle.
+    pub instances: Vec<ListInstance>,
+    /// Listen-Instanz, deren Werte der Ausdruck sieht, `None` außerhalb von Listen.
+    pub scope: Option<Ulid>,
+}
+
+impl ListBinding {
+    /// Erzeugt die Liste, wenn `node` ein Element mit `for-each` ist.
+    fn from_node(node: &VNode, parent: Ulid, anchor: Option<Ulid>) -> Option<Self> {
+        let VNode::Element(el) = node else {
+            return None;
+        };
+        let expr = el.attrs.get(FOR_EACH_ATTR)?.trim().to_string();
+
+        let mut prototype = el.clone();
+        prototype.attrs.remove(FOR_EACH_ATTR);
+        let alias = prototype.attrs.remove(AS_ATTR).unwrap_or_else(|| "item".to_string());
+        let key_field = prototype.attrs.remove(KEY_ATTR);
+        let filter = prototype.attrs.remove(IF_ATTR).map(|f| f.trim().to_string());
+        // eine HTML-ID wäre in jeder Instanz doppelt
+        prototype.id = None;
+
+        Some(Self {
+            internal_id: el.internal_id,
+            expr,
+            alias,
+            key_field,
+            filter,
+            parent,
+            anchor,
+            prototype: VNode::Element(prototype),
+            instances: Vec::new(),
+            scope: None,
+        })
+    }
 
+    /// Erzeugt eine neue Instanz der Vorlage mit eigenen IDs. Listen und bedingte Blöcke
+    /// darin landen in `lists` bzw. `conditionals` und sehen die Werte der Instanz.
+    fn instantiate(
+        &self,
+        values: &TemplateValues,
+        eval: Option<LuaEval>,
+        lists: &mut Vec<ListBinding>,
+        conditionals: &mut Vec<ConditionalBlock>,
+    ) -> VNode {
+        let mut node = self.prototype.clone();
+        node.generate_new_ids();
+        render_texts_in_subtree(&mut node, values, eval);
+
+        let (first_list, first_block) = (lists.len(), conditionals.len());
+        collect_dynamic(&mut node, lists, conditionals);
+        let scope = Some(*node.get_internal_id());
+        lists[first_list..].iter_mut().for_each(|l| l.scope = scope);
+        conditionals[first_block..].iter_mut().for_each(|b| b.scope = scope);
+        node
+    }
+}
+
+/// Attribute für bedingte Darstellung, `else-if` und `else` folgen direkt auf ein `if`.
+const IF_ATTR: &str = "if";
+const ELSE_IF_ATTR: &str = "else-if";
+const ELSE_ATTR: &str = "else";
+
+/// Ein Zweig eines bedingten Blocks.
+#[derive(Debug, Clone)]
+pub struct ConditionalBranch {
+    /// Lua-Ausdruck aus `if` bzw. `else-if`, `None` für `else`.
+    pub condition: Option<String>,
+    /// Der Knoten des Zweigs. Solange der Zweig aktiv ist, steht der aktuelle Stand im Baum.
+    pub node: VNode,
+}
+
+/// Eine Folge von Geschwistern mit `if`, `else-if` und `else`. Im Baum steht höchstens
+/// der Knoten des aktiven Zweigs, die übrigen werden hier aufbewahrt.
+#[derive(Debug, Clone)]
+pub struct ConditionalBlock {
+    pub internal_id: Ulid,
+    /// Element, in dem der aktive Zweig steht.
+    pub parent: Ulid,
+    /// Geschwister direkt vor dem Block, siehe `ListBinding::anchor`.
+    pub anchor: Option<Ulid>,
+    pub branches: Vec<ConditionalBranch>,
+    /// Index des Zweigs, der gerade im Baum steht.
+    pub active: Option<usize>,
+    /// Listen-Instanz, deren Werte die Bedingungen sehen, siehe `ListBinding::scope`.
+    pub scope: Option<Ulid>,
+}
+
+/// Liefert das Attribut für bedingte Darstellung, das der Knoten trägt.
+fn conditional_attr(node: &VNode) -> Option<&'static str> {
+    let VNode::Element(el) = node else {
+        return None;
+    };
+    [IF_ATTR, ELSE_IF_ATTR, ELSE_ATTR]
+        .into_iter()
+        .find(|attr| el.attrs.contains_key(*attr))
+}
+
+impl ConditionalBlock {
+    /// Baut den Block aus einer Kette `if`, `else-if`..., `else`. Dynamische Knoten innerhalb
+    /// der Zweige landen in `lists` bzw. `conditionals`.
+    fn from_chain(
+        chain: Vec<VNode>,
+        parent: Ulid,
+        anchor: Option<Ulid>,
+        lists: &mut Vec<ListBinding>,
+        conditionals: &mut Vec<ConditionalBlock>,
+    ) -> Self {
+        let branches = chain
+            .into_iter()
+            .map(|mut node| {
+                let mut condition = None;
+                if let VNode::Element(el) = &mut node {
+                    condition = el.attrs.remove(IF_ATTR).or_else(|| el.attrs.remove(ELSE_IF_ATTR));
+                    el.attrs.remove(ELSE_ATTR);
+                }
+                collect_dynamic(&mut node, lists, conditionals);
+                ConditionalBranch { condition, node }
+            })
+            .collect();
+
+        Self {
+            internal_id: Ulid::new(),
+            parent,
+            anchor,
+            branches,
+            active: None,
+            scope: None,
+        }
+    }
+}
+
+/// Entfernt alle Elemente mit `for-each`, `if`, `else-if` und `else` aus dem Baum und legt sie
+/// als Listen bzw. bedingte Blöcke ab. Ihre Knoten fügt der VDOM erst beim Update ein.
+fn collect_dynamic(node: &mut VNode, lists: &mut Vec<ListBinding>, conditionals: &mut Vec<ConditionalBlock>) {
+    let VNode::Element(el) = node else {
+        return;
+    };
+    let parent = el.internal_id;
+
+    let mut children = Vector::new();
+    let mut anchor = None;
+    let mut pending = std::mem::take(&mut el.children).into_iter().peekable();
+    while let Some(mut child) = pending.next() {
+        if let Some(list) = ListBinding::from_node(&child, parent, anchor) {
+            anchor = Some(list.internal_id);
+            lists.push(list);
+        } else if conditional_attr(&child) == Some(IF_ATTR) {
+            let mut chain = vec![child];
+            while let Some(next) = pending.peek() {
+                match conditional_attr(next) {
+                    Some(ELSE_IF_ATTR) => chain.push(pending.next().unwrap()),
+                    Some(ELSE_ATTR) => {
+                        chain.push(pending.next().unwrap());
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+
+            // äußere Blöcke vor den inneren, damit beim Update zuerst das Elternelement erscheint
+            let mut nested = Vec::new();
+            let block = ConditionalBlock::from_chain(chain, parent, anchor, lists, &mut nested);
+            anchor = Some(block.internal_id);
+            conditionals.push(block);
+            conditionals.extend(nested);
+        } else {
+            if conditional_attr(&child).is_some() {
+                warn!("else-if / else without preceding if, rendering unconditionally");
+            }
+            collect_dynamic(&mut child, lists, conditionals);
+            anchor = Some(*child.get_internal_id());
+            children.push_back(child);
+        }
+    }
+    el.children = children;
+}
 
 #[derive(Clone)]
 pub struct VDom {
     pub root: VNode,
     pub templates: HashMap<String, VNode>,
     pub id_map: HashMap<String, Ulid>,
+    pub stylesheet: Rc<StyleSheet>,
+    /// Zuletzt gesetzte Viewport-Größe, `None` bis zum ersten Frame.
+    pub viewport: Option<(f32, f32)>,
+    /// Ergebnis der Media Queries des Stylesheets für `viewport`.
+    pub media_state: Vec<bool>,
+    /// Globale Custom Properties, z. B. über `set_css_var` aus Lua gesetzt.
+    pub css_vars: HashMap<String, String>,
+    /// Markiert, dass die Styles vor dem nächsten Layout neu berechnet werden müssen.
+    pub styles_dirty: bool,
+    /// Zustands-Flags je Element, nur Elemente mit gesetztem Flag sind enthalten.
+    pub states: HashMap<Ulid, ElementState>,
+    /// Elemente, deren Teilbaum vor dem nächsten Layout neu gestylt werden muss.
+    pub restyle_roots: HashSet<Ulid>,
+    /// Alle `for-each` Listen des Dokuments.
+    pub lists: Vec<ListBinding>,
+    /// Alle bedingten Blöcke, äußere vor inneren.
+    pub conditionals: Vec<ConditionalBlock>,
+    /// Zuletzt gerenderte Template-Werte je Teilbaum (`set_text`, Listen-Instanzen),
+    /// damit von Lua abhängige Bindungen in jedem Frame neu ausgewertet werden können.
+    pub render_contexts: HashMap<Ulid, TemplateValues>,
+    /// Es wurde ein Knoten eingefügt, der außerhalb des Baums Änderungen an `state`
+    /// verpasst haben kann (Zweig eines Blocks, neues Element). Beim nächsten
+    /// `render_lua_bindings` werden daher alle Bindungen ausgewertet.
+    pub bindings_stale: bool,
 }
 
 impl VDom {
@@ -18,14 +312,277 @@ impl VDom {
         parse_html_to_vdom(html)
     }
 
+    pub fn with_stylesheet(
+        root: VNode,
+        templates: HashMap<String, VNode>,
+        id_map: HashMap<String, Ulid>,
+        stylesheet: StyleSheet,
+    ) -> Self {
+        let media_state = stylesheet.evaluate_media(None);
+        let mut root = root;
+        let mut lists = Vec::new();
+        let mut conditionals = Vec::new();
+        collect_dynamic(&mut root, &mut lists, &mut conditionals);
+        Self {
+            root,
+            templates,
+            id_map,
+            stylesheet: Rc::new(stylesheet),
+            viewport: None,
+            media_state,
+            css_vars: HashMap::new(),
+            styles_dirty: false,
+            states: HashMap::new(),
+            restyle_roots: HashSet::new(),
+            lists,
+            conditionals,
+            render_contexts: HashMap::new(),
+            bindings_stale: false,
+        }
+    }
+
+    /// Setzt die Viewport-Größe und wertet die Media Queries neu aus.
+    ///
+    /// Gibt `true` zurück, wenn sich dabei das Ergebnis mindestens einer Query geändert hat
+    /// und die Styles daher neu berechnet werden müssen.
+    pub fn set_viewport(&mut self, width: f32, height: f32) -> bool {
+        if self.viewport == Some((width, height)) {
+            return false;
+        }
+        self.viewport = Some((width, height));
+
+        let media_state = self.stylesheet.evaluate_media(self.viewport);
+        if media_state != self.media_state {
+            self.media_state = media_state;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn restyle_scope(&mut self, scope: &RestyleScope) {
+        let ctx = CascadeContext {
+            stylesheet: &self.stylesheet,
+            media_state: &self.media_state,
+            variables: &self.css_vars,
+            states: &self.states,
+        };
+        cascade::restyle(&mut self.root, &ctx, scope);
+    }
+
+    /// Berechnet die Styles aller Elemente aus Stylesheet und Inline-Styles neu.
+    pub fn restyle(&mut self) {
+        self.restyle_scope(&RestyleScope::All);
+        self.styles_dirty = false;
+        self.restyle_roots.clear();
+    }
+
+    /// Berechnet die Styles nur neu, wenn seit der letzten Berechnung etwas geändert wurde.
+    /// Wurden nur einzelne Elemente markiert, werden nur deren Teilbäume neu gestylt.
+    pub fn restyle_if_needed(&mut self) {
+        if self.styles_dirty {
+            self.restyle();
+        } else if !self.restyle_roots.is_empty() {
+            let roots = std::mem::take(&mut self.restyle_roots);
+            let scope = RestyleScope::subtrees(&self.root, roots);
+            self.restyle_scope(&scope);
+        }
+    }
+
+    /// Markiert den Teilbaum eines Elements für die nächste Style-Berechnung.
+    pub fn mark_for_restyle(&mut self, id: Ulid) {
+        self.restyle_roots.insert(id);
+    }
+
+    pub fn element_state(&self, id: &Ulid) -> ElementState {
+        self.states.get(id).copied().unwrap_or_default()
+    }
+
+    /// Setzt ein Zustands-Flag eines Elements und markiert es zum Neu-Stylen.
+    ///
+    /// `:focus` kann nur ein Element gleichzeitig haben, ein vorher fokussiertes Element
+    /// verliert den Fokus. Gibt `true` zurück, wenn sich ein Zustand geändert hat.
+    pub fn set_element_state(&mut self, id: &Ulid, flag: StateFlag, value: bool) -> bool {
+        if self.element_state(id).get(flag) == value {
+            return false;
+        }
+
+        if flag == StateFlag::Focus && value {
+            let focused: Vec<Ulid> = self.states
+                .iter()
+                .filter(|(other, state)| *other != id && state.focus)
+                .map(|(other, _)| *other)
+                .collect();
+            for other in focused {
+                self.set_element_state(&other, StateFlag::Focus, false);
+            }
+        }
+
+        let state = self.states.entry(*id).or_default();
+        state.set(flag, value);
+        if *state == ElementState::default() {
+            self.states.remove(id);
+        }
+
+        self.mark_for_restyle(*id);
+        true
+    }
+
+    /// Setzt eine globale Custom Property, `value = None` entfernt sie wieder.
+    pub fn set_css_var(&mut self, name: &str, value: Option<String>) {
+        let name = cascade::custom_property_name(name);
+        let changed = match value {
+            Some(value) => self.css_vars.insert(name, value.clone()) != Some(value),
+            None => self.css_vars.remove(&name).is_some(),
+        };
+        if changed {
+            self.styles_dirty = true;
+        }
+    }
+
+    /// Liefert den Wert einer globalen Custom Property, wie er am Root-Element berechnet wurde.
+    pub fn get_css_var(&mut self, name: &str) -> Option<String> {
+        self.restyle_if_needed();
+        let name = cascade::custom_property_name(name);
+        self.root.get_style().custom_properties.get(&name).cloned()
+    }
+
+    /// Element mit der HTML-ID, auch wenn es gerade in einem ausgeblendeten Zweig steht.
     pub fn find_element_by_id(&self, id: &str) -> Option<&VNode> {
-        self.id_map.get(id).and_then(|id| self.root.find_by_internal_id(id))
+        self.id_map.get(id).and_then(|id| self.find_node(id))
+    }
+
+    /// Knoten im Baum oder, solange sein Zweig ausgeblendet ist, im aufbewahrten Knoten
+    /// des bedingten Blocks. Änderungen daran erscheinen, sobald der Zweig aktiv wird.
+    pub fn find_node(&self, id: &Ulid) -> Option<&VNode> {
+        self.root.find_by_internal_id(id).or_else(|| {
+            self.conditionals.iter().find_map(|block| {
+                block.branches
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| Some(*index) != block.active)
+                    .find_map(|(_, branch)| branch.node.find_by_internal_id(id))
+            })
+        })
+    }
+
+    /// Wie `find_node`, veränderbar.
+    pub fn find_node_mut(&mut self, id: &Ulid) -> Option<&mut VNode> {
+        if self.root.find_by_internal_id(id).is_some() {
+            return self.root.find_by_internal_id_mut(id);
+        }
+        self.conditionals.iter_mut().find_map(|block| {
+            let active = block.active;
+            block.branches
+                .iter_mut()
+                .enumerate()
+                .filter(|(index, _)| Some(*index) != active)
+                .find_map(|(_, branch)| branch.node.find_by_internal_id_mut(id))
+        })
     }
 
     pub fn find_element_by_internal_id(&self, id: &Ulid) -> Option<&VNode> {
         self.root.find_by_internal_id(id)
     }
 
+    /// Alle Elemente, auf die einer der kommagetrennten Selektoren passt, in Dokumentreihenfolge.
+    ///
+    /// Mit `scope` nur Nachfahren dieses Elements. Wie bei `querySelectorAll` im Browser
+    /// dürfen die Selektoren trotzdem Vorfahren außerhalb prüfen.
+    pub fn query_selector_all(&self, selectors: &str, scope: Option<&Ulid>) -> Result<Vec<Ulid>, String> {
+        let parsed = Selector::parse_list(selectors)
+            .filter(|list| list.iter().all(|s| s.pseudo_element.is_none()))
+            .ok_or_else(|| format!("invalid selector '{}'", selectors))?;
+        let mut found = Vec::new();
+        if let VNode::Element(root) = &self.root {
+            let path = ElementPath::new(root, None).with_state(self.element_state(&root.internal_id));
+            self.collect_matches(&path, &parsed, scope, scope.is_none(), &mut found);
+        }
+        Ok(found)
+    }
+
+    fn collect_matches(
+        &self,
+        path: &ElementPath,
+        selectors: &[Selector],
+        scope: Option<&Ulid>,
+        in_scope: bool,
+        found: &mut Vec<Ulid>,
+    ) {
+        let el = path.element;
+        if in_scope && selectors.iter().any(|s| s.matches(path)) {
+            found.push(el.internal_id);
+        }
+        let children_in_scope = in_scope || scope == Some(&el.internal_id);
+        let count = element_count(&el.children);
+        let elements = el.children.iter().filter_map(|child| match child {
+            VNode::Element(child) => Some(child),
+            VNode::Text(_) => None,
+        });
+        for (index, child) in elements.enumerate() {
+            let child_path = ElementPath::new(child, Some(path))
+                .with_position(index, count)
+                .with_state(self.element_state(&child.internal_id));
+            self.collect_matches(&child_path, selectors, scope, children_in_scope, found);
+        }
+    }
+
+    /// Setzt ein Attribut eines Elements im Baum, siehe `ElementNode::set_attr`.
+    /// Selektoren können vom Attribut abhängen, das Element wird daher neu gestylt.
+    pub fn set_attribute(&mut self, id: &Ulid, name: &str, value: Option<String>) -> Result<(), String> {
+        let Some(VNode::Element(el)) = self.find_node_mut(id) else {
+            return Err("element not found in vdom".to_string());
+        };
+        let old_id = el.id.clone();
+        if !el.set_attr(name, value) {
+            return Ok(());
+        }
+
+        let new_id = el.id.clone();
+        if old_id != new_id {
+            if let Some(old_id) = old_id.filter(|old_id| self.id_map.get(old_id) == Some(id)) {
+                self.id_map.remove(&old_id);
+            }
+            if let Some(new_id) = new_id {
+                self.id_map.insert(new_id, *id);
+            }
+        }
+        self.mark_for_restyle(*id);
+        Ok(())
+    }
+
+    /// Die internen IDs aller Knoten im Baum.
+    pub fn tree_ids(&self) -> HashSet<Ulid> {
+        let mut ids = HashSet::new();
+        collect_ids(&self.root, &mut ids);
+        ids
+    }
+
+    /// Interne ID des Eltern-Elements, `None` für das Root-Element und unbekannte IDs.
+    pub fn parent_of(&self, id: &Ulid) -> Option<Ulid> {
+        self.root.ancestors_of(id)?.last().copied()
+    }
+
+    /// Interne IDs der Element-Kinder, Text-Knoten zählen nicht.
+    pub fn element_children(&self, id: &Ulid) -> Vec<Ulid> {
+        match self.root.find_by_internal_id(id) {
+            Some(VNode::Element(el)) => el
+                .children
+                .iter()
+                .filter(|child| matches!(child, VNode::Element(_)))
+                .map(|child| *child.get_internal_id())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Das Element-Geschwister im Abstand `offset`, `1` ist das nächste, `-1` das vorige.
+    pub fn sibling_of(&self, id: &Ulid, offset: isize) -> Option<Ulid> {
+        let siblings = self.element_children(&self.parent_of(id)?);
+        let index = siblings.iter().position(|sibling| sibling == id)?;
+        siblings.get(index.checked_add_signed(offset)?).copied()
+    }
+
     pub fn create_element_from_template(&self, template_id: &str) -> Option<VNode> {
         let mut template_node = self.templates.get(template_id).cloned();
 
@@ -39,18 +596,312 @@ impl VDom {
     }
 
     pub fn add_element(&mut self, target_id: &str, child: VNode) -> Result<Ulid, String> {
-        let target_ulid = self.id_map.get(target_id).ok_or("target id not found")?;
-        let target = self.root.find_by_internal_id_mut(target_ulid).ok_or("target not found")?;
-        if let VNode::Element(el) = target {
-            let child_id = child.get_internal_id().clone();
+        let target_ulid = *self.id_map.get(target_id).ok_or("target id not found")?;
+        let child_id = child.get_internal_id().clone();
+
+        let last = match self.root.find_by_internal_id(&target_ulid) {
+            Some(VNode::Element(el)) => el.children.last().map(|c| *c.get_internal_id()),
+            Some(_) => return Err("target is not an element".to_string()),
+            None => return Err("target not found".to_string()),
+        };
+
+        // ein Element mit `for-each` wird zur Liste, die Instanzen folgen beim nächsten Update
+        let anchor = last.map(|id| self.list_of_instance(&id).unwrap_or(id));
+        if let Some(list) = ListBinding::from_node(&child, target_ulid, anchor) {
+            self.lists.push(list);
+            return Ok(child_id);
+        }
+
+        // ebenso ein Element mit `if`, es erscheint erst wenn die Bedingung zutrifft
+        let mut child = child;
+        let mut lists = Vec::new();
+        let mut conditionals = Vec::new();
+        if conditional_attr(&child) == Some(IF_ATTR) {
+            let block = ConditionalBlock::from_chain(vec![child], target_ulid, anchor, &mut lists, &mut conditionals);
+            self.conditionals.push(block);
+            self.lists.extend(lists);
+            self.conditionals.extend(conditionals);
+            return Ok(child_id);
+        }
+        collect_dynamic(&mut child, &mut lists, &mut conditionals);
+        self.lists.extend(lists);
+        self.conditionals.extend(conditionals);
+
+        if let Some(VNode::Element(el)) = self.root.find_by_internal_id_mut(&target_ulid) {
             el.children.push_back(child);
-            Ok(child_id)
-        } else {
-            Err("target is not an element".to_string())
         }
+        self.bindings_stale = true;
+        // Geschwister können sich durch :last-child / :nth-child ändern
+        self.mark_for_restyle(target_ulid);
+        Ok(child_id)
+    }
+
+    /// Rendert einen Teilbaum mit neuen Template-Werten, z. B. nach `set_text`.
+    /// Die Werte bleiben für `render_lua_bindings` gespeichert.
+    pub fn render_subtree(&mut self, id: &Ulid, values: &TemplateValues, eval: Option<LuaEval>) -> Result<(), String> {
+        let node = self.find_node_mut(id).ok_or("node not found in vdom")?;
+        if render_texts_in_subtree(node, values, eval) {
+            self.mark_for_restyle(*id);
+        }
+        self.render_contexts.insert(*id, values.clone());
+        Ok(())
+    }
+
+    /// Wertet die von Lua abhängigen Platzhalter (`{{= }}`) und Attribut-Bindungen (`:name`)
+    /// neu aus, deren Ausdrücke laut `needs` betroffen sind, nach `bindings_stale` alle.
+    /// Elemente mit geänderten Attributen werden neu gestylt.
+    pub fn render_lua_bindings(&mut self, eval: LuaEval, needs: &dyn Fn(&str) -> bool) {
+        let all = |_: &str| true;
+        let needs: &dyn Fn(&str) -> bool = if std::mem::take(&mut self.bindings_stale) { &all } else { needs };
+        let mut changed = Vec::new();
+        vdom::render_lua_bindings(&mut self.root, &self.render_contexts, &HashMap::new(), eval, needs, &mut changed);
+        for id in changed {
+            self.mark_for_restyle(id);
+        }
+    }
+
+    /// Liefert die ID der Liste, zu der eine Instanz gehört.
+    fn list_of_instance(&self, id: &Ulid) -> Option<Ulid> {
+        self.lists
+            .iter()
+            .find(|list| list.instances.iter().any(|i| i.internal_id == *id))
+            .map(|list| list.internal_id)
+    }
+
+    /// Position in `children` direkt hinter `anchor`. Ist der Anker selbst eine Liste oder ein
+    /// bedingter Block, zählt dessen Ende.
+    fn anchor_position(&self, anchor: Option<Ulid>, children: &Vector<VNode>) -> usize {
+        let Some(anchor) = anchor else {
+            return 0;
+        };
+        if let Some(list) = self.lists.iter().find(|l| l.internal_id == anchor) {
+            return self.list_position(list, children) + list.instances.len();
+        }
+        if let Some(block) = self.conditionals.iter().find(|b| b.internal_id == anchor) {
+            return self.conditional_position(block, children) + block.active.is_some() as usize;
+        }
+        children
+            .iter()
+            .position(|c| *c.get_internal_id() == anchor)
+            .map(|pos| pos + 1)
+            .unwrap_or(children.len())
+    }
+
+    /// Position in `children`, an der die Instanzen der Liste stehen bzw. eingefügt werden.
+    fn list_position(&self, list: &ListBinding, children: &Vector<VNode>) -> usize {
+        if let Some(first) = list.instances.first() {
+            if let Some(pos) = children.iter().position(|c| *c.get_internal_id() == first.internal_id) {
+                return pos;
+            }
+        }
+        self.anchor_position(list.anchor, children)
+    }
+
+    /// Position in `children`, an der der aktive Zweig steht bzw. eingefügt wird.
+    fn conditional_position(&self, block: &ConditionalBlock, children: &Vector<VNode>) -> usize {
+        if let Some(active) = block.active {
+            let id = block.branches[active].node.get_internal_id();
+            if let Some(pos) = children.iter().position(|c| c.get_internal_id() == id) {
+                return pos;
+            }
+        }
+        self.anchor_position(block.anchor, children)
+    }
+
+    /// Schaltet einen bedingten Block auf den Zweig `active` um, `None` blendet ihn aus.
+    ///
+    /// Der bisher aktive Knoten wird samt aller Änderungen im Block aufbewahrt und behält
+    /// seine interne ID. Steht das Elternelement gerade nicht im Baum (z. B. in einem inaktiven
+    /// Zweig), passiert nichts. Gibt `true` zurück, wenn sich der Baum geändert hat.
+    pub fn set_conditional(&mut self, block_id: &Ulid, active: Option<usize>) -> Result<bool, String> {
+        let index = self.conditionals
+            .iter()
+            .position(|b| b.internal_id == *block_id)
+            .ok_or("conditional block not found")?;
+        if self.conditionals[index].active == active {
+            return Ok(false);
+        }
+        if active.is_some_and(|a| a >= self.conditionals[index].branches.len()) {
+            return Err("conditional branch out of range".to_string());
+        }
+
+        let parent_id = self.conditionals[index].parent;
+        let mut children = match self.root.find_by_internal_id(&parent_id) {
+            Some(VNode::Element(parent)) => parent.children.clone(),
+            _ => return Ok(false),
+        };
+        let position = self.conditional_position(&self.conditionals[index], &children);
+
+        if let Some(old) = self.conditionals[index].active {
+            let old_id = *self.conditionals[index].branches[old].node.get_internal_id();
+            if let Some(pos) = children.iter().position(|c| *c.get_internal_id() == old_id) {
+                self.conditionals[index].branches[old].node = children.remove(pos);
+            }
+            self.states.remove(&old_id);
+        }
+        if let Some(new) = active {
+            let node = self.conditionals[index].branches[new].node.clone();
+            children.insert(position.min(children.len()), node);
+            self.bindings_stale = true;
+        }
+
+        if let Some(VNode::Element(parent)) = self.root.find_by_internal_id_mut(&parent_id) {
+            parent.children = children;
+        }
+        self.conditionals[index].active = active;
+        self.mark_for_restyle(parent_id);
+        Ok(true)
+    }
+
+    /// Gleicht die Instanzen einer Liste mit den Einträgen der Tabelle ab.
+    ///
+    /// Instanzen werden über ihren Schlüssel wiederverwendet, behalten also ihre interne ID
+    /// samt Zustand; nur neue Einträge werden aus der Vorlage erzeugt. Gibt `true` zurück,
+    /// wenn sich der Baum geändert hat.
+    ///
+    /// Steht das Elternelement gerade nicht im Baum, bleibt die Liste unverändert.
+    /// `eval` wertet `{{= }}` Ausdrücke in den Platzhaltern aus.
+    pub fn update_list(&mut self, list_id: &Ulid, items: Vec<ListItem>, eval: Option<LuaEval>) -> Result<bool, String> {
+        let index = self.lists
+            .iter()
+            .position(|l| l.internal_id == *list_id)
+            .ok_or("list not found")?;
+
+        // Instanzen einer inneren Liste sehen auch die Werte der äußeren Instanz
+        let mut items = items;
+        if let Some(scope) = self.lists[index].scope.and_then(|id| self.render_contexts.get(&id)) {
+            for item in &mut items {
+                let mut values = scope.clone();
+                values.extend(std::mem::take(&mut item.values));
+                item.values = values;
+            }
+        }
+
+        let unchanged = {
+            let instances = &self.lists[index].instances;
+            instances.len() == items.len()
+                && instances.iter().zip(&items).all(|(i, item)| i.key == item.key && i.values == item.values)
+        };
+        if unchanged {
+            return Ok(false);
+        }
+
+        let parent_id = self.lists[index].parent;
+        let children = match self.root.find_by_internal_id(&parent_id) {
+            Some(VNode::Element(parent)) => parent.children.clone(),
+            // das Elternelement steht in einem inaktiven Zweig
+            _ => return Ok(false),
+        };
+        let position = self.list_position(&self.lists[index], &children);
+
+        // alte Instanzen herausnehmen, nach Schlüssel für die Wiederverwendung
+        let old_instances = std::mem::take(&mut self.lists[index].instances);
+        let old_ids: HashSet<Ulid> = old_instances.iter().map(|i| i.internal_id).collect();
+        let mut old_nodes: HashMap<Ulid, VNode> = HashMap::new();
+        let mut remaining = Vector::new();
+        for child in children {
+            if old_ids.contains(child.get_internal_id()) {
+                old_nodes.insert(*child.get_internal_id(), child);
+            } else {
+                remaining.push_back(child);
+            }
+        }
+        let mut reusable: HashMap<String, (VNode, TemplateValues)> = HashMap::new();
+        for instance in old_instances {
+            if let Some(node) = old_nodes.remove(&instance.internal_id) {
+                reusable.entry(instance.key).or_insert((node, instance.values));
+            }
+        }
+
+        let list = &self.lists[index];
+        let mut lists = Vec::new();
+        let mut conditionals = Vec::new();
+        let mut instances = Vec::with_capacity(items.len());
+        let mut nodes = Vec::with_capacity(items.len());
+        for item in items {
+            let node = match reusable.remove(&item.key) {
+                Some((mut node, values)) => {
+                    if values != item.values {
+                        render_texts_in_subtree(&mut node, &item.values, eval);
+                    }
+                    node
+                }
+                None => list.instantiate(&item.values, eval, &mut lists, &mut conditionals),
+            };
+            instances.push(ListInstance {
+                key: item.key,
+                internal_id: *node.get_internal_id(),
+                values: item.values,
+            });
+            nodes.push(node);
+        }
+
+        // entfernte Instanzen verlieren ihre Zustands-Flags, Template-Werte und inneren Listen
+        self.forget_subtrees(reusable.values().map(|(node, _)| node));
+        let index = self.lists
+            .iter()
+            .position(|l| l.internal_id == *list_id)
+            .ok_or("list not found")?;
+        for instance in &instances {
+            self.render_contexts.insert(instance.internal_id, instance.values.clone());
+        }
+
+        let mut children = remaining;
+        let tail = children.split_off(position.min(children.len()));
+        children.extend(nodes);
+        children.append(tail);
+
+        if let Some(VNode::Element(parent)) = self.root.find_by_internal_id_mut(&parent_id) {
+            parent.children = children;
+        }
+        self.lists[index].instances = instances;
+        self.lists.extend(lists);
+        self.conditionals.extend(conditionals);
+        self.mark_for_restyle(parent_id);
+        Ok(true)
     }
 
+    /// Template-Werte, die Ausdrücke einer Liste oder eines Blocks mit `scope` sehen.
+    pub fn scope_values(&self, scope: Option<Ulid>) -> TemplateValues {
+        scope
+            .and_then(|id| self.render_contexts.get(&id))
+            .cloned()
+            .unwrap_or_default()
+    }
 
+    /// Vergisst Listen und bedingte Blöcke aus entfernten Teilbäumen samt Zustands-Flags und
+    /// Template-Werten ihrer Knoten, auch die in aufbewahrten Zweigen.
+    fn forget_subtrees<'a>(&mut self, nodes: impl IntoIterator<Item = &'a VNode>) {
+        let mut ids = HashSet::new();
+        for node in nodes {
+            collect_ids(node, &mut ids);
+        }
+        loop {
+            let (gone, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.conditionals)
+                .into_iter()
+                .partition(|b| ids.contains(&b.parent));
+            self.conditionals = kept;
+            if gone.is_empty() {
+                break;
+            }
+            for block in &gone {
+                block.branches.iter().for_each(|b| collect_ids(&b.node, &mut ids));
+            }
+        }
+        self.lists.retain(|l| !ids.contains(&l.parent));
+        for id in &ids {
+            self.states.remove(id);
+            self.render_contexts.remove(id);
+        }
+    }
+}
+
+/// Sammelt die internen IDs aller Knoten im Teilbaum.
+fn collect_ids(node: &VNode, ids: &mut HashSet<Ulid>) {
+    ids.insert(*node.get_internal_id());
+    if let VNode::Element(el) = node {
+        el.children.iter().for_each(|c| collect_ids(c, ids));
+    }
 }
 
 pub trait FindBy {
@@ -60,3 +911,223 @@ pub trait FindBy {
 pub trait FindByIdMut {
     fn find_by_internal_id_mut(&mut self, id: &Ulid) -> Option<&mut VNode>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::TemplateValue;
+    use crate::vdom::texts;
+
+    const HTML: &str = r#"
+        <html><body>
+            <div id="list">
+                <h1>Wetter</h1>
+                <div class="card" for-each="weather_data" as="w" key="city"><p>{{ w.city }}: {{ temp }}</p></div>
+                <p>Ende</p>
+            </div>
+        </body></html>
+    "#;
+
+    fn item(city: &str, temp: &str) -> ListItem {
+        let mut values = HashMap::new();
+        values.insert("city".to_string(), city.into());
+        values.insert("w.city".to_string(), city.into());
+        values.insert("temp".to_string(), temp.into());
+        ListItem { key: city.to_string(), values }
+    }
+
+    #[test]
+    fn test_update_list_keeps_keyed_instances() {
+        let mut vdom = VDom::new(HTML).unwrap();
+        assert_eq!(vdom.lists.len(), 1);
+        assert_eq!(vdom.lists[0].alias, "w");
+        assert_eq!(texts(&vdom.root), vec!["Wetter", "Ende"]);
+
+        let list_id = vdom.lists[0].internal_id;
+        assert!(vdom.update_list(&list_id, vec![item("Berlin", "12"), item("Hamburg", "9")], None).unwrap());
+        assert_eq!(texts(&vdom.root), vec!["Wetter", "Berlin: 12", "Hamburg: 9", "Ende"]);
+        let hamburg = vdom.lists[0].instances[1].internal_id;
+
+        assert!(!vdom.update_list(&list_id, vec![item("Berlin", "12"), item("Hamburg", "9")], None).unwrap());
+
+        assert!(vdom.update_list(&list_id, vec![item("Hamburg", "10"), item("Köln", "14")], None).unwrap());
+        assert_eq!(texts(&vdom.root), vec!["Wetter", "Hamburg: 10", "Köln: 14", "Ende"]);
+        assert_eq!(vdom.lists[0].instances[0].internal_id, hamburg);
+
+        assert!(vdom.update_list(&list_id, Vec::new(), None).unwrap());
+        assert_eq!(texts(&vdom.root), vec!["Wetter", "Ende"]);
+    }
+
+    #[test]
+    fn test_reordered_instances_are_moved() {
+        fn ops(op: &vdom::DiffOp, out: &mut Vec<String>) {
+            match op {
+                vdom::DiffOp::Composite(inner) => inner.iter().for_each(|op| ops(op, out)),
+                vdom::DiffOp::PatchChild(_, inner) => ops(inner, out),
+                vdom::DiffOp::Replace(..) => out.push("replace".to_string()),
+                vdom::DiffOp::MoveChild { from, to } => out.push(format!("move {} {}", from, to)),
+                vdom::DiffOp::AddChild(index, _) => out.push(format!("add {}", index)),
+                vdom::DiffOp::RemoveChild(index) => out.push(format!("remove {}", index)),
+                _ => {}
+            }
+        }
+
+        let mut vdom = VDom::new(HTML).unwrap();
+        let list_id = vdom.lists[0].internal_id;
+        vdom.update_list(&list_id, vec![item("Berlin", "12"), item("Hamburg", "9")], None).unwrap();
+        let before = vdom.root.clone();
+
+        vdom.update_list(&list_id, vec![item("Köln", "14"), item("Hamburg", "9"), item("Berlin", "12")], None).unwrap();
+        let mut found = Vec::new();
+        ops(&vdom::diff_vnode(&before, &vdom.root).unwrap(), &mut found);
+        assert_eq!(found, vec!["add 1", "move 3 2"]);
+
+        // das Diff führt den alten Baum in den neuen über
+        let patched = vdom::apply_patch(&before, &vdom::diff_vnode(&before, &vdom.root).unwrap());
+        assert!(vdom::diff_vnode(&patched, &vdom.root).is_none());
+    }
+
+    #[test]
+    fn test_nested_dynamics_in_list_instances() {
+        let mut vdom = VDom::new(r#"
+            <html><body>
+                <div id="list">
+                    <div for-each="weather_data" as="w" key="city">
+                        <h2>{{ w.city }}</h2>
+                        <span if="w.city == 'Berlin'">Hauptstadt</span>
+                        <p for-each="w.days" as="d">{{ w.city }} {{ d.name }}</p>
+                    </div>
+                </div>
+            </body></html>
+        "#).unwrap();
+        assert_eq!(vdom.lists.len(), 1);
+
+        let mut berlin = item("Berlin", "12");
+        berlin.values.insert("w.days.1.name".to_string(), "Mo".into());
+        let list_id = vdom.lists[0].internal_id;
+        vdom.update_list(&list_id, vec![berlin, item("Hamburg", "9")], None).unwrap();
+
+        // je Instanz eine innere Liste und ein Block, die die Werte der Instanz sehen
+        assert_eq!(vdom.lists.len(), 3);
+        assert_eq!(vdom.conditionals.len(), 2);
+        let berlin_id = vdom.lists[0].instances[0].internal_id;
+        let inner = vdom.lists.iter().find(|l| l.scope == Some(berlin_id)).unwrap().internal_id;
+        assert_eq!(vdom.scope_values(Some(berlin_id)).get("w.days.1.name"), Some(&TemplateValue::from("Mo")));
+
+        let mut day = HashMap::new();
+        day.insert("d.name".to_string(), "Mo".into());
+        vdom.update_list(&inner, vec![ListItem { key: "1".to_string(), values: day }], None).unwrap();
+        let block = vdom.conditionals.iter().find(|b| b.scope == Some(berlin_id)).unwrap().internal_id;
+        vdom.set_conditional(&block, Some(0)).unwrap();
+        assert_eq!(texts(&vdom.root), vec!["Berlin", "Hauptstadt", "Berlin Mo", "Hamburg"]);
+
+        // mit der Instanz verschwinden auch ihre inneren Listen und Blöcke
+        vdom.update_list(&list_id, vec![item("Hamburg", "9")], None).unwrap();
+        assert_eq!(vdom.lists.len(), 2);
+        assert_eq!(vdom.conditionals.len(), 1);
+        assert_eq!(texts(&vdom.root), vec!["Hamburg"]);
+    }
+
+    #[test]
+    fn test_style_binding_merges_static_declarations() {
+        let vdom = VDom::new(r#"
+            <html><body><p id="t" style="padding: 4px; color: blue" :style="alert_style">Text</p></body></html>
+        "#).unwrap();
+        let Some(VNode::Element(mut el)) = vdom.find_element_by_id("t").cloned() else {
+            panic!("element not found");
+        };
+
+        let eval = |_: &str, _: &TemplateValues| Some(TemplateValue::from("color: red"));
+        assert!(el.render_bindings(&HashMap::new(), Some(&eval), |_| true));
+        assert_eq!(el.get_attr("style").as_deref(), Some("color: red; padding: 4px"));
+
+        // ohne Wert gelten wieder nur die Deklarationen aus dem HTML
+        let eval = |_: &str, _: &TemplateValues| -> Option<TemplateValue> { None };
+        assert!(el.render_bindings(&HashMap::new(), Some(&eval), |_| true));
+        assert_eq!(el.get_attr("style").as_deref(), Some("color: blue; padding: 4px"));
+    }
+
+    #[test]
+    fn test_conditional_branches() {
+        let mut vdom = VDom::new(r#"
+            <html><body>
+                <p>Start</p>
+                <p if="temp > 30">Heiß</p>
+                <p else-if="temp < 0">Frost</p>
+                <p else>Mild</p>
+                <p>Ende</p>
+            </body></html>
+        "#).unwrap();
+
+        assert_eq!(vdom.conditionals.len(), 1);
+        let block = &vdom.conditionals[0];
+        assert_eq!(block.branches.len(), 3);
+        assert_eq!(block.branches[0].condition.as_deref(), Some("temp > 30"));
+        assert_eq!(block.branches[2].condition, None);
+        let block_id = block.internal_id;
+        assert_eq!(texts(&vdom.root), vec!["Start", "Ende"]);
+
+        assert!(vdom.set_conditional(&block_id, Some(2)).unwrap());
+        assert_eq!(texts(&vdom.root), vec!["Start", "Mild", "Ende"]);
+        assert!(!vdom.set_conditional(&block_id, Some(2)).unwrap());
+
+        assert!(vdom.set_conditional(&block_id, Some(0)).unwrap());
+        assert_eq!(texts(&vdom.root), vec!["Start", "Heiß", "Ende"]);
+
+        assert!(vdom.set_conditional(&block_id, None).unwrap());
+        assert_eq!(texts(&vdom.root), vec!["Start", "Ende"]);
+    }
+
+    #[test]
+    fn test_query_selector_and_traversal() {
+        let vdom = VDom::new(r#"
+            <html><body>
+                <ul id="days">
+                    <li class="day">Mo</li>
+                    <li class="day today">Di</li>
+                    <li class="day">Mi</li>
+                </ul>
+                <p class="day">Legende</p>
+            </body></html>
+        "#).unwrap();
+
+        let days = *vdom.id_map.get("days").unwrap();
+        let all = vdom.query_selector_all(".day", None).unwrap();
+        assert_eq!(all.len(), 4);
+        let items = vdom.query_selector_all("li", Some(&days)).unwrap();
+        assert_eq!(items, vdom.element_children(&days));
+        assert_eq!(vdom.query_selector_all("body .today, ul > li:last-child", Some(&days)).unwrap(), items[1..].to_vec());
+        assert!(vdom.query_selector_all("ul", Some(&days)).unwrap().is_empty());
+        assert!(vdom.query_selector_all("li::before", None).is_err());
+
+        assert_eq!(vdom.parent_of(&items[0]), Some(days));
+        assert_eq!(vdom.sibling_of(&items[1], 1), Some(items[2]));
+        assert_eq!(vdom.sibling_of(&items[1], -1), Some(items[0]));
+        assert_eq!(vdom.sibling_of(&items[0], -1), None);
+        assert_eq!(vdom.sibling_of(&days, 1), Some(all[3]));
+        assert_eq!(vdom.parent_of(vdom.root.get_internal_id()), None);
+    }
+
+    #[test]
+    fn test_animated_values_survive_restyle() {
+        let mut vdom = VDom::new(r#"
+            <html><head><style>.bar { width: 100px; color: #000000ff; }</style></head>
+            <body><div id="bar" class="bar">42</div></body></html>
+        "#).unwrap();
+        vdom.restyle();
+        let id = *vdom.id_map.get("bar").unwrap();
+        if let Some(VNode::Element(el)) = vdom.root.find_by_internal_id_mut(&id) {
+            el.animated.width = Some(crate::styles::Dimension::Points(250.0));
+            el.animated.color = Some([255, 0, 0, 255]);
+        }
+
+        vdom.set_css_var("--accent", Some("red".to_string()));
+        vdom.restyle_if_needed();
+        let Some(VNode::Element(el)) = vdom.find_element_by_internal_id(&id) else {
+            panic!("bar not found");
+        };
+        assert_eq!(el.style.width, Some(crate::styles::Dimension::Points(250.0)));
+        assert_eq!(el.style.color, Some([255, 0, 0, 255]));
+        assert_eq!(el.children[0].get_style().color, Some([255, 0, 0, 255]));
+    }
+}
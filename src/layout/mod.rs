@@ -5,7 +5,8 @@
 //!
 //! Das Modul bietet folgende Funktionen:
 //! - **build_tree**: Rekursiver Aufbau des Taffy‑Baums aus dem VDOM, während ein Mapping von
-//!   VDOM‑IDs zu Taffy‑Knoten gepflegt wird.
+//!   VDOM‑IDs zu Taffy‑Knoten gepflegt wird. Pseudo-Elemente (`::before` / `::after`) werden
+//!   dabei als zusätzliche Text-Knoten mit stabiler ID eingefügt.
 //! - **apply_diff**: Anwendung von Diff‑Operationen (z. B. Replace, AddChild, RemoveChild),
 //!   um gezielt nur die betroffenen Knoten zu aktualisieren.
 //! - **compute_layout**: Auslösen der Layout‑Berechnung in Taffy anhand gegebener Container‑Maße.
@@ -19,7 +20,7 @@ use scraper::Node;
 use taffy::prelude::*;
 use taffy::geometry::Size;
 
-use crate::{styles::Style, vdom::{TextNode, VNode}, DiffOp, Renderer};
+use crate::{styles::selector::PseudoElement, vdom::{TextNode, VNode}, DiffOp, Renderer};
 
 use ulid::Ulid;
 
@@ -68,10 +69,13 @@ impl LayoutMapping {
                 let style = element.style.to_taffy_style();
 
 
-                // Verarbeite rekursiv alle Kindknoten.
-                let children: Vec<NodeId> = element
-                    .children
+                // Verarbeite rekursiv alle Kindknoten, `::before` / `::after` rahmen sie ein.
+                let before = element.pseudo_node(PseudoElement::Before);
+                let after = element.pseudo_node(PseudoElement::After);
+                let children: Vec<NodeId> = before
                     .iter()
+                    .chain(element.children.iter())
+                    .chain(after.iter())
                     .map(|child| {
                         self.build_tree(child, parent_node)
                     })
@@ -148,55 +152,54 @@ impl LayoutMapping {
 
                 // ich muss hier irgendwie schauen das ggf. die ausgetauschte vnode mehrere children haben kann!
             }
-            DiffOp::ChangeAttributes { tag: _tag, changes } => {
+            DiffOp::ChangeAttributes { .. } => {
+                // Attribute wirken nur über die Kaskade auf das Layout, der neue Style folgt
+                // als `ChangeStyle`. Die Textgröße kann trotzdem von ihnen abhängen,
+                // z. B. über `content: attr(...)`, daher wird neu gemessen.
                 if let Some(current_node_id) = node_id {
-                    // Aktuellen Style abrufen und anpassen
-                    let current_style = self.taffy
-                        .style(current_node_id)
-                        .expect("Fehler beim Abrufen des Stils").clone();
-                    for (attr, _old, new_value) in changes {
-                        if *attr == "style".to_string() {
-                            if let Some(style) = new_value {
-                                let new_taffy_style = Style::from_str(style).to_taffy_style();
-                                if new_taffy_style != current_style {
-                                    self.taffy.set_style(node_id.unwrap(), new_taffy_style).expect("couldnt set new style");
-                                }
-                            }
-                        }
-
-                    }
+                    let _ = self.taffy.mark_dirty(current_node_id);
+                }
+            }
+            DiffOp::ChangeStyle(style) => {
+                if let Some(current_node_id) = node_id {
+                    self.taffy.set_style(current_node_id, style.to_taffy_style()).expect("couldnt set new style");
                 } else {
-                    panic!("Knoten-ID nicht gefunden für ChangeAttributes");
+                    panic!("Knoten-ID nicht gefunden für ChangeStyle");
                 }
             }
             DiffOp::AddChild(index, new_vnode) => {
-                let new_style = new_vnode.get_style().to_taffy_style();
-                let new_node_context = new_vnode.get_node_context();
-                match self.taffy.new_leaf_with_context(new_style, new_node_context) {
-                    Ok(new_node_id) => {
-                        
-                        if let Some(node_id) = node_id {
-                            self.id_map.insert(*vnode_id, new_node_id);
-                            let _r = self.taffy.insert_child_at_index(node_id, *index, new_node_id);
-                        } else {
-                            panic!("Knoten-ID nicht gefunden");
-                        }
-                    }
-                    Err(e) => {
-                        panic!("Fehler beim Erstellen des neuen Knotens: {:?}", e);
+                if let Some(node_id) = node_id {
+                    // mit allen Kindern, die Zuordnung der IDs übernimmt `build_tree`
+                    let new_node_id = self.build_tree(new_vnode, None);
+                    // ein vorhandenes `::before` steht im Taffy-Baum vor den Kindern
+                    let offset = match node {
+                        VNode::Element(el) => el.style.before.is_some() as usize,
+                        VNode::Text(_) => 0,
+                    };
+                    let _r = self.taffy.insert_child_at_index(node_id, *index + offset, new_node_id);
+                } else {
+                    panic!("Knoten-ID nicht gefunden");
+                }
+            }
+            DiffOp::MoveChild { from, to } => {
+                if let Some(node_id) = node_id {
+                    let offset = match node {
+                        VNode::Element(el) => el.style.before.is_some() as usize,
+                        VNode::Text(_) => 0,
+                    };
+                    if let Ok(child) = self.taffy.remove_child_at_index(node_id, from + offset) {
+                        let _ = self.taffy.insert_child_at_index(node_id, to + offset, child);
                     }
                 }
             }
             DiffOp::RemoveChild(index) => {
-                panic!("remove child");
                 if let VNode::Element(elem) = node {
                     if let Some(child) = elem.children.get(*index) {
-                        let id = child.get_internal_id();
-                        if let Some(node) = self.id_map.remove(id) {
-                            self.taffy.remove(node).expect("Fehler beim Entfernen des Knotens");
+                        if let Some(child_node) = self.id_map.get(child.get_internal_id()).copied() {
+                            self.taffy.remove(child_node).expect("Fehler beim Entfernen des Knotens");
                         }
+                        self.forget(child);
                     }
-
                 }
             }
             DiffOp::PatchChild(index, boxed_diff) => {
@@ -220,6 +223,16 @@ impl LayoutMapping {
         }
     }
 
+    /// Entfernt einen VDOM‑Knoten samt Kindern aus dem Mapping.
+    fn forget(&mut self, vnode: &VNode) {
+        self.id_map.remove(vnode.get_internal_id());
+        if let VNode::Element(el) = vnode {
+            for child in &el.children {
+                self.forget(child);
+            }
+        }
+    }
+
     /// Führt die Layout‑Berechnung des Taffy‑Baums durch.
     ///
     /// Hierbei wird der Root‑Knoten (angenommen als erster im Mapping) mit der angegebenen Container‑Größe
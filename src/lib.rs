@@ -3,21 +3,29 @@ mod vdom;
 mod scripting;
 mod parser;
 mod render;
+mod template;
+mod component;
+mod clock;
 pub mod layout;
 pub mod styles;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
 use log::warn;
 use render::render_dom;
 use taffy::{NodeId, Style};
 use vdom::diff_vnode;
-use parser::load_lua_scripts;
+use parser::{document_title, load_lua_scripts, load_template_scripts, ScriptSource};
 use scripting::Engine;
+use clock::FrameTimer;
+pub use scripting::{default_storage_dir, EngineOptions, FileStorage, MemoryStorage, Message, ScriptError, Storage};
+pub use clock::{Clock, FrameTime, ManualClock, SystemClock};
 pub use parser::parse_html_to_vdom;
 pub use render::Renderer;
+pub use mlua;
 
 pub use vdom::DiffOp;
+pub use document::{ElementState, StateFlag};
 
 pub use parser::parse_color;
 
@@ -25,6 +33,12 @@ pub struct Dynamite<R: Renderer> {
     pub vdom: document::VDom,
     pub layout: layout::LayoutMapping,
     engine: Engine,
+    /// Globale Skripte, sie laufen erst mit `load_scripts` bzw. im ersten `run_frame`.
+    pending_scripts: Option<Vec<ScriptSource>>,
+    /// Ein Skript ist beim Laden fehlgeschlagen, `on:load` und `on:update` laufen dann nie.
+    scripts_failed: bool,
+    clock: Rc<dyn Clock>,
+    frames: FrameTimer,
     first_run: bool,
     renderer: R
 }
@@ -32,11 +46,26 @@ pub struct Dynamite<R: Renderer> {
 impl<R: Renderer> Dynamite<R> {
 
     pub fn new(html: &str, render_backend: R) -> Result<Self, String> {
+        Self::with_options(html, render_backend, EngineOptions::default())
+    }
+
+    /// Wie `new`, aber mit eigenen Einstellungen für Lua, z. B. `EngineOptions::sandboxed()`
+    /// für Inhalte, denen nicht voll vertraut wird.
+    pub fn with_options(html: &str, render_backend: R, options: EngineOptions) -> Result<Self, String> {
+        Self::with_clock(html, render_backend, options, Rc::new(SystemClock::default()))
+    }
+
+    /// Wie `with_options`, Frames, Timer und Animationen laufen aber nach `clock`,
+    /// z. B. einer `ManualClock` in Tests.
+    pub fn with_clock(html: &str, render_backend: R, mut options: EngineOptions, clock: Rc<dyn Clock>) -> Result<Self, String> {
         let vdom = parse_html_to_vdom(html)?;
-        let mut engine = Engine::new();
+        if options.storage_namespace.is_none() {
+            options.storage_namespace = document_title(html);
+        }
+        let mut engine = Engine::with_options(&options, clock.clone())?;
         engine.search_onupdate_functions(&vdom)?;
         let scripts = load_lua_scripts(html)?;
-        engine.load_scripts(scripts).map_err(|e| e.to_string())?;
+        engine.load_template_scripts(load_template_scripts(html)?).map_err(|e| e.to_string())?;
 
         let mut layout = layout::LayoutMapping::new();
         let _ = layout.build_tree(&vdom.root, None);
@@ -44,12 +73,99 @@ impl<R: Renderer> Dynamite<R> {
         Ok(Self {
             vdom,
             engine,
+            pending_scripts: Some(scripts),
+            scripts_failed: false,
+            clock,
+            frames: FrameTimer::default(),
             first_run: true,
             renderer: render_backend,
             layout
         })
     }
 
+    /// Setzt einen interaktiven Zustand (`:hover`, `:active`, `:focus`) eines Elements
+    /// anhand seiner HTML-ID. Neu gestylt wird beim nächsten `run_frame`, und zwar nur
+    /// der Teilbaum des betroffenen Elements.
+    pub fn set_element_state(&mut self, id: &str, flag: StateFlag, value: bool) -> Result<bool, String> {
+        let internal_id = *self.vdom.id_map.get(id).ok_or("element id not found")?;
+        Ok(self.vdom.set_element_state(&internal_id, flag, value))
+    }
+
+    /// Registriert eine Rust-Funktion als globale Lua-Funktion, z. B. für gerätespezifische APIs.
+    /// Vor dem ersten `run_frame` aufgerufen, steht sie schon den `<script>` Blöcken zur Verfügung.
+    ///
+    /// ```ignore
+    /// dynamite.register_function("read_sensor", |_, channel: u8| Ok(sensor::read(channel)))?;
+    /// ```
+    pub fn register_function<A, T, F>(&mut self, name: &str, function: F) -> Result<(), String>
+    where
+        A: mlua::FromLuaMulti,
+        T: mlua::IntoLuaMulti,
+        F: Fn(&mlua::Lua, A) -> mlua::Result<T> + 'static,
+    {
+        self.engine.register_function(name, function).map_err(|e| format!("registering {} failed: {}", name, e))
+    }
+
+    /// Registriert eine Tabelle `name`, die `build` füllt. Sie ist als Global und über
+    /// `require(name)` erreichbar.
+    ///
+    /// ```ignore
+    /// dynamite.register_module("gpio", |lua, module| {
+    ///     module.set("read", lua.create_function(|_, pin: u8| Ok(gpio::read(pin)))?)?;
+    ///     module.set("led", lua.create_userdata(Led::open()?)?)
+    /// })?;
+    /// ```
+    pub fn register_module<F>(&mut self, name: &str, build: F) -> Result<(), String>
+    where
+        F: FnOnce(&mlua::Lua, &mlua::Table) -> mlua::Result<()>,
+    {
+        self.engine.register_module(name, build).map_err(|e| format!("registering {} failed: {}", name, e))
+    }
+
+    /// Setzt ein Lua-Global, z. B. eigene Userdata oder Konfigurationswerte.
+    pub fn register_global(&mut self, name: &str, value: impl mlua::IntoLua) -> Result<(), String> {
+        self.engine.register_global(name, value).map_err(|e| format!("registering {} failed: {}", name, e))
+    }
+
+    /// Führt die globalen `<script>` Blöcke aus, sonst passiert das im ersten `run_frame`.
+    /// Nützlich, um Skriptfehler schon vor dem ersten Frame zu erhalten, z. B. direkt nach
+    /// `new` und dem Registrieren eigener Funktionen.
+    ///
+    /// Schlägt ein Skript fehl, laufen die übrigen trotzdem und der erste Fehler kommt einmal
+    /// zurück, hier oder aus dem `run_frame`, das die Skripte lädt. Ohne `continue_on_error`
+    /// laufen danach weder `on:load` noch `on:update`, das Dokument wird aber weiter gerendert.
+    pub fn load_scripts(&mut self) -> Result<(), String> {
+        let Some(scripts) = self.pending_scripts.take() else {
+            return Ok(());
+        };
+        let result = self.engine.load_scripts(scripts);
+        self.scripts_failed = result.is_err();
+        result
+    }
+
+    /// Ersetzt den Speicher hinter der Lua-API `storage`, z. B. durch einen `MemoryStorage` in Tests.
+    /// Vor dem ersten `run_frame` gesetzt, sehen ihn schon die `<script>` Blöcke.
+    pub fn set_storage(&mut self, store: Box<dyn Storage>) -> Result<(), String> {
+        self.engine.set_storage(store).map_err(|e| e.to_string())
+    }
+
+    /// Stellt den `on_message` Handlern eine Nachricht zu, z. B. Sensorwerte oder MQTT-Nachrichten.
+    /// Die Handler laufen im nächsten `run_frame`.
+    pub fn post_message(&mut self, name: impl Into<String>, data: serde_json::Value) -> Result<(), String> {
+        self.engine.post_message(Message { name: name.into(), data }).map_err(|e| e.to_string())
+    }
+
+    /// Liefert die Ereignisse, die Skripte seit dem letzten Aufruf mit `emit` erzeugt haben.
+    pub fn drain_events(&mut self) -> Result<Vec<Message>, String> {
+        self.engine.drain_events().map_err(|e| e.to_string())
+    }
+
+    /// Liefert die Skriptfehler seit dem letzten Aufruf, mit Position und Traceback.
+    /// Mit `EngineOptions::continue_on_error` ist das der einzige Weg, von ihnen zu erfahren.
+    pub fn take_script_errors(&mut self) -> Vec<ScriptError> {
+        self.engine.take_errors()
+    }
+
     /// called when a frame should be prepared for rendering
     /// 
     /// This function will call all `onupdate` functions in the Lua scripts
@@ -57,20 +173,44 @@ impl<R: Renderer> Dynamite<R> {
     /// and apply the changes to the real DOM.
     pub fn run_frame(&mut self, ctx: &mut R::Context, size: (u32, u32)) -> Result<bool, String> {
         let old_vdom = self.vdom.root.clone();
+
+        // Media Queries neu auswerten, neu gestylt wird nur wenn eine Query umschlägt
+        if self.vdom.set_viewport(size.0 as f32, size.1 as f32) {
+            self.vdom.restyle();
+        }
+
         self.engine.begin(&self.vdom).unwrap();
+        self.load_scripts()?;
+        let frame = self.frames.tick(self.clock.instant());
 
         let mut first_draw = true;
         if self.first_run {
-            self.first_run = false;
-            self.engine.call_onload()?;
+            // nicht mit halb geladenen Skripten
+            if !self.scripts_failed {
+                self.first_run = false;
+                self.engine.call_onload(frame)?;
+            }
         } else {
             first_draw = false;
-            self.engine.call_onupdates()?;
+            self.engine.call_onupdates(frame)?;
         }
+        self.engine.poll_fetches()?;
+        self.engine.deliver_messages()?;
+        self.engine.run_timers()?;
+
+        // Bedingungen zuerst, Listen können in einem gerade eingeblendeten Zweig stehen
+        self.engine.update_conditionals()?;
+        self.engine.update_lists()?;
+        self.engine.update_instances()?;
+        self.engine.update_bindings()?;
+        self.engine.tick_animations()?;
+        self.engine.flush_storage()?;
 
-        let vdom = self.engine.commit().unwrap();
+        let mut vdom = self.engine.commit().unwrap();
+        vdom.restyle_if_needed();
 
-        let patch = diff_vnode(&old_vdom, &vdom);
+        let patch = diff_vnode(&old_vdom, &vdom.root);
+        self.vdom = vdom;
 
         //warn!("old_vdom: {:?}", old_vdom);
 
@@ -83,9 +223,7 @@ impl<R: Renderer> Dynamite<R> {
             //self.layout.taffy.clear();
             //self.layout.id_map.clear();
             // create first node!
-            let node = self.layout.build_tree(&vdom, None);
-
-            self.vdom.root = vdom;
+            let node = self.layout.build_tree(&self.vdom.root, None);
 
             let dirty = self.layout.taffy.dirty(node).unwrap_or(false);
 
@@ -111,4 +249,35 @@ impl<R: Renderer> Dynamite<R> {
 
         Ok(false)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::styles::Style;
+
+    struct NullRenderer;
+
+    impl Renderer for NullRenderer {
+        type Context = ();
+        fn draw_text(&mut self, _: &mut (), _: &str, _: &Style, _: f32, _: f32) {}
+        fn draw_element(&mut self, _: &mut (), _: &str, _: &Style, _: f32, _: f32, _: f32, _: f32) {}
+        fn measure_text(&self, _: &(), _: &str, _: &Style) -> (u32, u32) {
+            (0, 0)
+        }
+    }
+
+    #[test]
+    fn test_failed_script_is_reported_once() {
+        let html = r#"<html><body on:load="loaded" on:update="updated">
+            <script>function loaded() load_ran = true end function updated() update_ran = true end</script>
+            <script>error("boom")</script>
+        </body></html>"#;
+        let mut dynamite = Dynamite::new(html, NullRenderer).unwrap();
+        assert!(dynamite.run_frame(&mut (), (100, 100)).unwrap_err().contains("boom"));
+        assert!(dynamite.run_frame(&mut (), (100, 100)).is_ok());
+
+        let (load_ran, update_ran): (bool, bool) = dynamite.engine.lua.load("return load_ran == true, update_ran == true").eval().unwrap();
+        assert!(!load_ran && !update_ran);
+    }
+}
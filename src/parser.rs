@@ -1,27 +1,24 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
 use im::Vector;
 use scraper::{ElementRef, Html, Node, Selector};
 use ulid::Ulid;
 
-use crate::{document::VDom, styles::Style, vdom::{ElementNode, TextNode, VNode}};
+use crate::{component::expand_components, document::VDom, styles::{stylesheet::StyleSheet, Style}, template::{AttrBinding, CompiledTemplate}, vdom::{ElementNode, TextNode, VNode}};
 
 
 pub fn parse_element(element: &ElementRef) -> VNode {
-    parse_element_internal(element, false, true, true).expect("parse_element failed")
+    parse_element_internal(element).expect("parse_element failed")
 }
 
+/// Verschachtelte `<template>` werden übersprungen, sie stehen als eigene Templates bereit.
 pub fn parse_templates(element: &ElementRef) -> VNode {
-    parse_element_internal(element, true, true, false).expect("parse_templates failed")
+    parse_element_internal(element).expect("parse_templates failed")
 }
 
-fn parse_element_internal(element: &ElementRef, inside_template: bool, start: bool, ignore_templates: bool) -> Result<VNode, String> {
+fn parse_element_internal(element: &ElementRef) -> Result<VNode, String> {
     let tag = element.value().name().to_string();
 
-    if inside_template && tag == "template" && start == false {
-        panic!("Nested <template> tags are not allowed");
-    }
-
     let id = element.value().attr("id").map(|s| s.to_string());
     //let template = element.value().attr("template").map(|s| s.to_string());
     //let for_each = element.value().attr("for-each").map(|s| s.to_string());
@@ -29,10 +26,15 @@ fn parse_element_internal(element: &ElementRef, inside_template: bool, start: bo
     let mut attrs = HashMap::new();
     let mut styles = HashMap::new();
 
+    let mut bindings = HashMap::new();
+
     for attr in element.value().attrs() {
         let key = attr.0.to_string();
         let value = attr.1.to_string();
-        if key == "style" {
+        if let Some((name, binding)) = AttrBinding::parse(&key, &value) {
+            // gebundene Attribute erhalten ihren Wert erst beim Rendern
+            bindings.insert(name, binding);
+        } else if key == "style" {
             let parsed_styles = parse_styles(&value);
             styles.extend(parsed_styles);
         } else {
@@ -54,6 +56,9 @@ fn parse_element_internal(element: &ElementRef, inside_template: bool, start: bo
                     if el.name() == "script" {
                         return None; // ❌ script ignorieren
                     }
+                    if el.name() == "style" {
+                        return None; // ❌ stylesheets werden separat geladen
+                    }
                 }
             }
             match child.value() {
@@ -67,13 +72,15 @@ fn parse_element_internal(element: &ElementRef, inside_template: bool, start: bo
                             attrs: attrs.clone(),
                             style: style.clone(),
                             template: text.trim().to_string(),
+                            compiled: Rc::new(CompiledTemplate::parse(text.trim())),
                             rendered: text.trim().to_string(),
+                            slot_depth: 0,
                         })))
                     }
                 },
                 scraper::Node::Element(_) => {
                     let el = ElementRef::wrap(child).unwrap();
-                    Some(parse_element_internal(&el, inside_template, false, ignore_templates).map(|n| n))
+                    Some(parse_element_internal(&el))
                 }
                 _ => None,
             }
@@ -85,7 +92,12 @@ fn parse_element_internal(element: &ElementRef, inside_template: bool, start: bo
         id,
         tag,
         attrs,
-        style,  
+        static_style: if bindings.contains_key("style") { styles.clone() } else { HashMap::new() },
+        inline_style: styles,
+        bindings,
+        scope: Default::default(),
+        style,
+        animated: Style::default(),
         children,
     }))
 }
@@ -130,12 +142,14 @@ pub fn parse_html_to_vdom(html: &str) -> Result<VDom, String> {
         .count();
 
 
+        // Skripte neben dem Wurzelelement gehören zum Template, siehe `load_template_scripts`
         let mut tpl_children = tpl
             .first_child().unwrap()
             .children()
             .filter_map(|child| {
                 ElementRef::wrap(child)
-            });
+            })
+            .filter(|child| child.value().name() != "script");
         let main_child = tpl_children.next().ok_or("template tag must contain one child element")?;
 
         if tpl_children.next().is_some() {
@@ -146,18 +160,40 @@ pub fn parse_html_to_vdom(html: &str) -> Result<VDom, String> {
         templates.insert(id, vnode);
     }
 
+    // `<use>` in Templates auflösen, create_element kopiert dann nur noch
+    let mut expanded = HashMap::new();
+    for (id, template) in &templates {
+        let mut template = template.clone();
+        expand_components(&mut template, &templates)?;
+        expanded.insert(id.clone(), template);
+    }
+    let templates = expanded;
+
     let body = document.select(&body_selector).next().ok_or("<body> not found")?;
 
-    let root = parse_element(&body);
+    let mut root = parse_element(&body);
+    expand_components(&mut root, &templates)?;
 
     index_node(&root, &mut id_map);
 
+    let stylesheet = load_stylesheet(&document);
+
+    let mut vdom = VDom::with_stylesheet(root, templates, id_map, stylesheet);
+    vdom.restyle();
+
+    Ok(vdom)
+}
+
+/// Sammelt die Regeln aller `<style>` Blöcke des Dokuments in einem Stylesheet.
+pub fn load_stylesheet(document: &Html) -> StyleSheet {
+    let style_selector = Selector::parse("style").unwrap();
 
-    Ok(VDom {
-        root,
-        id_map,
-        templates,
-    })
+    let mut stylesheet = StyleSheet::default();
+    for style in document.select(&style_selector) {
+        let css: String = style.text().collect();
+        stylesheet.append(&css);
+    }
+    stylesheet
 }
 
 
@@ -172,20 +208,106 @@ pub fn parse_styles(style_str: &str) -> HashMap<String, String> {
     styles
 }
 
-pub fn load_lua_scripts(html: &str) -> Result<Vec<String>, String> {
+/// Liefert das nächste `<template>`, in dem ein Element steht.
+fn enclosing_template<'a>(element: &ElementRef<'a>) -> Option<ElementRef<'a>> {
+    element
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        .find(|el| el.value().name() == "template")
+}
+
+/// Ein `<script>` Block, global oder aus einem Template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptSource {
+    /// Index unter allen `<script>` Blöcken des Dokuments, auch denen in Templates.
+    pub index: usize,
+    pub source: String,
+    /// Beginn des Inhalts in der HTML-Datei, beginnend bei 1.
+    pub line: usize,
+    pub column: usize,
+}
+
+pub fn load_lua_scripts(html: &str) -> Result<Vec<ScriptSource>, String> {
     let document = Html::parse_document(html);
     let script_selector = Selector::parse("script").unwrap();
+    let positions = script_positions(html);
 
     let mut scripts = Vec::new();
-    for script in document.select(&script_selector) {
+    for (index, script) in document.select(&script_selector).enumerate() {
+        // Skripte in Templates laufen je Instanz, siehe `load_template_scripts`
+        if enclosing_template(&script).is_some() {
+            continue;
+        }
         if let Some(script_content) = script.text().next() {
             //lua.load(script_content).exec()?;
-            scripts.push(script_content.to_string());
+            let (line, column) = positions.get(index).copied().unwrap_or((1, 1));
+            scripts.push(ScriptSource { index, source: script_content.to_string(), line, column });
+        }
+    }
+    Ok(scripts)
+}
+
+/// Sucht den Beginn des Inhalts aller `<script>` Tags im Quelltext, als Zeile und Spalte.
+/// Kommentare werden übersprungen, die Reihenfolge entspricht der des Dokuments.
+fn script_positions(html: &str) -> Vec<(usize, usize)> {
+    let lower = html.to_ascii_lowercase();
+    let mut positions = Vec::new();
+    let mut offset = 0;
+    while offset < lower.len() {
+        let rest = &lower[offset..];
+        let Some(next) = rest.find('<') else {
+            break;
+        };
+        let tag = &rest[next..];
+        if tag.starts_with("<!--") {
+            offset += next + tag.find("-->").map(|end| end + 3).unwrap_or(tag.len());
+        } else if tag.starts_with("<script") && !tag[7..].starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            let Some(open_end) = tag.find('>') else {
+                break;
+            };
+            let content = offset + next + open_end + 1;
+            let before = &html[..content];
+            let line = before.matches('\n').count() + 1;
+            let column = before[before.rfind('\n').map(|i| i + 1).unwrap_or(0)..].chars().count() + 1;
+            positions.push((line, column));
+            // bis zum schließenden Tag, der Inhalt kann `<` enthalten
+            offset = content + lower[content..].find("</script").unwrap_or(lower.len() - content);
+        } else {
+            offset += next + 1;
         }
     }
+    positions
+}
+
+/// Sammelt die `<script>` Blöcke in Templates, nach Template-ID. Index und Position zählen
+/// wie bei `load_lua_scripts` über alle `<script>` Blöcke des Dokuments.
+pub fn load_template_scripts(html: &str) -> Result<HashMap<String, Vec<ScriptSource>>, String> {
+    let document = Html::parse_document(html);
+    let script_selector = Selector::parse("script").unwrap();
+    let positions = script_positions(html);
+
+    let mut scripts: HashMap<String, Vec<ScriptSource>> = HashMap::new();
+    for (index, script) in document.select(&script_selector).enumerate() {
+        let Some(template) = enclosing_template(&script) else {
+            continue;
+        };
+        let id = template.value().attr("id").ok_or("template without id")?;
+        let (line, column) = positions.get(index).copied().unwrap_or((1, 1));
+        let source = script.text().collect::<String>();
+        scripts.entry(id.to_string()).or_default().push(ScriptSource { index, source, line, column });
+    }
     Ok(scripts)
 }
 
+/// Text des `<title>`, Standard-Namespace für `storage`.
+pub fn document_title(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("title").unwrap();
+    let title = document.select(&selector).next()?.text().collect::<String>();
+    let title = title.trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
 pub fn parse_color(input: &str) -> Option<[u8; 4]> {
     let hex = input.strip_prefix('#')?;
 
@@ -207,4 +329,27 @@ pub fn parse_color(input: &str) -> Option<[u8; 4]> {
         }
         _ => None,
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_positions() {
+        let html = "<html>\n<!-- <script>x</script> -->\n<body>\n  <script>a = 1</script>\n<script type=\"text/lua\">\nb = \"<\"\n</script></body></html>";
+        let scripts = load_lua_scripts(html).unwrap();
+        assert_eq!(scripts.len(), 2);
+        assert_eq!((scripts[0].line, scripts[0].column), (4, 11));
+        assert_eq!((scripts[1].index, scripts[1].line, scripts[1].column), (1, 5, 25));
+    }
+
+    #[test]
+    fn test_template_script_positions() {
+        let html = "<html><body>\n<script>a = 1</script>\n<template id=\"card\"><div></div>\n<script>\nb = 2</script></template>\n</body></html>";
+        let scripts = load_template_scripts(html).unwrap();
+        let card = &scripts["card"];
+        assert_eq!(card.len(), 1);
+        assert_eq!((card[0].index, card[0].line, card[0].column), (1, 4, 9));
+    }
+}
@@ -3,7 +3,7 @@ use std::{collections::HashMap, sync::Arc};
 use log::warn;
 use ulid::Ulid;
 
-use crate::{layout::LayoutMapping, styles::Style, vdom::{DiffOp, VNode}};
+use crate::{layout::LayoutMapping, styles::{selector::PseudoElement, Style}, vdom::{DiffOp, VNode}};
 
 
 pub trait Renderer {
@@ -64,7 +64,9 @@ pub fn render_dom<R: Renderer>(
                             );
                             is_dirty = true;
                         //}
-                        for child in &el.children {
+                        let before = el.pseudo_node(PseudoElement::Before);
+                        let after = el.pseudo_node(PseudoElement::After);
+                        for child in before.iter().chain(el.children.iter()).chain(after.iter()) {
                             if render_dom(l, child, render, ctx, (abs_x, abs_y)) {
                                 is_dirty = true;
                             }
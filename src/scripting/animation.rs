@@ -0,0 +1,475 @@
+use std::time::{Duration, Instant};
+use mlua::{AnyUserData, Function, Lua, Table, UserDataMethods, Value};
+
+use crate::{document::{FindByIdMut, VDom}, parse_color, styles::{Dimension, Style}, vdom::VNode};
+
+use super::{timer::clock, DynamiteContext, ElementContext};
+
+/// Zeitverlauf einer Animation, entspricht den CSS `transition-timing-function` Werten.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    CubicBezier(f32, f32, f32, f32),
+}
+
+impl Easing {
+    pub const EASE: Easing = Easing::CubicBezier(0.25, 0.1, 0.25, 1.0);
+    pub const EASE_IN: Easing = Easing::CubicBezier(0.42, 0.0, 1.0, 1.0);
+    pub const EASE_OUT: Easing = Easing::CubicBezier(0.0, 0.0, 0.58, 1.0);
+    pub const EASE_IN_OUT: Easing = Easing::CubicBezier(0.42, 0.0, 0.58, 1.0);
+
+    /// Parst einen Easing-Namen (`linear`, `ease`, `ease-in`, `ease-out`, `ease-in-out`)
+    /// oder eine `cubic-bezier(x1, y1, x2, y2)` Angabe.
+    pub fn from_str(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        match s.as_str() {
+            "linear" => Some(Easing::Linear),
+            "ease" => Some(Easing::EASE),
+            "ease-in" => Some(Easing::EASE_IN),
+            "ease-out" => Some(Easing::EASE_OUT),
+            "ease-in-out" => Some(Easing::EASE_IN_OUT),
+            _ => {
+                let args = s.strip_prefix("cubic-bezier(")?.strip_suffix(')')?;
+                let values = args
+                    .split(',')
+                    .map(|v| v.trim().parse::<f32>().ok())
+                    .collect::<Option<Vec<f32>>>()?;
+                match values.as_slice() {
+                    [x1, y1, x2, y2] if (0.0..=1.0).contains(x1) && (0.0..=1.0).contains(x2) => {
+                        Some(Easing::CubicBezier(*x1, *y1, *x2, *y2))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// Bildet den linearen Fortschritt `t` (0.0 – 1.0) auf den Easing-Verlauf ab.
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match *self {
+            Easing::Linear => t,
+            Easing::CubicBezier(x1, y1, x2, y2) => {
+                if t == 0.0 || t == 1.0 {
+                    return t;
+                }
+                let s = solve_bezier_x(x1, x2, t);
+                bezier_axis(y1, y2, s)
+            }
+        }
+    }
+}
+
+fn bezier_axis(p1: f32, p2: f32, s: f32) -> f32 {
+    let u = 1.0 - s;
+    3.0 * u * u * s * p1 + 3.0 * u * s * s * p2 + s * s * s
+}
+
+fn bezier_axis_derivative(p1: f32, p2: f32, s: f32) -> f32 {
+    let u = 1.0 - s;
+    3.0 * u * u * p1 + 6.0 * u * s * (p2 - p1) + 3.0 * s * s * (1.0 - p2)
+}
+
+/// Sucht den Kurvenparameter `s`, an dem die x-Koordinate der Bezier-Kurve `x` ergibt.
+/// Zuerst Newton-Iteration, bei flacher Steigung Fallback auf Bisektion.
+fn solve_bezier_x(x1: f32, x2: f32, x: f32) -> f32 {
+    let mut s = x;
+    for _ in 0..8 {
+        let error = bezier_axis(x1, x2, s) - x;
+        if error.abs() < 1e-5 {
+            return s;
+        }
+        let slope = bezier_axis_derivative(x1, x2, s);
+        if slope.abs() < 1e-6 {
+            break;
+        }
+        s -= error / slope;
+    }
+
+    let (mut low, mut high) = (0.0, 1.0);
+    s = x;
+    for _ in 0..32 {
+        let value = bezier_axis(x1, x2, s);
+        if (value - x).abs() < 1e-5 {
+            break;
+        }
+        if value < x {
+            low = s;
+        } else {
+            high = s;
+        }
+        s = (low + high) / 2.0;
+    }
+    s
+}
+
+/// Ein animierbarer Wert einer Style-Eigenschaft.
+#[derive(Debug, Clone, PartialEq)]
+enum TweenValue {
+    Length(Dimension),
+    Number(f32),
+    Color([u8; 4]),
+}
+
+impl TweenValue {
+    fn interpolate(&self, to: &TweenValue, t: f32) -> TweenValue {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        match (self, to) {
+            (TweenValue::Length(Dimension::Points(a)), TweenValue::Length(Dimension::Points(b))) => {
+                TweenValue::Length(Dimension::Points(lerp(*a, *b)))
+            }
+            (TweenValue::Length(Dimension::Percent(a)), TweenValue::Length(Dimension::Percent(b))) => {
+                TweenValue::Length(Dimension::Percent(lerp(*a, *b)))
+            }
+            (TweenValue::Number(a), TweenValue::Number(b)) => TweenValue::Number(lerp(*a, *b)),
+            (TweenValue::Color(a), TweenValue::Color(b)) => {
+                let mut c = [0u8; 4];
+                for (channel, (from, to)) in c.iter_mut().zip(a.iter().zip(b.iter())) {
+                    *channel = lerp(*from as f32, *to as f32).round().clamp(0.0, 255.0) as u8;
+                }
+                TweenValue::Color(c)
+            }
+            // unterschiedliche Einheiten lassen sich nicht interpolieren, springe am Ende auf den Zielwert
+            _ => if t >= 1.0 { to.clone() } else { self.clone() },
+        }
+    }
+}
+
+/// Liest den aktuellen Wert einer animierbaren Eigenschaft aus dem Style.
+fn read_property(style: &Style, property: &str) -> Option<TweenValue> {
+    match property {
+        "width" => Some(TweenValue::Length(style.width.clone().unwrap_or(Dimension::Points(0.0)))),
+        "height" => Some(TweenValue::Length(style.height.clone().unwrap_or(Dimension::Points(0.0)))),
+        "gap" => Some(TweenValue::Length(style.gap.clone().unwrap_or(Dimension::Points(0.0)))),
+        "font-size" => Some(TweenValue::Number(style.font_size.unwrap_or(16.0))),
+        "opacity" => Some(TweenValue::Number(style.opacity.unwrap_or(1.0))),
+        "background-color" => Some(TweenValue::Color(style.background_color.unwrap_or([0, 0, 0, 0]))),
+        "color" => Some(TweenValue::Color(style.color.unwrap_or([0, 0, 0, 255]))),
+        _ => None,
+    }
+}
+
+fn write_property(style: &mut Style, property: &str, value: &TweenValue) {
+    match (property, value) {
+        ("width", TweenValue::Length(d)) => style.width = Some(d.clone()),
+        ("height", TweenValue::Length(d)) => style.height = Some(d.clone()),
+        ("gap", TweenValue::Length(d)) => style.gap = Some(d.clone()),
+        ("font-size", TweenValue::Number(n)) => style.font_size = Some(*n),
+        ("opacity", TweenValue::Number(n)) => style.opacity = Some(n.clamp(0.0, 1.0)),
+        ("background-color", TweenValue::Color(c)) => style.background_color = Some(*c),
+        ("color", TweenValue::Color(c)) => style.color = Some(*c),
+        _ => {}
+    }
+}
+
+/// Parst den Zielwert einer Eigenschaft aus einem Lua-Wert (`"300px"`, `0.5`, `"#ff0000ff"`).
+fn parse_target(property: &str, value: &Value) -> Option<TweenValue> {
+    let text = match value {
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.to_str().ok()?.to_string(),
+        _ => return None,
+    };
+
+    match read_property(&Style::default(), property)? {
+        TweenValue::Length(_) => text.parse::<Dimension>().ok().map(TweenValue::Length),
+        TweenValue::Number(_) => text.trim().trim_end_matches("px").parse::<f32>().ok().map(TweenValue::Number),
+        TweenValue::Color(_) => parse_color(text.trim()).map(TweenValue::Color),
+    }
+}
+
+struct Animation {
+    id: u64,
+    element: ElementContext,
+    targets: Vec<(String, TweenValue)>,
+    /// Startwerte, werden beim ersten Tick aus dem aktuellen Style gelesen.
+    from: Option<Vec<TweenValue>>,
+    start: Instant,
+    duration: Duration,
+    easing: Easing,
+    on_done: Option<Function>,
+}
+
+impl Animation {
+    /// Schreibt den Fortschritt der Animation in die Animationsebene des Elements,
+    /// die Kaskade übernimmt die Werte beim nächsten Neu-Stylen.
+    /// Gibt `false` zurück, wenn das Element nicht (mehr) existiert.
+    fn apply(&mut self, vdom: &mut VDom, progress: f32) -> bool {
+        let in_tree = {
+            let mut temp_node = self.element.temp_node.borrow_mut();
+            let (node, in_tree) = match temp_node.as_mut() {
+                Some(node) => (node, false),
+                None => match vdom.root.find_by_internal_id_mut(&self.element.internal_id) {
+                    Some(node) => (node, true),
+                    None => return false,
+                },
+            };
+
+            let VNode::Element(el) = node else {
+                return false;
+            };
+
+            let from = self.from.get_or_insert_with(|| {
+                let mut current = el.style.clone();
+                current.overlay(&el.animated);
+                self.targets
+                    .iter()
+                    .map(|(property, target)| read_property(&current, property).unwrap_or_else(|| target.clone()))
+                    .collect()
+            });
+
+            let eased = self.easing.apply(progress);
+            for ((property, target), start) in self.targets.iter().zip(from.iter()) {
+                let value = if progress >= 1.0 { target.clone() } else { start.interpolate(target, eased) };
+                write_property(&mut el.animated, property, &value);
+            }
+            in_tree
+        };
+
+        // ein noch nicht eingefügtes Element wird beim Einfügen gestylt
+        if in_tree {
+            vdom.mark_for_restyle(self.element.internal_id);
+        }
+        true
+    }
+}
+
+/// Hält alle laufenden Animationen, wird als `_animations` in den Lua-Globals abgelegt.
+#[derive(Default)]
+pub struct AnimationContext {
+    next_id: u64,
+    animations: Vec<Animation>,
+}
+impl mlua::UserData for AnimationContext {}
+
+/// Leichtes Handle auf eine laufende Animation, das an Lua zurückgegeben wird.
+#[derive(Clone)]
+pub struct AnimationHandle {
+    pub id: u64,
+}
+
+impl mlua::UserData for AnimationHandle {
+    fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("cancel", |lua, this, _: ()| {
+            let ctx_ud: AnyUserData = lua.globals().get("_animations")?;
+            let mut ctx = ctx_ud.borrow_mut::<AnimationContext>()?;
+            ctx.animations.retain(|a| a.id != this.id);
+            Ok(())
+        });
+
+        methods.add_method("is_running", |lua, this, _: ()| {
+            let ctx_ud: AnyUserData = lua.globals().get("_animations")?;
+            let ctx = ctx_ud.borrow::<AnimationContext>()?;
+            Ok(ctx.animations.iter().any(|a| a.id == this.id))
+        });
+    }
+}
+
+/// `animate(element, {width="300px", opacity=0}, duration, easing, on_done)`
+///
+/// Startet eine Animation der angegebenen Style-Eigenschaften über `duration` Sekunden.
+/// Laufende Animationen derselben Eigenschaft auf demselben Element werden übernommen.
+fn animate(
+    lua: &Lua,
+    (element, properties, duration, easing, on_done): (AnyUserData, Table, f64, Option<String>, Option<Function>),
+) -> mlua::Result<AnyUserData> {
+    let element = element.borrow::<ElementContext>()?.clone();
+    let duration = Duration::try_from_secs_f64(duration.max(0.0)).map_err(|_| mlua::Error::runtime("invalid duration"))?;
+
+    let easing = match easing {
+        Some(name) => Easing::from_str(&name)
+            .ok_or_else(|| mlua::Error::external(format!("unknown easing: {}", name)))?,
+        None => Easing::EASE,
+    };
+
+    let mut targets = Vec::new();
+    for pair in properties.pairs::<String, Value>() {
+        let (property, value) = pair?;
+        let target = parse_target(&property, &value)
+            .ok_or_else(|| mlua::Error::external(format!("cannot animate property: {}", property)))?;
+        targets.push((property, target));
+    }
+
+    let ctx_ud: AnyUserData = lua.globals().get("_animations")?;
+    let mut ctx = ctx_ud.borrow_mut::<AnimationContext>()?;
+
+    for anim in ctx.animations.iter_mut().filter(|a| a.element.internal_id == element.internal_id) {
+        let keep: Vec<bool> = anim.targets
+            .iter()
+            .map(|(p, _)| !targets.iter().any(|(t, _)| t == p))
+            .collect();
+        let mut flags = keep.iter();
+        anim.targets.retain(|_| *flags.next().unwrap());
+        if let Some(from) = anim.from.as_mut() {
+            let mut flags = keep.iter();
+            from.retain(|_| *flags.next().unwrap());
+        }
+    }
+    ctx.animations.retain(|a| !a.targets.is_empty());
+
+    ctx.next_id += 1;
+    let id = ctx.next_id;
+    ctx.animations.push(Animation {
+        id,
+        element,
+        targets,
+        from: None,
+        start: clock(lua).instant(),
+        duration,
+        easing,
+        on_done,
+    });
+
+    lua.create_userdata(AnimationHandle { id })
+}
+
+/// Schreibt den aktuellen Stand aller Animationen in den VDOM.
+/// Beendete Animationen werden entfernt und ihr `on_done` Callback aufgerufen.
+pub fn tick_animations(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    let ctx_ud: AnyUserData = globals.get("_animations")?;
+    let vdom_ud: AnyUserData = globals.get("_vdom")?;
+
+    let finished = {
+        let mut ctx = ctx_ud.borrow_mut::<AnimationContext>()?;
+        let vdom_context = vdom_ud.borrow::<DynamiteContext>()?;
+        let mut vdom = vdom_context.0.borrow_mut();
+        let now = clock(lua).instant();
+
+        let mut finished = Vec::new();
+        ctx.animations.retain_mut(|anim| {
+            let elapsed = now.saturating_duration_since(anim.start);
+            let progress = if anim.duration.is_zero() {
+                1.0
+            } else {
+                (elapsed.as_secs_f32() / anim.duration.as_secs_f32()).min(1.0)
+            };
+
+            if !anim.apply(&mut vdom, progress) {
+                return false;
+            }
+
+            if progress >= 1.0 {
+                if let Some(on_done) = anim.on_done.take() {
+                    finished.push(on_done);
+                }
+                false
+            } else {
+                true
+            }
+        });
+        finished
+    };
+
+    // erst nach dem Freigeben der Borrows aufrufen, da on_done neue Animationen starten darf.
+    // Schlägt ein Callback fehl, laufen die übrigen trotzdem, gemeldet wird der erste Fehler.
+    let mut first_error = None;
+    for on_done in finished {
+        if let Err(e) = on_done.call::<()>(()) {
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub fn init_animation_methods(lua: &Lua) -> Result<(), mlua::Error> {
+    let globals = lua.globals();
+    globals.set("_animations", lua.create_userdata(AnimationContext::default())?)?;
+    globals.set("animate", lua.create_function(animate)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_easing_endpoints() {
+        for easing in [Easing::Linear, Easing::EASE, Easing::EASE_IN, Easing::EASE_OUT, Easing::EASE_IN_OUT] {
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert_eq!(easing.apply(1.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_easing_curves() {
+        assert!((Easing::EASE_IN_OUT.apply(0.5) - 0.5).abs() < 1e-3);
+        assert!(Easing::EASE_IN.apply(0.25) < 0.25);
+        assert!(Easing::EASE_OUT.apply(0.25) > 0.25);
+    }
+
+    #[test]
+    fn test_easing_from_str() {
+        assert_eq!(Easing::from_str("linear"), Some(Easing::Linear));
+        assert_eq!(Easing::from_str("cubic-bezier(0.1, 0.2, 0.3, 0.4)"), Some(Easing::CubicBezier(0.1, 0.2, 0.3, 0.4)));
+        assert_eq!(Easing::from_str("cubic-bezier(2, 0, 0, 1)"), None);
+        assert_eq!(Easing::from_str("bounce"), None);
+    }
+
+    #[test]
+    fn test_interpolate_values() {
+        let a = TweenValue::Length(Dimension::Points(100.0));
+        let b = TweenValue::Length(Dimension::Points(300.0));
+        assert_eq!(a.interpolate(&b, 0.5), TweenValue::Length(Dimension::Points(200.0)));
+
+        let a = TweenValue::Color([0, 0, 0, 255]);
+        let b = TweenValue::Color([255, 100, 0, 255]);
+        assert_eq!(a.interpolate(&b, 0.5), TweenValue::Color([128, 50, 0, 255]));
+    }
+
+    #[test]
+    fn test_animate_survives_restyle() {
+        use std::rc::Rc;
+        use crate::{clock::ManualClock, scripting::{get_vdom, Engine, EngineOptions}};
+
+        let clock = Rc::new(ManualClock::new(std::time::SystemTime::UNIX_EPOCH));
+        let engine = Engine::with_options(&EngineOptions::default(), clock.clone()).unwrap();
+        let vdom = VDom::new(r#"
+            <html><head><style>#bar { width: 100px; }</style></head>
+            <body><div id="bar">42</div></body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        engine.lua.load(r#"
+            local bar = get_element_by_id("bar")
+            animate(bar, { opacity = 0 }, 0.5, "linear", function() error("on_done failed") end)
+            animate(bar, { width = "300px" }, 1, "linear", function() done = true end)
+        "#).exec().unwrap();
+
+        let width = || {
+            let vdom = get_vdom(&engine.lua).unwrap();
+            let mut vdom = vdom.borrow_mut();
+            vdom.restyle_if_needed();
+            let id = *vdom.id_map.get("bar").unwrap();
+            vdom.find_element_by_internal_id(&id).unwrap().get_style().width.clone()
+        };
+        let restyle = || get_vdom(&engine.lua).unwrap().borrow_mut().set_css_var("--accent", Some("red".to_string()));
+
+        get_vdom(&engine.lua).unwrap().borrow_mut().restyle();
+        tick_animations(&engine.lua).unwrap();
+        assert_eq!(width(), Some(Dimension::Points(100.0)));
+
+        clock.advance(Duration::from_millis(250));
+        tick_animations(&engine.lua).unwrap();
+        restyle();
+        assert_eq!(width(), Some(Dimension::Points(150.0)));
+
+        // das fehlerhafte on_done hält das zweite nicht auf
+        clock.advance(Duration::from_millis(750));
+        assert!(tick_animations(&engine.lua).is_err());
+        assert!(engine.lua.globals().get::<bool>("done").unwrap());
+        restyle();
+        assert_eq!(width(), Some(Dimension::Points(300.0)));
+
+        // beendete Animationen behalten ihren Endwert
+        clock.advance(Duration::from_millis(100));
+        tick_animations(&engine.lua).unwrap();
+        restyle();
+        assert_eq!(width(), Some(Dimension::Points(300.0)));
+
+        let error = engine.lua.load(r#"animate(get_element_by_id("bar"), { opacity = 0 }, math.huge)"#).exec().unwrap_err();
+        assert!(error.to_string().contains("invalid duration"));
+    }
+}
@@ -0,0 +1,158 @@
+//! # Skriptfehler
+//!
+//! Fehler aus Lua werden mit ihrer Herkunft gemeldet: der `<script>` Block, die Zeile in der
+//! HTML-Datei, der Traceback und der auslösende Handler. Die Skripte heißen dafür `script#<n>`,
+//! `n` ist ihr Index unter allen `<script>` Blöcken des Dokuments.
+
+use std::{collections::HashMap, fmt};
+
+/// Position eines `<script>` Blocks in der HTML-Datei, beginnend bei 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScriptPosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Ein Fehler beim Laden oder Ausführen eines Lua-Skripts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptError {
+    pub message: String,
+    /// Index des `<script>` Blocks, in dem der Fehler auftrat.
+    pub script: Option<usize>,
+    /// Zeile in der HTML-Datei.
+    pub line: Option<usize>,
+    /// Spalte in der HTML-Datei. Lua meldet nur Zeilen, bekannt ist sie daher
+    /// nur für die erste Zeile eines Skripts.
+    pub column: Option<usize>,
+    pub traceback: Option<String>,
+    /// HTML-ID des Elements, an dem der Handler steht.
+    pub element: Option<String>,
+    /// Auslöser, z. B. `on:update="update_data"`, `if="..."` oder `script#0`.
+    pub handler: Option<String>,
+}
+
+impl ScriptError {
+    /// Ein Fehler, der nicht aus Lua stammt, z. B. ein ungültiger Handler-Name.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            script: None,
+            line: None,
+            column: None,
+            traceback: None,
+            element: None,
+            handler: None,
+        }
+    }
+
+    /// Übernimmt Meldung und Traceback eines Lua-Fehlers und rechnet die Zeile auf die
+    /// HTML-Datei um, wenn der Fehler in einem bekannten Skript liegt.
+    pub fn from_lua(error: &mlua::Error, scripts: &HashMap<usize, ScriptPosition>) -> Self {
+        let (message, traceback) = split_lua_error(error);
+        let mut result = Self::new(message);
+        result.traceback = traceback;
+
+        if let Some((script, lua_line)) = script_location(&result.message) {
+            result.script = Some(script);
+            if let Some(position) = scripts.get(&script) {
+                result.line = Some(position.line + lua_line - 1);
+                result.column = (lua_line == 1).then_some(position.column);
+            }
+        }
+        result
+    }
+
+    pub fn with_handler(mut self, handler: impl Into<String>, element: Option<String>) -> Self {
+        self.handler = Some(handler.into());
+        self.element = element;
+        self
+    }
+
+    /// Die Felder als Lua-Tabelle für `on:error`.
+    pub fn to_lua(&self, lua: &mlua::Lua) -> mlua::Result<mlua::Table> {
+        let table = lua.create_table()?;
+        table.set("message", self.message.as_str())?;
+        table.set("script", self.script)?;
+        table.set("line", self.line)?;
+        table.set("column", self.column)?;
+        table.set("traceback", self.traceback.as_deref())?;
+        table.set("element", self.element.as_deref())?;
+        table.set("handler", self.handler.as_deref())?;
+        Ok(table)
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(handler) = &self.handler {
+            write!(f, "{} failed: ", handler)?;
+        }
+        write!(f, "{}", self.message)?;
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, " (line {}, column {})", line, column),
+            (Some(line), None) => write!(f, " (line {})", line),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Chunk-Name eines `<script>` Blocks.
+pub fn chunk_name(index: usize) -> String {
+    format!("script#{}", index)
+}
+
+/// Trennt Meldung und Traceback, mlua hängt den Traceback an die Meldung an.
+fn split_lua_error(error: &mlua::Error) -> (String, Option<String>) {
+    let (message, traceback) = match error {
+        mlua::Error::CallbackError { traceback, cause } => (cause.to_string(), Some(traceback.clone())),
+        mlua::Error::RuntimeError(message) => (message.clone(), None),
+        mlua::Error::SyntaxError { message, .. } => (message.clone(), None),
+        other => (other.to_string(), None),
+    };
+    match message.split_once("\nstack traceback:") {
+        Some((message, trace)) => (message.to_string(), Some(format!("stack traceback:{}", trace))),
+        None => (message, traceback),
+    }
+}
+
+/// Sucht `script#<n>...:<zeile>:` in der Meldung, z. B. `[string "script#0"]:12: boom`.
+fn script_location(message: &str) -> Option<(usize, usize)> {
+    let start = message.find("script#")? + "script#".len();
+    let rest = &message[start..];
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let script = rest[..digits].parse().ok()?;
+
+    let rest = rest[digits..].trim_start_matches(['"', ']']);
+    let rest = rest.strip_prefix(':')?;
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let line = rest[..digits].parse().ok()?;
+    Some((script, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_lua_error_to_html() {
+        let scripts = HashMap::from([(1, ScriptPosition { line: 20, column: 30 })]);
+        let error = mlua::Error::RuntimeError(
+            "[string \"script#1\"]:3: attempt to index a nil value\nstack traceback:\n\t[C]: in ?".to_string(),
+        );
+        let error = ScriptError::from_lua(&error, &scripts).with_handler("on:update=\"update_data\"", None);
+        assert_eq!(error.script, Some(1));
+        assert_eq!(error.line, Some(22));
+        assert_eq!(error.column, None);
+        assert_eq!(error.traceback.as_deref(), Some("stack traceback:\n\t[C]: in ?"));
+        assert_eq!(
+            error.to_string(),
+            "on:update=\"update_data\" failed: [string \"script#1\"]:3: attempt to index a nil value (line 22)"
+        );
+
+        let error = mlua::Error::RuntimeError("script#1:1: boom".to_string());
+        let error = ScriptError::from_lua(&error, &scripts);
+        assert_eq!((error.line, error.column), (Some(20), Some(30)));
+    }
+}
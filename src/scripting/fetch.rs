@@ -0,0 +1,482 @@
+//! # fetch
+//!
+//! HTTP-Anfragen laufen auf einigen Worker-Threads mit einem gemeinsamen Client, die Antwort
+//! kommt in einem späteren `run_frame` an – per Callback oder, innerhalb einer Coroutine, als
+//! Rückgabewert:
+//!
+//! ```lua
+//! fetch("https://api.example.com/weather", { timeout = 5 }, function(res)
+//!     if res.ok then state.weather = parse_json(res.body) end
+//! end)
+//!
+//! async(function()
+//!     local res = fetch("/weather", { method = "POST", headers = { ["Content-Type"] = "application/json" }, body = "{}" })
+//!     print(res.status, res.error)
+//! end)
+//! ```
+//!
+//! Optionen: `method` (Standard `GET`), `headers`, `body`, `timeout` in Sekunden (Standard 30,
+//! höchstens ein Tag) und `callback`. Die Antwort hat die Felder `ok`, `status`, `body`,
+//! `headers` und `error`; `status` fehlt, wenn keine Antwort kam. URLs, die mit `/` beginnen,
+//! werden gegen `EngineOptions::fetch_base_url` aufgelöst, z. B. für einen lokalen Mock-Server
+//! in Tests.
+//!
+//! Mit `EngineOptions::http_cache_dir` laufen GET-Anfragen über den `http_cache`, die Antwort
+//! nennt dann in `from_cache` und `age`, ob sie aus dem Cache stammt und wie alt sie ist.
+//!
+//! `get_webdata(url)` ist die blockierende Variante, es liefert den Body (`{}` ohne Antwort)
+//! und als zweiten Wert die Antwort wie bei `fetch`.
+//!
+//! Ohne `EngineOptions::allow_network` (z. B. in `EngineOptions::sandboxed()`) werfen beide
+//! einen Fehler.
+
+use std::{collections::HashMap, sync::{mpsc::{channel, Receiver, Sender}, Arc, Mutex}, thread, time::Duration};
+
+use log::warn;
+use mlua::{AnyUserData, Function, Lua, Table};
+use reqwest::blocking::Client;
+
+use super::{http_cache::{self, CacheEntry, HttpCache}, sandbox::limited, EngineOptions};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Längere Timeouts werden gekürzt, `reqwest` rechnet sie auf einen Zeitpunkt um.
+const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+/// So viele Anfragen laufen gleichzeitig, weitere warten in der Reihe.
+const WORKERS: usize = 4;
+
+/// Lua-Seite von `fetch`: Optionen auflösen, die Coroutine-Variante wartet per `yield`.
+const FETCH_PRELUDE: &str = r#"
+function fetch(url, opts, callback)
+    if type(opts) == "function" then
+        opts, callback = nil, opts
+    end
+    opts = opts or {}
+    callback = callback or opts.callback
+    if callback then
+        return _fetch_start(url, opts, callback)
+    end
+
+    local co, main = nil, true
+    if coroutine then
+        co, main = coroutine.running()
+    end
+    if main then
+        error("fetch needs a callback outside of a coroutine", 2)
+    end
+    _fetch_start(url, opts, function(response)
+        local ok, err = coroutine.resume(co, response)
+        if not ok then
+            error(err, 0)
+        end
+    end)
+    return coroutine.yield()
+end
+
+function async(fn, ...)
+    local co = coroutine.create(fn)
+    local ok, err = coroutine.resume(co, ...)
+    if not ok then
+        error(err, 0)
+    end
+    return co
+end
+"#;
+
+struct FetchRequest {
+    url: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    timeout: Duration,
+}
+
+#[derive(Debug, Default)]
+struct FetchResponse {
+    status: Option<u16>,
+    body: String,
+    headers: Vec<(String, String)>,
+    error: Option<String>,
+    from_cache: bool,
+    /// Sekunden seit dem Abruf, nur bei Antworten aus dem Cache nicht 0.
+    age: u64,
+}
+
+impl FetchResponse {
+    fn failed(error: impl ToString) -> Self {
+        Self { error: Some(error.to_string()), ..Default::default() }
+    }
+
+    fn cached(entry: &CacheEntry, now: u64) -> Self {
+        Self {
+            status: Some(entry.status),
+            body: entry.body.clone(),
+            headers: entry.headers.clone(),
+            error: None,
+            from_cache: true,
+            age: entry.age(now),
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        self.error.is_none() && self.status.is_some_and(|status| (200..300).contains(&status))
+    }
+
+    fn to_lua(&self, lua: &Lua) -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        table.set("ok", self.is_ok())?;
+        table.set("status", self.status)?;
+        table.set("body", self.body.as_str())?;
+        let headers = lua.create_table()?;
+        for (name, value) in &self.headers {
+            headers.set(name.as_str(), value.as_str())?;
+        }
+        table.set("headers", headers)?;
+        table.set("error", self.error.as_deref())?;
+        table.set("from_cache", self.from_cache)?;
+        table.set("age", self.age)?;
+        Ok(table)
+    }
+}
+
+/// Wird als `_fetch` in den Lua-Globals abgelegt.
+pub struct FetchContext {
+    next_id: u64,
+    base_url: Option<String>,
+    cache: Option<HttpCache>,
+    /// Callbacks der laufenden Anfragen.
+    pending: HashMap<u64, Function>,
+    sender: Sender<(u64, FetchResponse)>,
+    receiver: Receiver<(u64, FetchResponse)>,
+    /// Von allen Anfragen geteilt, erst bei der ersten erzeugt.
+    client: Option<Client>,
+    /// Warteschlange der Worker, die mit der ersten `fetch` Anfrage starten.
+    jobs: Option<Sender<(u64, FetchRequest)>>,
+}
+impl mlua::UserData for FetchContext {}
+
+impl FetchContext {
+    fn client(&mut self) -> Result<Client, String> {
+        if let Some(client) = &self.client {
+            return Ok(client.clone());
+        }
+        let client = Client::builder().build().map_err(|e| e.to_string())?;
+        self.client = Some(client.clone());
+        Ok(client)
+    }
+
+    /// Reiht die Anfrage für die Worker ein, die Antwort kommt über `receiver`.
+    fn submit(&mut self, id: u64, request: FetchRequest) {
+        if self.jobs.is_none() {
+            let client = match self.client() {
+                Ok(client) => client,
+                Err(e) => {
+                    let _ = self.sender.send((id, FetchResponse::failed(e)));
+                    return;
+                }
+            };
+            let (jobs, queue) = channel::<(u64, FetchRequest)>();
+            let queue = Arc::new(Mutex::new(queue));
+            for _ in 0..WORKERS {
+                let queue = queue.clone();
+                let client = client.clone();
+                let cache = self.cache.clone();
+                let responses = self.sender.clone();
+                // endet, sobald der Kontext und mit ihm die Warteschlange weg ist
+                thread::spawn(move || loop {
+                    let job = queue.lock().map(|guard| guard.recv());
+                    let Ok(Ok((id, request))) = job else {
+                        break;
+                    };
+                    let _ = responses.send((id, perform(&client, request, cache.as_ref())));
+                });
+            }
+            self.jobs = Some(jobs);
+        }
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send((id, request));
+        }
+    }
+}
+
+fn with_fetch<R>(lua: &Lua, f: impl FnOnce(&mut FetchContext) -> R) -> mlua::Result<R> {
+    let ctx_ud: AnyUserData = lua.globals().get("_fetch")?;
+    let mut ctx = ctx_ud.borrow_mut::<FetchContext>()?;
+    Ok(f(&mut ctx))
+}
+
+/// Führt die Anfrage aus, GET-Anfragen über den Cache.
+fn perform(client: &Client, mut request: FetchRequest, cache: Option<&HttpCache>) -> FetchResponse {
+    let Some(cache) = cache.filter(|_| request.method.eq_ignore_ascii_case("GET")) else {
+        return send(client, request);
+    };
+    let url = request.url.clone();
+    let now = http_cache::now();
+    let cached = cache.load(&url);
+    if let Some(entry) = cached.as_ref().filter(|entry| entry.is_fresh(now)) {
+        return FetchResponse::cached(entry, now);
+    }
+    if let Some(entry) = &cached {
+        request.headers.extend(entry.validators());
+    }
+
+    let response = send(client, request);
+    match cached {
+        Some(entry) if response.status == Some(304) => {
+            let entry = entry.revalidated(response.headers, now);
+            cache.store(&url, &entry);
+            FetchResponse::cached(&entry, now)
+        }
+        Some(entry) if response.error.is_some() || response.status.is_some_and(|status| status >= 500) => {
+            warn!(
+                "fetching {} failed ({}), using cached response from {}s ago",
+                url,
+                response.error.as_deref().unwrap_or("server error"),
+                entry.age(now)
+            );
+            FetchResponse::cached(&entry, now)
+        }
+        _ => {
+            let entry = response
+                .status
+                .filter(|_| response.is_ok())
+                .and_then(|status| CacheEntry::new(status, response.body.clone(), response.headers.clone(), now));
+            if let Some(entry) = entry {
+                cache.store(&url, &entry);
+            }
+            response
+        }
+    }
+}
+
+fn send(client: &Client, request: FetchRequest) -> FetchResponse {
+    let method = match reqwest::Method::from_bytes(request.method.to_uppercase().as_bytes()) {
+        Ok(method) => method,
+        Err(_) => return FetchResponse::failed(format!("invalid method: {}", request.method)),
+    };
+
+    let mut builder = client.request(method, &request.url).timeout(request.timeout);
+    for (name, value) in request.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(body) = request.body {
+        builder = builder.body(body);
+    }
+
+    let response = match builder.send() {
+        Ok(response) => response,
+        Err(e) => return FetchResponse::failed(e),
+    };
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    match response.text() {
+        Ok(body) => FetchResponse { status: Some(status), body, headers, ..Default::default() },
+        Err(e) => FetchResponse { status: Some(status), headers, ..FetchResponse::failed(e) },
+    }
+}
+
+/// `_fetch_start(url, opts, callback)`, startet die Anfrage und liefert ihre ID.
+fn fetch_start(lua: &Lua, (url, opts, callback): (String, Table, Function)) -> mlua::Result<u64> {
+    let method = opts.get::<Option<String>>("method")?.unwrap_or_else(|| "GET".to_string());
+    let mut headers = Vec::new();
+    if let Some(table) = opts.get::<Option<Table>>("headers")? {
+        for pair in table.pairs::<String, String>() {
+            headers.push(pair?);
+        }
+    }
+    let body = opts.get::<Option<String>>("body")?;
+    let timeout = match opts.get::<Option<f64>>("timeout")? {
+        Some(secs) => Duration::try_from_secs_f64(secs)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+            .ok_or_else(|| mlua::Error::runtime(format!("invalid timeout: {}", secs)))?
+            .min(MAX_TIMEOUT),
+        None => DEFAULT_TIMEOUT,
+    };
+
+    with_fetch(lua, |ctx| {
+        let id = ctx.next_id;
+        ctx.next_id += 1;
+        ctx.pending.insert(id, callback);
+        let url = match (&ctx.base_url, url.starts_with('/')) {
+            (Some(base), true) => format!("{}{}", base.trim_end_matches('/'), url),
+            _ => url,
+        };
+        ctx.submit(id, FetchRequest { url, method, headers, body, timeout });
+        id
+    })
+}
+
+/// Blockiert den Frame bis zur Antwort, besser `fetch` nutzen.
+fn get_webdata(lua: &Lua, url: String) -> mlua::Result<(String, Table)> {
+    let (client, cache) = with_fetch(lua, |ctx| (ctx.client(), ctx.cache.clone()))?;
+    let request = FetchRequest { url, method: "GET".to_string(), headers: Vec::new(), body: None, timeout: DEFAULT_TIMEOUT };
+    let response = match client {
+        Ok(client) => perform(&client, request, cache.as_ref()),
+        Err(e) => FetchResponse::failed(e),
+    };
+    let body = match response.error {
+        Some(_) => "{}".to_string(),
+        None => response.body.clone(),
+    };
+    Ok((body, response.to_lua(lua)?))
+}
+
+/// Ruft die Callbacks aller inzwischen beantworteten Anfragen auf.
+/// Schlägt ein Callback fehl, laufen die übrigen trotzdem, gemeldet wird der erste Fehler.
+pub fn poll_fetches(lua: &Lua) -> mlua::Result<()> {
+    let done: Vec<(Function, FetchResponse)> = with_fetch(lua, |ctx| {
+        let mut done = Vec::new();
+        while let Ok((id, response)) = ctx.receiver.try_recv() {
+            if let Some(callback) = ctx.pending.remove(&id) {
+                done.push((callback, response));
+            }
+        }
+        done
+    })?;
+
+    let mut first_error = None;
+    for (callback, response) in done {
+        let result = limited(lua, || callback.call::<()>(response.to_lua(lua)?));
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub fn init_fetch_methods(lua: &Lua, options: &EngineOptions) -> mlua::Result<()> {
+    let (sender, receiver) = channel();
+    let globals = lua.globals();
+    globals.set("_fetch", lua.create_userdata(FetchContext {
+        next_id: 0,
+        base_url: options.fetch_base_url.clone(),
+        cache: options.http_cache_dir.clone().map(HttpCache::new),
+        pending: HashMap::new(),
+        sender,
+        receiver,
+        client: None,
+        jobs: None,
+    })?)?;
+    if options.allow_network {
+        globals.set("_fetch_start", lua.create_function(fetch_start)?)?;
+        globals.set("get_webdata", lua.create_function(get_webdata)?)?;
+    } else {
+        let disabled = lua.create_function(|_, _: mlua::MultiValue| -> mlua::Result<()> {
+            Err(mlua::Error::runtime("network access is disabled"))
+        })?;
+        globals.set("_fetch_start", disabled.clone())?;
+        globals.set("get_webdata", disabled)?;
+    }
+    lua.load(FETCH_PRELUDE).set_name("fetch").exec()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{Read, Write}, net::TcpListener, time::Instant};
+
+    use super::*;
+
+    /// Beantwortet genau eine Anfrage mit `status` und `body`.
+    fn mock_server(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 4096];
+            let _ = stream.read(&mut buffer);
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}", address)
+    }
+
+    fn wait_for_fetches(lua: &Lua) {
+        let started = Instant::now();
+        while with_fetch(lua, |ctx| ctx.pending.len()).unwrap() > 0 {
+            assert!(started.elapsed() < Duration::from_secs(10), "fetch timed out");
+            thread::sleep(Duration::from_millis(5));
+            poll_fetches(lua).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fetch_callback_and_coroutine() {
+        let lua = Lua::new();
+        let options = EngineOptions { fetch_base_url: Some(mock_server("200 OK", "hello")), ..Default::default() };
+        init_fetch_methods(&lua, &options).unwrap();
+        lua.load(r#"
+            async(function()
+                local res = fetch("/data", { method = "POST", body = "x" })
+                result = res.status .. " " .. res.body .. " " .. tostring(res.ok)
+            end)
+        "#).exec().unwrap();
+        // noch nicht beantwortet, der Frame läuft weiter
+        assert_eq!(lua.globals().get::<Option<String>>("result").unwrap(), None);
+
+        wait_for_fetches(&lua);
+        assert_eq!(lua.globals().get::<String>("result").unwrap(), "200 hello true");
+    }
+
+    #[test]
+    fn test_fetch_reports_errors() {
+        let lua = Lua::new();
+        let url = mock_server("404 Not Found", "missing");
+        init_fetch_methods(&lua, &EngineOptions::default()).unwrap();
+        lua.globals().set("url", url).unwrap();
+        lua.load(r#"
+            fetch(url, function(res) status, ok = res.status, res.ok end)
+            fetch("http://127.0.0.1:1/", { timeout = 1 }, function(res) failed = res.error ~= nil and res.status == nil end)
+        "#).exec().unwrap();
+        wait_for_fetches(&lua);
+        assert_eq!(lua.globals().get::<u16>("status").unwrap(), 404);
+        assert!(!lua.globals().get::<bool>("ok").unwrap());
+        assert!(lua.globals().get::<bool>("failed").unwrap());
+        assert!(lua.load("fetch('/x')").exec().is_err());
+        for timeout in ["1e30", "0", "0 / 0"] {
+            let error = lua.load(format!("fetch(url, {{ timeout = {} }}, print)", timeout)).exec().unwrap_err();
+            assert!(error.to_string().contains("invalid timeout"), "{}", timeout);
+        }
+    }
+
+    #[test]
+    fn test_network_can_be_disabled() {
+        let lua = Lua::new();
+        init_fetch_methods(&lua, &EngineOptions::sandboxed()).unwrap();
+        let error = lua.load("fetch('http://127.0.0.1:1/', function() end)").exec().unwrap_err();
+        assert!(error.to_string().contains("network access is disabled"));
+        assert!(lua.load("get_webdata('http://127.0.0.1:1/')").exec().is_err());
+    }
+
+    #[test]
+    fn test_cached_response_when_offline() {
+        let cache = HttpCache::new(std::env::temp_dir().join(format!("dynamite-cache-{}", ulid::Ulid::new())));
+        let url = format!("{}/weather", mock_server("200 OK", "[1]"));
+        let request = || FetchRequest {
+            url: url.clone(),
+            method: "GET".to_string(),
+            headers: Vec::new(),
+            body: None,
+            timeout: Duration::from_secs(5),
+        };
+
+        let client = Client::new();
+        let online = perform(&client, request(), Some(&cache));
+        assert!(online.is_ok() && !online.from_cache);
+        // der Mock-Server antwortet nur einmal, danach ist er nicht mehr erreichbar
+        let offline = perform(&client, request(), Some(&cache));
+        assert!(offline.is_ok() && offline.from_cache);
+        assert_eq!(offline.body, "[1]");
+        assert!(perform(&client, request(), None).error.is_some());
+    }
+}
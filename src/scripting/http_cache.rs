@@ -0,0 +1,217 @@
+//! # HTTP-Cache
+//!
+//! Speichert GET-Antworten von `fetch` und `get_webdata` auf der Platte, wenn
+//! `EngineOptions::http_cache_dir` gesetzt ist. Eine Datei je URL, als JSON.
+//!
+//! - `Cache-Control: max-age=<s>` – bis dahin kommt die Antwort ohne Anfrage aus dem Cache.
+//!   Ohne `max-age` oder mit `no-cache` wird jedes Mal nachgefragt.
+//! - `ETag` / `Last-Modified` – beim Nachfragen als `If-None-Match` / `If-Modified-Since`
+//!   mitgeschickt, ein `304` liefert die gespeicherte Antwort.
+//! - `no-store` – die Antwort wird nicht gespeichert.
+//! - Fällt die Anfrage aus oder antwortet der Server mit `5xx`, gilt die letzte gute Antwort,
+//!   egal wie alt (stale-if-error).
+//!
+//! Die Antwort sagt mit `from_cache` und `age` (Sekunden seit dem Abruf), woher sie stammt.
+
+use std::{fs, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+
+use log::warn;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone)]
+pub struct HttpCache {
+    dir: PathBuf,
+}
+
+/// Eine gespeicherte Antwort.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub status: u16,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+    /// Zeitpunkt des Abrufs bzw. der letzten Bestätigung, Sekunden seit 1970.
+    pub stored_at: u64,
+    /// Ohne Wert muss vor jeder Verwendung nachgefragt werden.
+    pub max_age: Option<u64>,
+}
+
+impl CacheEntry {
+    /// `None`, wenn die Antwort nicht gespeichert werden darf.
+    pub fn new(status: u16, body: String, headers: Vec<(String, String)>, now: u64) -> Option<Self> {
+        let mut entry = Self { status, body, headers, stored_at: now, max_age: None };
+        entry.max_age = match entry.cache_control() {
+            CacheControl::NoStore => return None,
+            CacheControl::MaxAge(max_age) => Some(max_age),
+            CacheControl::Revalidate => None,
+        };
+        Some(entry)
+    }
+
+    pub fn age(&self, now: u64) -> u64 {
+        now.saturating_sub(self.stored_at)
+    }
+
+    pub fn is_fresh(&self, now: u64) -> bool {
+        self.max_age.is_some_and(|max_age| self.age(now) < max_age)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Header für eine bedingte Anfrage.
+    pub fn validators(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = self.header("etag") {
+            headers.push(("If-None-Match".to_string(), etag.to_string()));
+        }
+        if let Some(modified) = self.header("last-modified") {
+            headers.push(("If-Modified-Since".to_string(), modified.to_string()));
+        }
+        headers
+    }
+
+    /// Nach einem `304`, die neuen Header gehen vor.
+    pub fn revalidated(mut self, headers: Vec<(String, String)>, now: u64) -> Self {
+        for (name, value) in headers {
+            match self.headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(&name)) {
+                Some(existing) => existing.1 = value,
+                None => self.headers.push((name, value)),
+            }
+        }
+        self.stored_at = now;
+        self.max_age = match self.cache_control() {
+            CacheControl::MaxAge(max_age) => Some(max_age),
+            _ => None,
+        };
+        self
+    }
+
+    fn cache_control(&self) -> CacheControl {
+        let Some(header) = self.header("cache-control") else {
+            return CacheControl::Revalidate;
+        };
+        let directives: Vec<String> = header.split(',').map(|d| d.trim().to_ascii_lowercase()).collect();
+        if directives.iter().any(|d| d == "no-store") {
+            return CacheControl::NoStore;
+        }
+        if directives.iter().any(|d| d == "no-cache") {
+            return CacheControl::Revalidate;
+        }
+        directives
+            .iter()
+            .find_map(|d| d.strip_prefix("max-age=")?.trim_matches('"').parse().ok())
+            .map_or(CacheControl::Revalidate, CacheControl::MaxAge)
+    }
+
+    fn to_json(&self, url: &str) -> Value {
+        json!({
+            "url": url,
+            "status": self.status,
+            "body": self.body,
+            "headers": self.headers,
+            "stored_at": self.stored_at,
+            "max_age": self.max_age,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let headers = value["headers"]
+            .as_array()?
+            .iter()
+            .filter_map(|pair| Some((pair[0].as_str()?.to_string(), pair[1].as_str()?.to_string())))
+            .collect();
+        Some(Self {
+            status: u16::try_from(value["status"].as_u64()?).ok()?,
+            body: value["body"].as_str()?.to_string(),
+            headers,
+            stored_at: value["stored_at"].as_u64()?,
+            max_age: value["max_age"].as_u64(),
+        })
+    }
+}
+
+enum CacheControl {
+    NoStore,
+    Revalidate,
+    MaxAge(u64),
+}
+
+impl HttpCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Die gespeicherte Antwort für `url`, beschädigte Dateien gelten als fehlend.
+    pub fn load(&self, url: &str) -> Option<CacheEntry> {
+        let data = fs::read_to_string(self.path(url)).ok()?;
+        let value: Value = serde_json::from_str(&data).ok()?;
+        // Kollisionen des Hashs
+        if value["url"].as_str() != Some(url) {
+            return None;
+        }
+        CacheEntry::from_json(&value)
+    }
+
+    /// Fehler beim Schreiben werden nur geloggt, ohne Cache geht es auch.
+    pub fn store(&self, url: &str, entry: &CacheEntry) {
+        let path = self.path(url);
+        let temp = path.with_extension("tmp");
+        let result = fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&temp, entry.to_json(url).to_string()))
+            .and_then(|_| fs::rename(&temp, &path));
+        if let Err(e) = result {
+            warn!("writing HTTP cache for {} failed: {}", url, e);
+        }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", fnv1a(url.as_bytes())))
+    }
+}
+
+/// Sekunden seit 1970.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Stabil über Programmversionen hinweg, anders als `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_cache_control_and_storage() {
+        let fresh = CacheEntry::new(200, "{}".into(), headers(&[("Cache-Control", "public, max-age=60")]), 1000).unwrap();
+        assert!(fresh.is_fresh(1059));
+        assert!(!fresh.is_fresh(1060));
+        assert!(CacheEntry::new(200, "{}".into(), headers(&[("cache-control", "no-store")]), 1000).is_none());
+
+        let etag = CacheEntry::new(200, "[1]".into(), headers(&[("ETag", "\"v1\""), ("Cache-Control", "no-cache")]), 1000).unwrap();
+        assert!(!etag.is_fresh(1000));
+        assert_eq!(etag.validators(), headers(&[("If-None-Match", "\"v1\"")]));
+
+        let cache = HttpCache::new(std::env::temp_dir().join(format!("dynamite-cache-{}", ulid::Ulid::new())));
+        assert_eq!(cache.load("http://example.com/a"), None);
+        cache.store("http://example.com/a", &etag);
+        assert_eq!(cache.load("http://example.com/a"), Some(etag.clone()));
+        assert_eq!(cache.load("http://example.com/b"), None);
+
+        let revalidated = etag.revalidated(headers(&[("cache-control", "max-age=10")]), 2000);
+        assert_eq!(revalidated.age(2005), 5);
+        assert!(revalidated.is_fresh(2005));
+        assert_eq!(revalidated.body, "[1]");
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+}
@@ -0,0 +1,220 @@
+//! # Skripte in Templates
+//!
+//! `<script>` Blöcke innerhalb eines `<template>` laufen nicht im globalen Lua-State, sondern
+//! einmal für jede Instanz, die `create_element` aus dem Template erzeugt. Jede Instanz hat
+//! ihre eigene Umgebung:
+//!
+//! - `self` – das Handle des Elements
+//! - `props` – die an `create_element(id, props)` übergebene Tabelle
+//! - `state` – eine Tabelle nur für diese Instanz, der globale reaktive Zustand bleibt `_G.state`
+//!
+//! Globale Zuweisungen des Skripts landen in der Umgebung, Lua-Globals bleiben lesbar.
+//! Funktionen der Umgebung lassen sich als Methoden des Handles aufrufen (`card:refresh()`).
+//!
+//! `on_mount` wird aufgerufen, sobald das Element im Dokument steht, `on_unmount` wenn es
+//! wieder verschwindet, z. B. mit einem ausgeblendeten `if` Zweig. Die Umgebung bleibt dabei
+//! erhalten, beim erneuten Einblenden folgt wieder `on_mount`. Ist das Element endgültig weg
+//! (auch in keinem ausgeblendeten Zweig) oder wurde es nie eingefügt und sein Handle ist
+//! nicht mehr erreichbar, wird die Instanz verworfen.
+
+use std::{cell::RefCell, collections::HashMap, rc::{Rc, Weak}};
+
+use mlua::{AnyUserData, Function, Lua, Table, Value};
+use ulid::Ulid;
+
+use super::{element_handle, error::chunk_name, get_vdom, sandbox::limited, ElementContext};
+use crate::{parser::ScriptSource, vdom::VNode};
+
+struct Instance {
+    env: Table,
+    mounted: bool,
+    /// Der noch nicht eingefügte Knoten aus `create_element`, solange ein Handle lebt.
+    pending: Weak<RefCell<Option<VNode>>>,
+}
+
+impl Instance {
+    fn is_pending(&self) -> bool {
+        self.pending.upgrade().is_some_and(|node| node.borrow().is_some())
+    }
+}
+
+/// Wird als `_instances` in den Lua-Globals abgelegt.
+#[derive(Default)]
+pub struct InstanceContext {
+    /// Kompilierte Skripte nach Template-ID, in der Reihenfolge des Dokuments.
+    scripts: HashMap<String, Vec<Function>>,
+    instances: HashMap<Ulid, Instance>,
+}
+impl mlua::UserData for InstanceContext {}
+
+fn with_instances<R>(lua: &Lua, f: impl FnOnce(&mut InstanceContext) -> R) -> mlua::Result<R> {
+    let ctx_ud: AnyUserData = lua.globals().get("_instances")?;
+    let mut ctx = ctx_ud.borrow_mut::<InstanceContext>()?;
+    Ok(f(&mut ctx))
+}
+
+/// Kompiliert die Skripte der Templates, ausgeführt werden sie erst mit `create_element`.
+/// Sie heißen wie globale Skripte `script#<n>`, Fehler nennen so die Zeile in der HTML-Datei.
+pub fn load_template_scripts(lua: &Lua, scripts: HashMap<String, Vec<ScriptSource>>) -> mlua::Result<()> {
+    let mut compiled = HashMap::new();
+    for (template_id, sources) in scripts {
+        let mut functions = Vec::new();
+        for script in sources {
+            // in derselben Zeile, damit Fehlermeldungen die Zeilen des Skripts nennen
+            let function = lua
+                .load(format!("local _ENV = ...; {}", script.source))
+                .set_name(chunk_name(script.index))
+                .into_function()?;
+            functions.push(function);
+        }
+        compiled.insert(template_id, functions);
+    }
+    with_instances(lua, |ctx| ctx.scripts.extend(compiled))
+}
+
+/// Führt das Skript des Templates für eine neue Instanz aus, ohne Skript passiert nichts.
+pub fn instantiate(lua: &Lua, template_id: &str, handle: AnyUserData, internal_id: Ulid, props: Table) -> mlua::Result<()> {
+    let Some(scripts) = with_instances(lua, |ctx| ctx.scripts.get(template_id).cloned())? else {
+        return Ok(());
+    };
+
+    let pending = Rc::downgrade(&handle.borrow::<ElementContext>()?.temp_node);
+    // `self` nur schwach, sonst hielte die Umgebung das Handle und damit einen nie eingefügten
+    // Knoten für immer fest. Ist es eingesammelt, genügt ein neues Handle auf den VDOM-Knoten.
+    let weak = lua.create_table()?;
+    let weak_meta = lua.create_table()?;
+    weak_meta.set("__mode", "v")?;
+    weak.set_metatable(Some(weak_meta));
+    weak.raw_set("self", handle)?;
+
+    let env = lua.create_table()?;
+    env.raw_set("props", props)?;
+    env.raw_set("state", lua.create_table()?)?;
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.create_function(move |lua, (_, key): (Table, Value)| {
+        if matches!(&key, Value::String(name) if &*name.as_bytes() == b"self") {
+            return match weak.raw_get::<Value>("self")? {
+                Value::Nil => element_handle(lua, internal_id).map(Value::UserData),
+                handle => Ok(handle),
+            };
+        }
+        lua.globals().raw_get::<Value>(key)
+    })?)?;
+    env.set_metatable(Some(meta));
+
+    for script in scripts {
+        limited(lua, || script.call::<()>(env.clone()))?;
+    }
+    with_instances(lua, |ctx| {
+        ctx.instances.insert(internal_id, Instance { env, mounted: false, pending });
+    })
+}
+
+/// Liefert einen Wert aus der Umgebung der Instanz, z. B. eine Methode.
+pub fn instance_value(lua: &Lua, internal_id: &Ulid, name: &str) -> mlua::Result<Value> {
+    let env = with_instances(lua, |ctx| ctx.instances.get(internal_id).map(|i| i.env.clone()))?;
+    match env {
+        Some(env) => env.raw_get(name),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// Ruft `on_mount` / `on_unmount` für Instanzen auf, die seit dem letzten Aufruf in das
+/// Dokument gekommen oder aus ihm verschwunden sind, und verwirft die endgültig entfernten.
+pub fn update_instances(lua: &Lua) -> mlua::Result<()> {
+    if with_instances(lua, |ctx| ctx.instances.is_empty())? {
+        return Ok(());
+    }
+    let vdom = get_vdom(lua)?;
+    let changed: Vec<(Table, bool)> = with_instances(lua, |ctx| {
+        let vdom = vdom.borrow();
+        // ein Durchlauf durch den Baum für alle Instanzen
+        let present = vdom.tree_ids();
+        let mut changed = Vec::new();
+        ctx.instances.retain(|id, instance| {
+            let mounted = present.contains(id);
+            if mounted != instance.mounted {
+                instance.mounted = mounted;
+                changed.push((instance.env.clone(), mounted));
+            }
+            mounted || instance.is_pending() || vdom.find_node(id).is_some()
+        });
+        changed
+    })?;
+
+    // die Hooks dürfen wieder auf den VDOM und andere Instanzen zugreifen
+    for (env, mounted) in changed {
+        let hook = if mounted { "on_mount" } else { "on_unmount" };
+        if let Some(function) = env.raw_get::<Option<Function>>(hook)? {
+            limited(lua, || function.call::<()>(()))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn init_instance_methods(lua: &Lua) -> mlua::Result<()> {
+    lua.globals().set("_instances", lua.create_userdata(InstanceContext::default())?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{clock::SystemClock, document::{FindByIdMut, VDom}, parser, scripting::{Engine, EngineOptions, ScriptError}};
+
+    #[test]
+    fn test_removed_instances_are_dropped() {
+        let html = r#"
+            <html><body>
+                <div id="list"></div>
+                <template id="card">
+                    <div class="card">{{ name }}</div>
+                    <script>
+                        function on_mount() mounted = (mounted or 0) + 1 end
+                        function on_unmount() unmounted = (unmounted or 0) + 1 end
+                    </script>
+                </template>
+            </body></html>
+        "#;
+        let mut engine = Engine::with_options(&EngineOptions::default(), Rc::new(SystemClock::default())).unwrap();
+        engine.load_template_scripts(parser::load_template_scripts(html).unwrap()).unwrap();
+        engine.begin(&VDom::new(html).unwrap()).unwrap();
+        let count = |lua: &Lua| with_instances(lua, |ctx| ctx.instances.len()).unwrap();
+
+        engine.lua.load(r#"
+            add_element("list", create_element("card", { name = "A" }))
+            kept = create_element("card", { name = "B" })
+        "#).exec().unwrap();
+        update_instances(&engine.lua).unwrap();
+        assert_eq!(count(&engine.lua), 2);
+
+        // nie eingefügt und ohne Handle
+        engine.lua.load("kept = nil collectgarbage()").exec().unwrap();
+        update_instances(&engine.lua).unwrap();
+        assert_eq!(count(&engine.lua), 1);
+
+        let vdom = get_vdom(&engine.lua).unwrap();
+        let list = *vdom.borrow().id_map.get("list").unwrap();
+        if let Some(VNode::Element(el)) = vdom.borrow_mut().root.find_by_internal_id_mut(&list) {
+            el.children.clear();
+        }
+        update_instances(&engine.lua).unwrap();
+        assert_eq!(count(&engine.lua), 0);
+        let (mounted, unmounted): (i64, i64) = engine.lua.load("return mounted, unmounted").eval().unwrap();
+        assert_eq!((mounted, unmounted), (1, 1));
+    }
+
+    #[test]
+    fn test_template_errors_name_html_line() {
+        let html = "<html><body>\n<script>a = 1</script>\n<template id=\"card\"><div></div>\n<script>\nlocal x = 1\nerror('boom')\n</script></template>\n</body></html>";
+        let mut engine = Engine::with_options(&EngineOptions::default(), Rc::new(SystemClock::default())).unwrap();
+        engine.load_template_scripts(parser::load_template_scripts(html).unwrap()).unwrap();
+        engine.begin(&VDom::new(html).unwrap()).unwrap();
+
+        let error = engine.lua.load("create_element('card', {})").exec().unwrap_err();
+        let error = ScriptError::from_lua(&error, &engine.script_positions);
+        assert_eq!((error.script, error.line), (Some(1), Some(6)));
+    }
+}
@@ -0,0 +1,139 @@
+//! # Nachrichten
+//!
+//! Kanal zwischen Host und Skripten. `Dynamite::post_message` stellt eine Nachricht zu, die
+//! im nächsten `run_frame` an die Handler geht:
+//!
+//! ```lua
+//! on_message("sensor", function(name, data) state.temp = data.temp end)
+//! on_message(function(name, data) print("message", name) end)   -- alle Nachrichten
+//!
+//! emit("selected", { city = "Berlin" })
+//! ```
+//!
+//! `emit` sammelt Ereignisse für den Host, der sie mit `Dynamite::drain_events` abholt.
+//! Die Daten gehen als JSON hin und her, siehe `to_json`.
+
+use std::collections::VecDeque;
+
+use mlua::{AnyUserData, Function, Lua, Value};
+use serde_json::Value as JsonValue;
+
+use super::{json_to_lua, lua_to_json, sandbox::limited};
+
+/// Eine Nachricht vom Host oder ein Ereignis aus `emit`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub name: String,
+    pub data: JsonValue,
+}
+
+/// Wird als `_messages` in den Lua-Globals abgelegt.
+#[derive(Default)]
+pub struct MessageContext {
+    inbox: VecDeque<Message>,
+    outbox: Vec<Message>,
+    /// Handler mit dem Namen, auf den sie hören, ohne Namen hören sie auf alle.
+    handlers: Vec<(Option<String>, Function)>,
+}
+impl mlua::UserData for MessageContext {}
+
+fn with_messages<R>(lua: &Lua, f: impl FnOnce(&mut MessageContext) -> R) -> mlua::Result<R> {
+    let ctx_ud: AnyUserData = lua.globals().get("_messages")?;
+    let mut ctx = ctx_ud.borrow_mut::<MessageContext>()?;
+    Ok(f(&mut ctx))
+}
+
+pub fn post_message(lua: &Lua, message: Message) -> mlua::Result<()> {
+    with_messages(lua, |ctx| ctx.inbox.push_back(message))
+}
+
+/// Die seit dem letzten Aufruf mit `emit` erzeugten Ereignisse.
+pub fn drain_events(lua: &Lua) -> mlua::Result<Vec<Message>> {
+    with_messages(lua, |ctx| std::mem::take(&mut ctx.outbox))
+}
+
+/// Stellt die wartenden Nachrichten zu, in der Reihenfolge ihres Eingangs. Nachrichten, die
+/// Handler dabei auslösen, folgen im nächsten Frame.
+/// Schlägt ein Handler fehl, laufen die übrigen trotzdem, gemeldet wird der erste Fehler.
+pub fn deliver_messages(lua: &Lua) -> mlua::Result<()> {
+    let (messages, handlers) = with_messages(lua, |ctx| (std::mem::take(&mut ctx.inbox), ctx.handlers.clone()))?;
+
+    let mut first_error = None;
+    for message in messages {
+        for (filter, handler) in &handlers {
+            if filter.as_ref().is_some_and(|name| *name != message.name) {
+                continue;
+            }
+            let result = limited(lua, || handler.call::<()>((message.name.as_str(), json_to_lua(lua, &message.data)?)));
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// `on_message(name, fn)` oder `on_message(fn)` für alle Nachrichten.
+fn on_message(lua: &Lua, (first, second): (Value, Option<Function>)) -> mlua::Result<()> {
+    let (filter, handler) = match (first, second) {
+        (Value::Function(handler), None) => (None, handler),
+        (Value::String(name), Some(handler)) => (Some(name.to_str()?.to_string()), handler),
+        _ => return Err(mlua::Error::runtime("usage: on_message([name,] handler)")),
+    };
+    with_messages(lua, |ctx| ctx.handlers.push((filter, handler)))
+}
+
+/// `emit(name, data)`
+fn emit(lua: &Lua, (name, data): (String, Value)) -> mlua::Result<()> {
+    let data = lua_to_json(lua, &data, 0)?;
+    with_messages(lua, |ctx| ctx.outbox.push(Message { name, data }))
+}
+
+pub fn init_message_methods(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    globals.set("_messages", lua.create_userdata(MessageContext::default())?)?;
+    globals.set("on_message", lua.create_function(on_message)?)?;
+    globals.set("emit", lua.create_function(emit)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::scripting::init_json_methods;
+
+    #[test]
+    fn test_messages_in_both_directions() {
+        let lua = Lua::new();
+        init_json_methods(&lua).unwrap();
+        init_message_methods(&lua).unwrap();
+        lua.load(r#"
+            received = {}
+            on_message("sensor", function(name, data) table.insert(received, name .. "=" .. data.temp) end)
+            on_message(function(name, data)
+                table.insert(received, "any:" .. name)
+                emit("ack", { name = name })
+            end)
+        "#).exec().unwrap();
+
+        post_message(&lua, Message { name: "sensor".into(), data: json!({ "temp": 21 }) }).unwrap();
+        post_message(&lua, Message { name: "schedule".into(), data: JsonValue::Null }).unwrap();
+        assert!(drain_events(&lua).unwrap().is_empty());
+        deliver_messages(&lua).unwrap();
+
+        let received: String = lua.load("return table.concat(received, ',')").eval().unwrap();
+        assert_eq!(received, "sensor=21,any:sensor,any:schedule");
+        let events = drain_events(&lua).unwrap();
+        assert_eq!(events, vec![
+            Message { name: "ack".into(), data: json!({ "name": "sensor" }) },
+            Message { name: "ack".into(), data: json!({ "name": "schedule" }) },
+        ]);
+        assert!(drain_events(&lua).unwrap().is_empty());
+        assert!(lua.load("on_message(42)").exec().is_err());
+    }
+}
@@ -1,28 +1,54 @@
 mod timer;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+mod animation;
+mod state;
+mod instance;
+mod sandbox;
+mod error;
+mod fetch;
+mod http_cache;
+mod storage;
+mod messages;
+use std::{cell::RefCell, collections::{HashMap, HashSet}, rc::Rc};
 use log::warn;
-use mlua::{AnyUserData, Lua, Result, UserDataMethods, Value};
-use regex::Regex;
-use reqwest::blocking::get;
+use mlua::{AnyUserData, Lua, LuaSerdeExt, MetaMethod, Result, UserDataMethods, Value, Variadic};
 use serde_json::Value as JsonValue;
 use timer::init_timer_methods;
+use animation::init_animation_methods;
+use state::init_state_methods;
+use instance::init_instance_methods;
+use fetch::init_fetch_methods;
+pub use sandbox::EngineOptions;
+pub use storage::{default_storage_dir, FileStorage, MemoryStorage, Storage};
+pub use messages::Message;
+pub use error::ScriptError;
+use error::{chunk_name, ScriptPosition};
 use ulid::Ulid;
 
-use crate::{document::{self, FindByIdMut}, vdom::{self, ElementNode, TextNode, VNode}, render};
+use crate::{clock::{Clock, FrameTime}, parser::ScriptSource, document::{self, FindByIdMut, ListItem, StateFlag}, styles::cascade::custom_property_name, template::{TemplateValue, TemplateValues}, vdom::{self, render_texts_in_subtree, ElementNode, VNode}, render};
 
 #[derive(Clone)]
 pub struct ElementContext {
     pub internal_id: Ulid,
     pub temp_node: Rc<RefCell<Option<VNode>>>,
-    pub values: Rc<RefCell<HashMap<String, String>>>,
+    pub values: Rc<RefCell<TemplateValues>>,
 }
 
 impl mlua::UserData for ElementContext {
     fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("set_text", |lua, this, (key, value): (String, String)| {
+        methods.add_method("set_text", |lua, this, (key, value): (String, Value)| {
 
             let globals = lua.globals();
-            this.values.borrow_mut().insert(key.clone(), value.clone());
+            {
+                let mut values = this.values.borrow_mut();
+                match &value {
+                    // Tabellen werden für `{{ key.feld }}` flach abgelegt
+                    Value::Table(table) => flatten_table(table, &key, &mut values, 0)?,
+                    value => {
+                        values.insert(key.clone(), lua_to_template_value(value).unwrap_or_else(|| "".into()));
+                    }
+                }
+            }
+            let eval = |expr: &str, values: &TemplateValues| eval_template_expr(lua, expr, values);
 
             let has_node = {
                 let temp_node = this.temp_node.borrow();
@@ -33,7 +59,7 @@ impl mlua::UserData for ElementContext {
                 warn!("set_text: temp_node found");
                 let mut temp_node = this.temp_node.borrow_mut();
                 let mut taked_node = temp_node.take().unwrap();
-                render_texts_in_subtree(&mut taked_node, &this.values.borrow());
+                render_texts_in_subtree(&mut taked_node, &this.values.borrow(), Some(&eval));
                 *temp_node = Some(taked_node);
             } else {
                 warn!("set_text: no temp_node found, using vdom");
@@ -42,51 +68,277 @@ impl mlua::UserData for ElementContext {
                 let vdom = vdom_context.0.clone();
                 let mut borrowed_vdom = vdom.borrow_mut();
 
-                if let Some(node) = borrowed_vdom.root.find_by_internal_id_mut(&this.internal_id) {
-                    warn!("set_text: found node in vdom");
-                    render_texts_in_subtree(node, &this.values.borrow());
-                } else {
-                    warn!("set_text: node not found in vdom");
-                    return Err(mlua::Error::external("node not found in vdom"));
-                }
+                borrowed_vdom
+                    .render_subtree(&this.internal_id, &this.values.borrow(), Some(&eval))
+                    .map_err(mlua::Error::external)?;
             }
 
             Ok(())
         });
 
+        methods.add_method("set_css_var", |lua, this, (name, value): (String, Option<String>)| {
+            let name = custom_property_name(&name);
+            this.with_node_mut(lua, |node| {
+                if let VNode::Element(el) = node {
+                    match value {
+                        Some(value) => el.inline_style.insert(name, value),
+                        None => el.inline_style.remove(&name),
+                    };
+                }
+            })?;
+            get_vdom(lua)?.borrow_mut().styles_dirty = true;
+            Ok(())
+        });
+
+        methods.add_method("set_state", |lua, this, (name, value): (String, bool)| {
+            let flag = StateFlag::from_str(&name)
+                .ok_or_else(|| mlua::Error::external(format!("unknown state: {}", name)))?;
+            let vdom = get_vdom(lua)?;
+            let changed = vdom.borrow_mut().set_element_state(&this.internal_id, flag, value);
+            Ok(changed)
+        });
+
+        methods.add_method("get_state", |lua, this, name: String| {
+            let flag = StateFlag::from_str(&name)
+                .ok_or_else(|| mlua::Error::external(format!("unknown state: {}", name)))?;
+            let vdom = get_vdom(lua)?;
+            let state = vdom.borrow().element_state(&this.internal_id);
+            Ok(state.get(flag))
+        });
+
+        methods.add_method("get_css_var", |lua, this, name: String| {
+            let name = custom_property_name(&name);
+            if let Some(VNode::Element(el)) = this.temp_node.borrow().as_ref() {
+                // noch nicht eingefügt, es gibt nur die eigenen Deklarationen
+                return Ok(el.inline_style.get(&name).cloned());
+            }
+
+            let vdom = get_vdom(lua)?;
+            let mut vdom = vdom.borrow_mut();
+            vdom.restyle_if_needed();
+            let node = vdom.find_node(&this.internal_id)
+                .ok_or_else(|| mlua::Error::external("node not found in vdom"))?;
+            Ok(node.get_style().custom_properties.get(&name).cloned())
+        });
+
+        methods.add_method("tag", |lua, this, ()| {
+            let tag = this.with_node(lua, |node| match node {
+                VNode::Element(el) => Some(el.tag.clone()),
+                VNode::Text(_) => None,
+            })?;
+            Ok(tag.flatten())
+        });
+
+        methods.add_method("id", |lua, this, ()| {
+            let id = this.with_node(lua, |node| match node {
+                VNode::Element(el) => el.id.clone(),
+                VNode::Text(_) => None,
+            })?;
+            Ok(id.flatten())
+        });
+
+        methods.add_method("get_attr", |lua, this, name: String| {
+            let value = this.with_node(lua, |node| match node {
+                VNode::Element(el) => el.get_attr(&name),
+                VNode::Text(_) => None,
+            })?;
+            Ok(value.flatten())
+        });
+
+        methods.add_method("set_attr", |lua, this, (name, value): (String, Value)| {
+            let value = attr_value(&name, value)?;
+            this.set_attr(lua, &name, value)
+        });
+
+        methods.add_method("remove_attr", |lua, this, name: String| {
+            this.set_attr(lua, &name, None)
+        });
+
+        // `data()` liefert alle `data-*` Attribute als Tabelle, `data(name)` eines davon,
+        // `data(name, value)` setzt es und `data(name, nil)` entfernt es.
+        methods.add_method("data", |lua, this, (name, value): (Option<String>, Variadic<Value>)| {
+            let Some(name) = name else {
+                let attrs: Vec<(String, String)> = this.with_node(lua, |node| match node {
+                    VNode::Element(el) => el.attrs
+                        .iter()
+                        .filter_map(|(key, value)| Some((key.strip_prefix(DATA_ATTR_PREFIX)?.to_string(), value.clone())))
+                        .collect(),
+                    VNode::Text(_) => Vec::new(),
+                })?.unwrap_or_default();
+                return Ok(Value::Table(lua.create_table_from(attrs)?));
+            };
+
+            let name = format!("{}{}", DATA_ATTR_PREFIX, name);
+            match value.into_iter().next() {
+                Some(value) => {
+                    let value = attr_value(&name, value)?;
+                    this.set_attr(lua, &name, value)?;
+                    Ok(Value::Nil)
+                }
+                None => {
+                    let value = this.with_node(lua, |node| match node {
+                        VNode::Element(el) => el.attrs.get(&name).cloned(),
+                        VNode::Text(_) => None,
+                    })?;
+                    match value.flatten() {
+                        Some(value) => Ok(Value::String(lua.create_string(&value)?)),
+                        None => Ok(Value::Nil),
+                    }
+                }
+            }
+        });
+
+        // Die Suche und Navigation gehen über den VDOM, ein noch nicht eingefügtes
+        // Element hat dort weder Eltern noch Kinder.
+        methods.add_method("query_selector", |lua, this, selectors: String| {
+            let found = query(lua, &selectors, Some(&this.internal_id))?;
+            found.first().map(|id| element_handle(lua, *id)).transpose()
+        });
+
+        methods.add_method("query_selector_all", |lua, this, selectors: String| {
+            let found = query(lua, &selectors, Some(&this.internal_id))?;
+            element_handles(lua, found)
+        });
+
+        methods.add_method("parent", |lua, this, ()| {
+            let parent = get_vdom(lua)?.borrow().parent_of(&this.internal_id);
+            parent.map(|id| element_handle(lua, id)).transpose()
+        });
+
+        methods.add_method("children", |lua, this, ()| {
+            let children = get_vdom(lua)?.borrow().element_children(&this.internal_id);
+            element_handles(lua, children)
+        });
+
+        methods.add_method("next_sibling", |lua, this, ()| {
+            let sibling = get_vdom(lua)?.borrow().sibling_of(&this.internal_id, 1);
+            sibling.map(|id| element_handle(lua, id)).transpose()
+        });
+
+        methods.add_method("prev_sibling", |lua, this, ()| {
+            let sibling = get_vdom(lua)?.borrow().sibling_of(&this.internal_id, -1);
+            sibling.map(|id| element_handle(lua, id)).transpose()
+        });
+
+        // Funktionen aus dem Skript des Templates, siehe `instance`
+        methods.add_meta_method(MetaMethod::Index, |lua, this, name: String| {
+            instance::instance_value(lua, &this.internal_id, &name)
+        });
     }
 }
 
-fn render_texts_in_subtree(
-    node: &mut vdom::VNode,
-    ctx: &HashMap<String, String>,
-) {
-    let re = Regex::new(r"\{\{\s*(\w+)\s*\}\}").unwrap();
+impl ElementContext {
+    /// Führt `f` auf dem Knoten dieses Handles aus – entweder auf dem noch nicht
+    /// eingefügten Template-Klon oder auf dem Knoten im VDOM, auch in einem ausgeblendeten Zweig.
+    fn with_node_mut<R>(&self, lua: &Lua, f: impl FnOnce(&mut VNode) -> R) -> Result<R> {
+        if let Some(node) = self.temp_node.borrow_mut().as_mut() {
+            return Ok(f(node));
+        }
+
+        let vdom = get_vdom(lua)?;
+        let mut vdom = vdom.borrow_mut();
+        let node = vdom
+            .find_node_mut(&self.internal_id)
+            .ok_or_else(|| mlua::Error::external("node not found in vdom"))?;
+        Ok(f(node))
+    }
+
+    /// Setzt ein Attribut auf dem noch nicht eingefügten Template-Klon oder im VDOM.
+    fn set_attr(&self, lua: &Lua, name: &str, value: Option<String>) -> Result<()> {
+        if let Some(VNode::Element(el)) = self.temp_node.borrow_mut().as_mut() {
+            el.set_attr(name, value);
+            return Ok(());
+        }
+
+        let vdom = get_vdom(lua)?;
+        let result = vdom.borrow_mut().set_attribute(&self.internal_id, name, value);
+        result.map_err(mlua::Error::external)
+    }
 
-    match node {
-        VNode::Text(TextNode { template, rendered, .. }) => {
-            let replaced = re.replace_all(template, |caps: &regex::Captures| {
-                let key = &caps[1];
-                ctx.get(key).cloned().unwrap_or_default()
-            });
-            *rendered = replaced.into_owned();
+    /// Wie `with_node_mut`, nur lesend. `None`, wenn der Knoten nicht mehr im VDOM steht.
+    fn with_node<R>(&self, lua: &Lua, f: impl FnOnce(&VNode) -> R) -> Result<Option<R>> {
+        if let Some(node) = self.temp_node.borrow().as_ref() {
+            return Ok(Some(f(node)));
         }
-        VNode::Element(ElementNode { children, .. }) => {
-            for child in children.iter_mut() {
-                render_texts_in_subtree(child, ctx);
+
+        let vdom = get_vdom(lua)?;
+        let vdom = vdom.borrow();
+        Ok(vdom.find_node(&self.internal_id).map(f))
+    }
+}
+
+/// Präfix der Attribute, die `data` liest und schreibt.
+const DATA_ATTR_PREFIX: &str = "data-";
+
+/// Wert für `set_attr` und `data`, `nil` entfernt das Attribut.
+fn attr_value(name: &str, value: Value) -> Result<Option<String>> {
+    if value.is_nil() {
+        return Ok(None);
+    }
+    lua_value_to_string(&value)
+        .map(Some)
+        .ok_or_else(|| mlua::Error::external(format!("attribute {} expects a string, number or boolean", name)))
+}
+
+/// Handle für ein Element, das bereits im VDOM steht.
+fn element_handle(lua: &Lua, internal_id: Ulid) -> Result<AnyUserData> {
+    lua.create_userdata(ElementContext {
+        internal_id,
+        temp_node: Rc::new(RefCell::new(None)),
+        values: Rc::new(RefCell::new(HashMap::new())),
+    })
+}
+
+fn element_handles(lua: &Lua, ids: Vec<Ulid>) -> Result<Vec<AnyUserData>> {
+    ids.into_iter().map(|id| element_handle(lua, id)).collect()
+}
+
+/// Elemente zu einer Selektorliste wie `".card > h2, #title"`, in Dokumentreihenfolge.
+/// Ein ungültiger Selektor ist ein Fehler im Skript.
+fn query(lua: &Lua, selectors: &str, scope: Option<&Ulid>) -> Result<Vec<Ulid>> {
+    let vdom = get_vdom(lua)?;
+    let found = vdom.borrow().query_selector_all(selectors, scope);
+    found.map_err(mlua::Error::external)
+}
+
+/// Holt den VDOM des aktuellen Frames aus den Lua-Globals.
+fn get_vdom(lua: &Lua) -> Result<Rc<RefCell<document::VDom>>> {
+    let vdom_ud: mlua::AnyUserData = lua.globals().get("_vdom")?;
+    let vdom_context = vdom_ud.borrow::<DynamiteContext>()?;
+    Ok(vdom_context.0.clone())
+}
+
+/// `set_css_var(name, value)` oder `set_css_var({ ["--accent"] = "#ff0000ff", ... })`
+///
+/// Setzt globale Custom Properties, `nil` als Wert entfernt sie. Die Styles werden
+/// einmalig vor dem nächsten Layout neu berechnet, egal wie viele Werte gesetzt wurden.
+fn set_css_var(lua: &Lua, (name, value): (Value, Option<String>)) -> Result<()> {
+    let vdom = get_vdom(lua)?;
+    let mut vdom = vdom.borrow_mut();
+    match name {
+        Value::Table(vars) => {
+            for pair in vars.pairs::<String, Option<String>>() {
+                let (name, value) = pair?;
+                vdom.set_css_var(&name, value);
             }
         }
+        Value::String(name) => vdom.set_css_var(&name.to_str()?, value),
+        _ => return Err(mlua::Error::external("set_css_var expects a name or a table")),
     }
+    Ok(())
 }
 
-fn get_webdata(_lua: &Lua, url: String) -> Result<String> {
-    let response = get(&url).and_then(|resp| resp.text()).unwrap_or_else(|_| "{}".to_string());
-    Ok(response)
+fn get_css_var(lua: &Lua, name: String) -> Result<Option<String>> {
+    let vdom = get_vdom(lua)?;
+    let mut vdom = vdom.borrow_mut();
+    Ok(vdom.get_css_var(&name))
 }
 
 /// Erzeugt ein Element aus einer Vorlage und gibt ein leichtes Handle (ElementContext) zurück.
 /// Dabei wird eine ULID generiert, die als ID im VDOM verwendet wird.
-fn create_element(lua: &Lua, vdom: Rc<RefCell<document::VDom>>, template_id: String) -> Result<mlua::Value> {
+///
+/// `props` werden wie mit `set_text` gerendert und stehen dem Skript des Templates zur Verfügung.
+fn create_element(lua: &Lua, vdom: Rc<RefCell<document::VDom>>, template_id: String, props: Option<mlua::Table>) -> Result<mlua::Value> {
     log::info!("create_element: {}", template_id);
     let e = {
         let vdom_ref = vdom.borrow();
@@ -94,13 +346,37 @@ fn create_element(lua: &Lua, vdom: Rc<RefCell<document::VDom>>, template_id: Str
         drop(vdom_ref);
         a
     };
-    if let Some(element) = e {
+    if let Some(mut element) = e {
+        let props = match props {
+            Some(props) => props,
+            None => lua.create_table()?,
+        };
+        let mut values = HashMap::new();
+        for pair in props.clone().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let Some(key) = lua_value_to_string(&key) else {
+                continue;
+            };
+            match &value {
+                Value::Table(table) => flatten_table(table, &key, &mut values, 0)?,
+                value => {
+                    values.insert(key, lua_to_template_value(value).unwrap_or_else(|| "".into()));
+                }
+            }
+        }
+        if !values.is_empty() {
+            let eval = |expr: &str, values: &TemplateValues| eval_template_expr(lua, expr, values);
+            render_texts_in_subtree(&mut element, &values, Some(&eval));
+        }
+
         // Gib ein leichtes Handle (ElementContext) an Lua zurück.
+        let internal_id = *element.get_internal_id();
         let handle = lua.create_userdata(ElementContext{
-            internal_id: Ulid::new(),
+            internal_id,
             temp_node: Rc::new(RefCell::new(Some(element))),
-            values: Rc::new(RefCell::new(HashMap::new())),
+            values: Rc::new(RefCell::new(values)),
         })?;
+        instance::instantiate(lua, &template_id, handle.clone(), internal_id, props)?;
 
         Ok(mlua::Value::UserData(handle))
     } else {
@@ -108,32 +384,633 @@ fn create_element(lua: &Lua, vdom: Rc<RefCell<document::VDom>>, template_id: Str
     }
 }
 
+/// Wandelt einfache Lua-Werte in Template-Werte um, Tabellen und Funktionen nicht.
+fn lua_to_template_value(value: &Value) -> Option<TemplateValue> {
+    match value {
+        Value::String(s) => s.to_str().ok().map(|s| s.to_string().into()),
+        Value::Integer(i) => Some(TemplateValue::Integer(*i)),
+        Value::Number(n) => Some(TemplateValue::Number(*n)),
+        Value::Boolean(b) => Some(TemplateValue::Bool(*b)),
+        _ => None,
+    }
+}
+
+impl mlua::IntoLua for TemplateValue {
+    fn into_lua(self, lua: &Lua) -> Result<Value> {
+        match self {
+            TemplateValue::Text(text) => lua.create_string(&text).map(Value::String),
+            TemplateValue::Integer(i) => Ok(Value::Integer(i)),
+            TemplateValue::Number(n) => Ok(Value::Number(n)),
+            TemplateValue::Bool(b) => Ok(Value::Boolean(b)),
+        }
+    }
+}
+
+/// Wahrheitswert für `if`, wie in Lua, nur `json.null` gilt wie `nil` als falsch.
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Nil | Value::Boolean(false) => false,
+        Value::LightUserData(ud) => !ud.0.is_null(),
+        _ => true,
+    }
+}
+
+/// Wandelt einfache Lua-Werte in Text für die Platzhalter um, Tabellen und Funktionen nicht.
+/// `json.null` bleibt wie `nil` leer.
+fn lua_value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => s.to_str().ok().map(|s| s.to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => Some(format!("{}", *n as i64)),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Maximale Tiefe beim Abflachen verschachtelter Tabellen, schützt vor Zyklen.
+const MAX_FLATTEN_DEPTH: usize = 8;
+
+/// Legt die Felder einer Tabelle als `prefix.feld` in `values` ab, verschachtelt rekursiv.
+fn flatten_table(table: &mlua::Table, prefix: &str, values: &mut TemplateValues, depth: usize) -> Result<()> {
+    if depth > MAX_FLATTEN_DEPTH {
+        return Ok(());
+    }
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (field, value) = pair?;
+        let Some(field) = lua_value_to_string(&field) else {
+            continue;
+        };
+        let key = format!("{}.{}", prefix, field);
+        match &value {
+            Value::Table(inner) => flatten_table(inner, &key, values, depth + 1)?,
+            value => {
+                if let Some(value) = lua_to_template_value(value) {
+                    values.insert(key, value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ein kompilierter `{{= }}` Ausdruck.
+struct CachedExpr {
+    function: mlua::Function,
+    /// Alle bisher gelesenen Schlüssel aus `state`.
+    deps: HashSet<String>,
+    /// Der Ausdruck hat andere Globals gelesen, `deps` ist also nicht vollständig.
+    untracked: bool,
+}
+
+/// Kompilierte Ausdrücke aus `{{= }}`, `:name`, `for-each`, `if` und `else-if`, nach
+/// Quelltext, als App-Data am Lua-State.
+#[derive(Default)]
+struct TemplateExprCache(HashMap<String, CachedExpr>);
+
+/// Kompiliert einen Ausdruck, der mit den Template-Werten als `_ENV` aufgerufen wird,
+/// jeden Quelltext nur einmal. `name` erscheint in Fehlermeldungen.
+fn compile_expr(lua: &Lua, name: impl FnOnce() -> String, expr: &str) -> Result<mlua::Function> {
+    let cached = lua
+        .app_data_ref::<TemplateExprCache>()
+        .and_then(|cache| cache.0.get(expr).map(|entry| entry.function.clone()));
+    if let Some(function) = cached {
+        return Ok(function);
+    }
+
+    let function = lua
+        .load(format!("local _ENV = ...\nreturn {}", expr))
+        .set_name(name())
+        .into_function()?;
+    if lua.app_data_ref::<TemplateExprCache>().is_none() {
+        lua.set_app_data(TemplateExprCache::default());
+    }
+    if let Some(mut cache) = lua.app_data_mut::<TemplateExprCache>() {
+        cache.0.insert(expr.to_string(), CachedExpr { function: function.clone(), deps: HashSet::new(), untracked: false });
+    }
+    Ok(function)
+}
+
+/// Baut aus den flachen Template-Werten eine Umgebung mit verschachtelten Tabellen
+/// (`entry.city` wird zu `entry = { city = ... }`, `w.days.1` zu `w = { days = { ... } }`
+/// mit Index 1), Globals bleiben über `__index` sichtbar.
+fn template_env(lua: &Lua, values: &TemplateValues) -> Result<mlua::Table> {
+    let env = lua.create_table()?;
+    for (key, value) in values {
+        let mut table = env.clone();
+        let mut parts = key.split('.').peekable();
+        while let Some(part) = parts.next() {
+            let part = match part.parse::<i64>() {
+                Ok(index) => Value::Integer(index),
+                Err(_) => Value::String(lua.create_string(part)?),
+            };
+            if parts.peek().is_none() {
+                if table.raw_get::<Value>(part.clone())?.is_nil() {
+                    table.raw_set(part, value.clone())?;
+                }
+                break;
+            }
+            table = match table.raw_get::<Value>(part.clone())? {
+                Value::Table(inner) => inner,
+                Value::Nil => {
+                    let inner = lua.create_table()?;
+                    table.raw_set(part, inner.clone())?;
+                    inner
+                }
+                // ein einfacher Wert verdeckt den Pfad
+                _ => break,
+            };
+        }
+    }
+    env.set_metatable(Some(template_env_metatable(lua)?));
+    Ok(env)
+}
+
+/// Registry-Name der Metatable, über die `template_env` die Globals sichtbar macht.
+const TEMPLATE_ENV_METATABLE: &str = "template_env_metatable";
+
+/// Metatable für `template_env`, jeder Zugriff auf ein Global wird für `state` vermerkt.
+fn template_env_metatable(lua: &Lua) -> Result<mlua::Table> {
+    if let Some(meta) = lua.named_registry_value::<Option<mlua::Table>>(TEMPLATE_ENV_METATABLE)? {
+        return Ok(meta);
+    }
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.create_function(|lua, (_, key): (mlua::Table, Value)| {
+        if let Value::String(name) = &key {
+            state::note_global_read(lua, &name.to_str()?)?;
+        }
+        lua.globals().raw_get::<Value>(key)
+    })?)?;
+    lua.set_named_registry_value(TEMPLATE_ENV_METATABLE, meta.clone())?;
+    Ok(meta)
+}
+
+/// Wandelt eine Tabelle aus einer Bindung in einen Attributwert um:
+/// `{ "card", "hot" }` und `{ card = true, hot = false }` ergeben Klassen (`card hot` bzw. `card`),
+/// `{ width = "10px" }` ergibt Deklarationen (`width: 10px`), z. B. für `:style`.
+fn table_to_attr_value(table: &mlua::Table) -> Result<String> {
+    let mut parts = Vec::new();
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        match (key, value) {
+            (Value::Integer(_), value) => parts.extend(lua_value_to_string(&value)),
+            (key, Value::Boolean(enabled)) => {
+                if enabled {
+                    parts.extend(lua_value_to_string(&key));
+                }
+            }
+            (key, value) => {
+                if let (Some(key), Some(value)) = (lua_value_to_string(&key), lua_value_to_string(&value)) {
+                    parts.push(format!("{}: {};", key, value));
+                }
+            }
+        }
+    }
+    // pairs liefert keine feste Reihenfolge
+    parts.sort();
+    Ok(parts.join(" "))
+}
+
+/// Wertet einen `{{= }}` Ausdruck mit den Template-Werten als Umgebung aus.
+/// Fehler werden geloggt, der Platzhalter bleibt dann leer.
+fn eval_template_expr(lua: &Lua, expr: &str, values: &TemplateValues) -> Option<TemplateValue> {
+    let result = (|| -> Result<Option<TemplateValue>> {
+        let function = compile_expr(lua, || format!("{{{{= {} }}}}", expr), expr)?;
+
+        state::begin_tracking(lua)?;
+        let value = sandbox::limited(lua, || template_env(lua, values).and_then(|env| function.call::<Value>(env)));
+        let reads = state::end_tracking(lua)?;
+        if let Some(entry) = lua.app_data_mut::<TemplateExprCache>().as_mut().and_then(|cache| cache.0.get_mut(expr)) {
+            entry.deps.extend(reads.keys);
+            // nach einem Fehler fehlen womöglich Zugriffe
+            entry.untracked |= reads.untracked || value.is_err();
+        }
+
+        match value? {
+            Value::Table(table) => table_to_attr_value(&table).map(|v| Some(v.into())),
+            value => Ok(lua_to_template_value(&value)),
+        }
+    })();
+
+    result.unwrap_or_else(|e| {
+        warn!("template expression failed: {}", e);
+        None
+    })
+}
+
+/// Liest die Einträge einer `for-each` Tabelle.
+///
+/// Tabellen-Einträge stellen ihre Felder als `{{ feld }}` und `{{ alias.feld }}` bereit,
+/// einfache Werte als `{{ alias }}`. Der Schlüssel ist das Feld `key_field`, sonst `id`,
+/// sonst die Position; bei einfachen Werten der Wert selbst.
+fn list_items(table: &mlua::Table, alias: &str, key_field: Option<&str>) -> Result<Vec<ListItem>> {
+    let mut items = Vec::new();
+    for (index, entry) in table.clone().sequence_values::<Value>().enumerate() {
+        let entry = entry?;
+        let mut values = HashMap::new();
+
+        let key = match &entry {
+            Value::Table(fields) => {
+                for pair in fields.clone().pairs::<Value, Value>() {
+                    let (field, value) = pair?;
+                    let Some(field) = lua_value_to_string(&field) else {
+                        continue;
+                    };
+                    // verschachtelte Tabellen, z. B. für ein inneres `for-each="w.days"`
+                    if let Value::Table(inner) = &value {
+                        flatten_table(inner, &format!("{}.{}", alias, field), &mut values, 1)?;
+                        flatten_table(inner, &field, &mut values, 1)?;
+                        continue;
+                    }
+                    let Some(value) = lua_to_template_value(&value) else {
+                        continue;
+                    };
+                    values.insert(format!("{}.{}", alias, field), value.clone());
+                    values.insert(field, value);
+                }
+                key_field
+                    .or(Some("id"))
+                    .and_then(|field| values.get(field).map(|v| v.to_string()))
+                    .unwrap_or_else(|| (index + 1).to_string())
+            }
+            value => {
+                let value = lua_to_template_value(value).unwrap_or_else(|| "".into());
+                let key = value.to_string();
+                values.insert(alias.to_string(), value);
+                key
+            }
+        };
+
+        items.push(ListItem { key, values });
+    }
+    Ok(items)
+}
+
+/// Registry-Name der Metatable, mit der `json.object` Tabellen markiert.
+const JSON_OBJECT_METATABLE: &str = "json_object_metatable";
+
+/// Maximale Verschachtelung für `to_json`, schützt vor Zyklen.
+const MAX_JSON_DEPTH: usize = 128;
+
+/// Wandelt JSON über das `serialize` Feature von mlua: Ganzzahlen bleiben Integer, `null`
+/// wird zu `json.null` und Arrays bekommen die Array-Metatable, damit sie als Array zurückgehen.
+/// Platzhalter und `if` behandeln `json.null` wie `nil`.
 fn json_to_lua(lua: &Lua, json: &JsonValue) -> Result<Value> {
-    match json {
-        JsonValue::Null => Ok(Value::Nil),
-        JsonValue::Bool(b) => Ok(Value::Boolean(*b)),
-        JsonValue::Number(n) => Ok(Value::Number(n.as_f64().unwrap_or(0.0))),
-        JsonValue::String(s) => Ok(Value::String(lua.create_string(s)?)),
-        JsonValue::Array(arr) => {
-            let table = lua.create_table()?;
-            for (i, v) in arr.iter().enumerate() {
-                table.set(i + 1, json_to_lua(lua, v)?)?;
+    lua.to_value(json)
+}
+
+/// Gegenstück zu `json_to_lua`. Tabellen mit der Array-Metatable (`json.array`) werden Arrays,
+/// mit `json.object` markierte Objekte. Sonst entscheidet der Inhalt: nur die Schlüssel `1..n`
+/// ergeben ein Array, eine leere Tabelle ein Objekt.
+fn lua_to_json(lua: &Lua, value: &Value, depth: usize) -> Result<JsonValue> {
+    if depth > MAX_JSON_DEPTH {
+        return Err(mlua::Error::runtime("to_json: table nested too deeply, is it cyclic?"));
+    }
+    match value {
+        Value::Nil => Ok(JsonValue::Null),
+        Value::LightUserData(ud) if ud.0.is_null() => Ok(JsonValue::Null),
+        Value::Boolean(b) => Ok(JsonValue::Bool(*b)),
+        Value::Integer(i) => Ok(JsonValue::from(*i)),
+        Value::Number(n) => serde_json::Number::from_f64(*n)
+            .map(JsonValue::Number)
+            .ok_or_else(|| mlua::Error::runtime(format!("to_json: cannot encode {}", n))),
+        Value::String(s) => Ok(JsonValue::String(s.to_str()?.to_string())),
+        Value::Table(table) => {
+            let metatable = table.metatable().map(|mt| mt.to_pointer());
+            let object_metatable: mlua::Table = lua.named_registry_value(JSON_OBJECT_METATABLE)?;
+            let is_array = if metatable == Some(lua.array_metatable().to_pointer()) {
+                true
+            } else if metatable == Some(object_metatable.to_pointer()) {
+                false
+            } else {
+                let len = table.raw_len();
+                len > 0 && table.clone().pairs::<Value, Value>().count() == len
+            };
+
+            if is_array {
+                let mut items = Vec::new();
+                for item in table.clone().sequence_values::<Value>() {
+                    items.push(lua_to_json(lua, &item?, depth + 1)?);
+                }
+                return Ok(JsonValue::Array(items));
+            }
+            let mut object = serde_json::Map::new();
+            for pair in table.clone().pairs::<Value, Value>() {
+                let (key, value) = pair?;
+                let key = match &key {
+                    Value::String(s) => s.to_str()?.to_string(),
+                    Value::Integer(i) => i.to_string(),
+                    Value::Number(n) => n.to_string(),
+                    other => return Err(mlua::Error::runtime(format!("to_json: unsupported key type {}", other.type_name()))),
+                };
+                object.insert(key, lua_to_json(lua, &value, depth + 1)?);
+            }
+            Ok(JsonValue::Object(object))
+        }
+        other => Err(mlua::Error::runtime(format!("to_json: cannot encode a {}", other.type_name()))),
+    }
+}
+
+/// `parse_json(text)` liefert den Wert bzw. `nil, Fehler`.
+fn parse_json(lua: &Lua, json_str: String) -> Result<(Value, Option<String>)> {
+    match serde_json::from_str::<JsonValue>(&json_str) {
+        Ok(parsed) => Ok((json_to_lua(lua, &parsed)?, None)),
+        Err(e) => Ok((Value::Nil, Some(e.to_string()))),
+    }
+}
+
+/// `to_json(value, { pretty = true })`, Objekt-Schlüssel sind sortiert.
+fn to_json(lua: &Lua, (value, options): (Value, Option<mlua::Table>)) -> Result<String> {
+    let pretty = match options {
+        Some(options) => options.get::<Option<bool>>("pretty")?.unwrap_or(false),
+        None => false,
+    };
+    let json = lua_to_json(lua, &value, 0)?;
+    let result = if pretty { serde_json::to_string_pretty(&json) } else { serde_json::to_string(&json) };
+    result.map_err(mlua::Error::external)
+}
+
+/// Die Tabelle `json` mit `null`, `array(t)`, `object(t)`, `encode` und `decode`.
+fn init_json_methods(lua: &Lua) -> Result<()> {
+    let object_metatable = lua.create_table()?;
+    lua.set_named_registry_value(JSON_OBJECT_METATABLE, object_metatable)?;
+
+    let json = lua.create_table()?;
+    json.set("null", lua.null())?;
+    json.set("array", lua.create_function(|lua, table: Option<mlua::Table>| {
+        let table = match table {
+            Some(table) => table,
+            None => lua.create_table()?,
+        };
+        table.set_metatable(Some(lua.array_metatable()));
+        Ok(table)
+    })?)?;
+    json.set("object", lua.create_function(|lua, table: Option<mlua::Table>| {
+        let table = match table {
+            Some(table) => table,
+            None => lua.create_table()?,
+        };
+        table.set_metatable(Some(lua.named_registry_value::<mlua::Table>(JSON_OBJECT_METATABLE)?));
+        Ok(table)
+    })?)?;
+    json.set("encode", lua.create_function(to_json)?)?;
+    json.set("decode", lua.create_function(parse_json)?)?;
+
+    let globals = lua.globals();
+    globals.set("json", json)?;
+    globals.set("to_json", lua.create_function(to_json)?)?;
+    Ok(())
+}
+
+/// Ein Element aus `parse_xml`, Texte und CDATA der direkten Kinder sind in `text` zusammengefasst.
+#[derive(Debug, Default, PartialEq)]
+struct XmlNode {
+    tag: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<XmlNode>,
+    text: String,
+}
+
+impl XmlNode {
+    /// Name ohne Namespace-Präfix, `dc:creator` wird zu `creator`.
+    fn local_name(&self) -> &str {
+        self.tag.rsplit(':').next().unwrap_or(&self.tag)
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn children_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlNode> {
+        self.children.iter().filter(move |child| child.local_name() == name)
+    }
+
+    /// Text des ersten vorhandenen, nicht leeren Kinds in der Reihenfolge von `names`.
+    fn child_text(&self, names: &[&str]) -> Option<String> {
+        names.iter().find_map(|name| {
+            let child = self.children_named(name).next()?;
+            // Atom: <author><name>…</name></author>
+            let text = match child.children_named("name").next() {
+                Some(inner) => inner.text.trim(),
+                None => child.text.trim(),
+            };
+            (!text.is_empty()).then(|| text.to_string())
+        })
+    }
+
+    fn descendants<'a>(&'a self, name: &str, out: &mut Vec<&'a XmlNode>) {
+        for child in &self.children {
+            if child.local_name() == name {
+                out.push(child);
+            } else {
+                child.descendants(name, out);
+            }
+        }
+    }
+
+    fn to_lua(&self, lua: &Lua) -> Result<mlua::Table> {
+        let table = lua.create_table()?;
+        table.set("tag", self.tag.as_str())?;
+        let attrs = lua.create_table()?;
+        for (key, value) in &self.attrs {
+            attrs.set(key.as_str(), value.as_str())?;
+        }
+        table.set("attrs", attrs)?;
+        let children = lua.create_table()?;
+        for (i, child) in self.children.iter().enumerate() {
+            children.set(i + 1, child.to_lua(lua)?)?;
+        }
+        table.set("children", children)?;
+        table.set("text", self.text.as_str())?;
+        Ok(table)
+    }
+}
+
+fn xml_element(start: &quick_xml::events::BytesStart) -> std::result::Result<XmlNode, String> {
+    let mut node = XmlNode {
+        tag: String::from_utf8_lossy(start.name().as_ref()).into_owned(),
+        ..Default::default()
+    };
+    for attr in start.attributes() {
+        let attr = attr.map_err(|e| e.to_string())?;
+        let value = attr.unescape_value().map_err(|e| e.to_string())?;
+        node.attrs.push((String::from_utf8_lossy(attr.key.as_ref()).into_owned(), value.into_owned()));
+    }
+    Ok(node)
+}
+
+fn parse_xml_tree(xml: &str) -> std::result::Result<XmlNode, String> {
+    use quick_xml::events::Event;
+
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut stack: Vec<XmlNode> = Vec::new();
+    let mut root = None;
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| format!("invalid XML at byte {}: {}", reader.buffer_position(), e))?;
+        let closed = match event {
+            Event::Start(start) => {
+                stack.push(xml_element(&start)?);
+                None
+            }
+            Event::Empty(start) => Some(xml_element(&start)?),
+            Event::End(_) => Some(stack.pop().ok_or("unexpected end tag")?),
+            Event::Text(text) => {
+                if let Some(node) = stack.last_mut() {
+                    // unbekannte Entities wie &nbsp; bleiben stehen
+                    match text.unescape() {
+                        Ok(unescaped) => node.text.push_str(&unescaped),
+                        Err(_) => node.text.push_str(&String::from_utf8_lossy(&text)),
+                    }
+                }
+                None
+            }
+            Event::CData(data) => {
+                if let Some(node) = stack.last_mut() {
+                    node.text.push_str(&String::from_utf8_lossy(&data.into_inner()));
+                }
+                None
+            }
+            Event::Eof => break,
+            _ => None,
+        };
+        if let Some(mut node) = closed {
+            // erst hier kürzen, Leerzeichen zwischen Text und CDATA oder Kindern bleiben stehen
+            let trimmed = node.text.trim();
+            if trimmed.len() != node.text.len() {
+                node.text = trimmed.to_string();
             }
-            Ok(Value::Table(table))
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None if root.is_none() => root = Some(node),
+                None => return Err("XML with more than one root element".to_string()),
+            }
+        }
+    }
+    if let Some(open) = stack.last() {
+        return Err(format!("unclosed element <{}>", open.tag));
+    }
+    root.ok_or_else(|| "XML without root element".to_string())
+}
+
+/// `parse_xml(text)` liefert das Wurzelelement als `{ tag, attrs, children, text }`
+/// bzw. `nil, Fehler`.
+fn parse_xml(lua: &Lua, xml: String) -> Result<(Option<mlua::Table>, Option<String>)> {
+    match parse_xml_tree(&xml) {
+        Ok(root) => Ok((Some(root.to_lua(lua)?), None)),
+        Err(e) => Ok((None, Some(e))),
+    }
+}
+
+/// `parse_csv(text, { delimiter = ";", header = true })`
+///
+/// Mit Kopfzeile (Standard) ist jede Zeile eine Tabelle nach Spaltennamen,
+/// ohne eine Liste der Felder. Zeilen dürfen unterschiedlich viele Felder haben.
+fn parse_csv(lua: &Lua, (text, options): (String, Option<mlua::Table>)) -> Result<(Option<mlua::Table>, Option<String>)> {
+    let mut delimiter = b',';
+    let mut header = true;
+    if let Some(options) = options {
+        if let Some(value) = options.get::<Option<String>>("delimiter")? {
+            match value.as_bytes() {
+                [byte] => delimiter = *byte,
+                _ => return Err(mlua::Error::runtime(format!("delimiter must be a single byte: {:?}", value))),
+            }
+        }
+        header = options.get::<Option<bool>>("header")?.unwrap_or(true);
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(header)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut names = Vec::new();
+    if header {
+        match reader.headers() {
+            Ok(header) => names = header.iter().map(|name| name.trim().to_string()).collect(),
+            Err(e) => return Ok((None, Some(e.to_string()))),
         }
-        JsonValue::Object(obj) => {
-            let table = lua.create_table()?;
-            for (k, v) in obj.iter() {
-                table.set(k.as_str(), json_to_lua(lua, v)?)?;
+    }
+
+    let rows = lua.create_table()?;
+    for (i, record) in reader.records().enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => return Ok((None, Some(e.to_string()))),
+        };
+        let row = lua.create_table()?;
+        for (j, field) in record.iter().enumerate() {
+            match names.get(j) {
+                Some(name) => row.set(name.as_str(), field)?,
+                None => row.set(j + 1, field)?,
             }
-            Ok(Value::Table(table))
         }
+        rows.set(i + 1, row)?;
     }
+    Ok((Some(rows), None))
+}
+
+/// Link eines Atom-Eintrags (`<link rel="alternate" href>`) oder RSS-Items (`<link>text</link>`).
+fn feed_link(node: &XmlNode) -> Option<String> {
+    node.children_named("link").find_map(|link| match link.attr("href") {
+        Some(href) if matches!(link.attr("rel"), None | Some("alternate")) => Some(href.to_string()),
+        Some(_) => None,
+        None => Some(link.text.trim().to_string()).filter(|text| !text.is_empty()),
+    })
 }
 
-fn parse_json(lua: &Lua, json_str: String) -> Result<Value> {
-    let parsed: JsonValue = serde_json::from_str(&json_str).unwrap_or(JsonValue::Null);
-    json_to_lua(lua, &parsed)
+/// Bild aus `<enclosure type="image/…">`, `<media:thumbnail>` oder `<media:content>`.
+fn feed_image(node: &XmlNode) -> Option<String> {
+    node.children.iter().find_map(|child| {
+        let url = child.attr("url").or_else(|| child.attr("href"))?;
+        let is_image = match child.local_name() {
+            "thumbnail" => true,
+            "enclosure" | "content" | "link" => child.attr("type").is_some_and(|t| t.starts_with("image/"))
+                || (child.local_name() == "content" && child.attr("medium") == Some("image")),
+            _ => false,
+        };
+        is_image.then(|| url.to_string())
+    })
+}
+
+/// `parse_feed(text)` vereinheitlicht RSS 1.0/2.0 und Atom zu
+/// `{ title, link, description, items = { { id, title, link, summary, published, author, image } } }`,
+/// fehlende Felder sind `nil`. Das Datum bleibt im Format des Feeds.
+fn parse_feed(lua: &Lua, xml: String) -> Result<(Option<mlua::Table>, Option<String>)> {
+    let root = match parse_xml_tree(&xml) {
+        Ok(root) => root,
+        Err(e) => return Ok((None, Some(e))),
+    };
+    let (channel, entries) = match root.local_name() {
+        "feed" => (&root, root.children_named("entry").collect::<Vec<_>>()),
+        "rss" | "RDF" => {
+            let mut items = Vec::new();
+            root.descendants("item", &mut items);
+            (root.children_named("channel").next().unwrap_or(&root), items)
+        }
+        other => return Ok((None, Some(format!("not an RSS or Atom feed: <{}>", other)))),
+    };
+
+    let feed = lua.create_table()?;
+    feed.set("title", channel.child_text(&["title"]))?;
+    feed.set("link", feed_link(channel))?;
+    feed.set("description", channel.child_text(&["description", "subtitle"]))?;
+    let items = lua.create_table()?;
+    for (i, entry) in entries.into_iter().enumerate() {
+        let item = lua.create_table()?;
+        let link = feed_link(entry);
+        item.set("id", entry.child_text(&["guid", "id"]).or_else(|| link.clone()))?;
+        item.set("title", entry.child_text(&["title"]))?;
+        item.set("link", link)?;
+        item.set("summary", entry.child_text(&["description", "summary", "content", "encoded"]))?;
+        item.set("published", entry.child_text(&["pubDate", "published", "updated", "date"]))?;
+        item.set("author", entry.child_text(&["author", "creator"]))?;
+        item.set("image", feed_image(entry))?;
+        items.set(i + 1, item)?;
+    }
+    feed.set("items", items)?;
+    Ok((Some(feed), None))
 }
 
 
@@ -142,64 +1019,142 @@ fn parse_json(lua: &Lua, json_str: String) -> Result<Value> {
 struct DynamiteContext(Rc<RefCell<document::VDom>>);
 impl mlua::UserData for DynamiteContext {}
 
+/// Ein Handler aus einem `on:` Attribut.
+pub struct Handler {
+    pub function: mlua::Function,
+    /// Das Attribut, z. B. `on:update="update_data"`.
+    pub name: String,
+    /// HTML-ID des Elements mit dem Attribut.
+    pub element: Option<String>,
+}
+
+/// So viele Fehler hält die Engine bis `take_errors`, ältere werden verworfen.
+const MAX_ERRORS: usize = 100;
+
 pub struct Engine {
     pub lua: Lua,
-    pub onupdate_fns: Vec<mlua::Function>,
-    pub onload_fns: Vec<mlua::Function>,
+    pub onupdate_fns: Vec<Handler>,
+    pub onload_fns: Vec<Handler>,
+    pub onerror_fn: Option<Handler>,
+    /// Position der Skripte in der HTML-Datei, nach Index.
+    script_positions: HashMap<usize, ScriptPosition>,
+    /// Gemeldete Fehler seit dem letzten `take_errors`, höchstens `MAX_ERRORS`.
+    errors: Vec<ScriptError>,
+    /// Nach einem fehlgeschlagenen Handler weiterrendern statt abzubrechen.
+    continue_on_error: bool,
 }
 
 impl Engine {
 
-    pub fn new() -> Self {
-        let lua = Lua::new();
-        Self::load_lua_api(&lua).unwrap();
-        Self {
+    /// Erzeugt die Engine, der Lua-State wird nach `options` eingeschränkt, siehe `sandbox`.
+    /// Timer, Zeitpläne und Animationen laufen nach `clock`.
+    pub fn with_options(options: &EngineOptions, clock: Rc<dyn Clock>) -> std::result::Result<Self, String> {
+        let lua = sandbox::create_lua(options)?;
+        Self::load_lua_api(&lua, options, clock).map_err(|e| format!("loading Lua API failed: {}", e))?;
+        Ok(Self {
             lua,
             onupdate_fns: Vec::new(),
             onload_fns: Vec::new(),
-        }
+            onerror_fn: None,
+            script_positions: HashMap::new(),
+            errors: Vec::new(),
+            continue_on_error: options.continue_on_error,
+        })
     }
 
-    pub fn load_scripts(&mut self, scripts: Vec<String>) -> Result<()> {
+    pub fn load_scripts(&mut self, scripts: Vec<ScriptSource>) -> std::result::Result<(), String> {
+        for script in &scripts {
+            self.script_positions.insert(script.index, ScriptPosition { line: script.line, column: script.column });
+        }
+        // auch nach einem Fehler alle laden, gemeldet wird der erste
+        let mut first_error = None;
         for script in scripts {
-            self.lua.load(script).exec()?;
+            let name = chunk_name(script.index);
+            let result = sandbox::limited(&self.lua, || self.lua.load(script.source.as_str()).set_name(name.as_str()).exec());
+            let Err(e) = result else {
+                continue;
+            };
+            if let Err(message) = self.report(ScriptError::from_lua(&e, &self.script_positions).with_handler(name, None)) {
+                first_error.get_or_insert(message);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    fn load_lua_api(lua: &mlua::Lua) -> Result<()> {
+    /// Merkt sich den Fehler und ruft den `on:error` Handler auf. Ohne `continue_on_error`
+    /// wird der Fehler zurückgegeben und der Frame bricht ab.
+    fn report(&mut self, error: ScriptError) -> std::result::Result<(), String> {
+        warn!("{}", error);
+        if let Some(onerror) = &self.onerror_fn {
+            let result = sandbox::limited(&self.lua, || onerror.function.call::<()>(error.to_lua(&self.lua)?));
+            if let Err(e) = result {
+                warn!("{} failed: {}", onerror.name, e);
+            }
+        }
+        let message = error.to_string();
+        if self.errors.len() >= MAX_ERRORS {
+            self.errors.remove(0);
+        }
+        self.errors.push(error);
+        if self.continue_on_error {
+            Ok(())
+        } else {
+            Err(message)
+        }
+    }
+
+    /// Meldet einen Lua-Fehler eines Handlers oder Ausdrucks, siehe `report`.
+    fn report_lua(&mut self, error: &mlua::Error, handler: String, element: Option<String>) -> std::result::Result<(), String> {
+        let error = ScriptError::from_lua(error, &self.script_positions).with_handler(handler, element);
+        self.report(error)
+    }
+
+    /// Liefert die seit dem letzten Aufruf gemeldeten Skriptfehler.
+    pub fn take_errors(&mut self) -> Vec<ScriptError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Kompiliert die `<script>` Blöcke der Templates, siehe `instance`.
+    pub fn load_template_scripts(&mut self, scripts: HashMap<String, Vec<ScriptSource>>) -> Result<()> {
+        for script in scripts.values().flatten() {
+            self.script_positions.insert(script.index, ScriptPosition { line: script.line, column: script.column });
+        }
+        instance::load_template_scripts(&self.lua, scripts)
+    }
+
+    fn load_lua_api(lua: &mlua::Lua, options: &EngineOptions, clock: Rc<dyn Clock>) -> Result<()> {
         let globals = lua.globals();
-        globals.set("create_element", lua.create_function(move |lua, id: String| {
+        globals.set("create_element", lua.create_function(move |lua, (id, props): (String, Option<mlua::Table>)| {
             let globals = lua.globals();
             let vdom_ud: mlua::AnyUserData = globals.get("_vdom")?;
             let vdom_context = vdom_ud.borrow::<DynamiteContext>()?;
             let vdom = vdom_context.0.clone();
-            create_element(lua, vdom, id)
+            drop(vdom_context);
+            create_element(lua, vdom, id, props)
         })?)?;
         //globals.set("set_text", lua.create_function(set_text)?)?;
-        globals.set("get_webdata", lua.create_function(get_webdata)?)?;
         globals.set("parse_json", lua.create_function(parse_json)?)?;
+        init_json_methods(lua)?;
+        globals.set("parse_xml", lua.create_function(parse_xml)?)?;
+        globals.set("parse_csv", lua.create_function(parse_csv)?)?;
+        globals.set("parse_feed", lua.create_function(parse_feed)?)?;
+        globals.set("set_css_var", lua.create_function(set_css_var)?)?;
+        globals.set("get_css_var", lua.create_function(get_css_var)?)?;
 
-        globals.set("get_element_by_id", lua.create_function_mut(move |lua, id: String| {
-            let globals = lua.globals();
-            let vdom_ud: mlua::AnyUserData = globals.get("_vdom")?;
-            let vdom_context = vdom_ud.borrow::<DynamiteContext>()?;
-            let vdom = vdom_context.0.clone();
-            let borrow_vdom = vdom.borrow();
-            let e = borrow_vdom.find_element_by_id(&id);
-
-            if let Some(element) = e {
-                let id = element.get_internal_id().clone();
-                let handle = lua.create_userdata(ElementContext{
-                    internal_id: id,
-                    temp_node: Rc::new(RefCell::new(None)),
-                    values: Rc::new(RefCell::new(HashMap::new())),
-                })?;
-                Ok(mlua::Value::UserData(handle))
-            } else {
-                Ok(mlua::Value::Nil)
-            }
-            
+        globals.set("get_element_by_id", lua.create_function(|lua, id: String| {
+            let found = get_vdom(lua)?.borrow().find_element_by_id(&id).map(|element| *element.get_internal_id());
+            found.map(|id| element_handle(lua, id)).transpose()
+        })?)?;
+        globals.set("query_selector", lua.create_function(|lua, selectors: String| {
+            let found = query(lua, &selectors, None)?;
+            found.first().map(|id| element_handle(lua, *id)).transpose()
+        })?)?;
+        globals.set("query_selector_all", lua.create_function(|lua, selectors: String| {
+            let found = query(lua, &selectors, None)?;
+            element_handles(lua, found)
         })?)?;
 
         let add_element_func = lua.create_function(move |lua, (target_id, node_ud): (String, AnyUserData)| {
@@ -216,13 +1171,23 @@ impl Engine {
                     .map_err(|e| mlua::Error::external(format!("add_element failed: {}", e)))?;
 
                 node.internal_id = id;
+                let values = node.values.borrow().clone();
+                if !values.is_empty() {
+                    vdom.render_contexts.insert(id, values);
+                }
             }
             Ok(())
         })?;
 
         globals.set("add_element", add_element_func)?;
 
-        init_timer_methods(lua)?;
+        init_timer_methods(lua, clock)?;
+        init_animation_methods(lua)?;
+        init_state_methods(lua)?;
+        init_instance_methods(lua)?;
+        init_fetch_methods(lua, options)?;
+        storage::init_storage_methods(lua, options)?;
+        messages::init_message_methods(lua)?;
         Ok(())
     }
 
@@ -238,52 +1203,615 @@ impl Engine {
         Ok(())
     }
 
-    pub fn commit(&self) -> Result<vdom::VNode> {
+    pub fn commit(&self) -> Result<document::VDom> {
         let globals = self.lua.globals();
         let dyn_userdata: mlua::AnyUserData = globals.get("_vdom")?;
         let tmp_ctx = dyn_userdata.borrow::<DynamiteContext>()?;
         let tmp = tmp_ctx.0.borrow().clone();
 
         // und jetzt...
-        Ok(tmp.root)
+        Ok(tmp)
     }
 
     pub fn search_onupdate_functions(&mut self, vdom: &document::VDom) -> std::result::Result<(), String> {
         let mut onupdate_fns = Vec::new();
         let mut onload_fns  = Vec::new();
+        let mut onerror_fn = None;
         let root = &vdom.root;
 
-        let mut search_in_node = |node: &vdom::VNode| {
-            if let vdom::VNode::Element( ElementNode { attrs, .. }) = node {
-                if let Some(onupdate) = attrs.get("on:update") {
-                    let onupdate_fn = self.lua.load(format!("{}()", onupdate)).into_function().unwrap();
-                    onupdate_fns.push(onupdate_fn);
-                }
-                if let Some(onload) = attrs.get("on:load") {
-                    let onload_fn = self.lua.load(format!("{}()", onload)).into_function().unwrap();
-                    onload_fns.push(onload_fn);
+        if let vdom::VNode::Element( ElementNode { attrs, id, .. }) = root {
+            for (attr, handlers) in [("on:update", Some(&mut onupdate_fns)), ("on:load", Some(&mut onload_fns)), ("on:error", None)] {
+                let Some(function_name) = attrs.get(attr) else {
+                    continue;
+                };
+                let name = format!("{}=\"{}\"", attr, function_name);
+                let function = match self.lua.load(format!("{}(...)", function_name)).set_name(name.as_str()).into_function() {
+                    Ok(function) => function,
+                    Err(e) => {
+                        let error = ScriptError::new(format!("invalid handler name: {}", e)).with_handler(name, id.clone());
+                        self.report(error)?;
+                        continue;
+                    }
+                };
+                let handler = Handler { function, name, element: id.clone() };
+                match handlers {
+                    Some(handlers) => handlers.push(handler),
+                    None => onerror_fn = Some(handler),
                 }
             }
-        };
-
-        search_in_node(root);
+        }
 
         self.onupdate_fns = onupdate_fns;
         self.onload_fns = onload_fns;
+        self.onerror_fn = onerror_fn;
         Ok(())
     }
 
-    pub fn call_onupdates(&self) -> std::result::Result<(), String> {
-        for onupdate_fn in &self.onupdate_fns {
-            onupdate_fn.call::<()>(()).map_err(|e| format!("onupdate failed: {}", e))?;
+    /// Die Handler bekommen `time` und `delta` des Frames in Sekunden.
+    pub fn call_onupdates(&mut self, frame: FrameTime) -> std::result::Result<(), String> {
+        self.call_handlers(|engine| &engine.onupdate_fns, frame)
+    }
+
+    pub fn call_onload(&mut self, frame: FrameTime) -> std::result::Result<(), String> {
+        self.call_handlers(|engine| &engine.onload_fns, frame)
+    }
+
+    fn call_handlers(&mut self, handlers: impl Fn(&Self) -> &Vec<Handler>, frame: FrameTime) -> std::result::Result<(), String> {
+        for index in 0..handlers(self).len() {
+            let handler = &handlers(self)[index];
+            if let Err(e) = sandbox::limited(&self.lua, || handler.function.call::<()>((frame.time, frame.delta))) {
+                let (name, element) = (handler.name.clone(), handler.element.clone());
+                self.report_lua(&e, name, element)?;
+            }
         }
         Ok(())
     }
 
-    pub fn call_onload(&self) -> std::result::Result<(), String> {
-        for onload_fn in &self.onload_fns {
-            onload_fn.call::<()>(()).map_err(|e| format!("onload failed: {}", e))?;
+    /// Wertet den Ausdruck eines Attributs aus. Innerhalb einer Listen-Instanz sieht er deren
+    /// Template-Werte, sonst nur die Globals.
+    fn eval_expr(&self, attr: &str, expr: &str, values: &TemplateValues) -> Result<Value> {
+        let function = compile_expr(&self.lua, || format!("{}=\"{}\"", attr, expr), expr)?;
+        let env = if values.is_empty() {
+            self.lua.globals()
+        } else {
+            template_env(&self.lua, values)?
+        };
+        sandbox::limited(&self.lua, || function.call::<Value>(env))
+    }
+
+    /// Wertet die Bedingungen aller `if` / `else-if` / `else` Blöcke aus und schaltet
+    /// auf den ersten zutreffenden Zweig um. Wie in Lua sind nur `nil` und `false` falsch.
+    pub fn update_conditionals(&mut self) -> std::result::Result<(), String> {
+        self.evaluate_conditionals(&HashSet::new())
+    }
+
+    /// Wie `update_conditionals`, lässt aber die Blöcke in `skip` aus.
+    fn evaluate_conditionals(&mut self, skip: &HashSet<Ulid>) -> std::result::Result<(), String> {
+        let vdom = get_vdom(&self.lua).map_err(|e| format!("if failed: {}", e))?;
+        let blocks: Vec<(Ulid, Vec<Option<String>>, TemplateValues)> = {
+            let vdom = vdom.borrow();
+            vdom.conditionals
+                .iter()
+                .filter(|b| !skip.contains(&b.internal_id))
+                .map(|b| {
+                    let conditions = b.branches.iter().map(|br| br.condition.clone()).collect();
+                    (b.internal_id, conditions, vdom.scope_values(b.scope))
+                })
+                .collect()
+        };
+
+        'blocks: for (block_id, conditions, values) in blocks {
+            let mut active = None;
+            for (index, condition) in conditions.iter().enumerate() {
+                let Some(condition) = condition else {
+                    active = Some(index);
+                    break;
+                };
+                let value = self.eval_expr("if", condition, &values);
+                let value = match value {
+                    Ok(value) => value,
+                    Err(e) => {
+                        // der Block behält seinen bisherigen Zweig
+                        self.report_lua(&e, format!("if=\"{}\"", condition), None)?;
+                        continue 'blocks;
+                    }
+                };
+                if is_truthy(&value) {
+                    active = Some(index);
+                    break;
+                }
+            }
+
+            vdom.borrow_mut().set_conditional(&block_id, active)?;
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Wertet die Ausdrücke aller `for-each` Listen aus und gleicht deren Instanzen ab.
+    ///
+    /// Listen und bedingte Blöcke, die dabei in neuen Instanzen entstehen, werden noch im
+    /// selben Durchlauf ausgewertet.
+    pub fn update_lists(&mut self) -> std::result::Result<(), String> {
+        let vdom = get_vdom(&self.lua).map_err(|e| format!("for-each failed: {}", e))?;
+        let known_blocks: HashSet<Ulid> = vdom.borrow().conditionals.iter().map(|b| b.internal_id).collect();
+        let mut done = HashSet::new();
+
+        loop {
+            let lists: Vec<(Ulid, document::ListBinding, TemplateValues)> = {
+                let vdom = vdom.borrow();
+                vdom.lists
+                    .iter()
+                    .filter(|l| !done.contains(&l.internal_id))
+                    .map(|l| (l.internal_id, l.clone(), vdom.scope_values(l.scope)))
+                    .collect()
+            };
+            if lists.is_empty() {
+                break;
+            }
+
+            for (list_id, list, values) in lists {
+                done.insert(list_id);
+                let expr = list.expr;
+                // ohne gültige Tabelle bleibt die Liste leer
+                let items = self
+                    .eval_expr("for-each", &expr, &values)
+                    .and_then(|value| match value {
+                        Value::Table(table) => list_items(&table, &list.alias, list.key_field.as_deref()),
+                        _ => Ok(Vec::new()),
+                    })
+                    .and_then(|items| match &list.filter {
+                        Some(filter) => self.filter_items(filter, items, &values),
+                        None => Ok(items),
+                    });
+                let items = match items {
+                    Ok(items) => items,
+                    Err(e) => {
+                        // die Liste behält ihre bisherigen Instanzen
+                        self.report_lua(&e, format!("for-each=\"{}\"", expr), None)?;
+                        continue;
+                    }
+                };
+
+                let eval = |expr: &str, values: &TemplateValues| eval_template_expr(&self.lua, expr, values);
+                // die Liste kann mit der äußeren Instanz bereits entfernt worden sein
+                let exists = vdom.borrow().lists.iter().any(|l| l.internal_id == list_id);
+                if exists {
+                    vdom.borrow_mut().update_list(&list_id, items, Some(&eval))?;
+                }
+            }
+        }
+
+        self.evaluate_conditionals(&known_blocks)
+    }
+
+    /// Behält die Einträge, für die `filter` aus dem `if` einer Liste zutrifft.
+    fn filter_items(&mut self, filter: &str, items: Vec<ListItem>, scope: &TemplateValues) -> Result<Vec<ListItem>> {
+        let mut kept = Vec::with_capacity(items.len());
+        for item in items {
+            let mut values = scope.clone();
+            values.extend(item.values.clone());
+            if is_truthy(&self.eval_expr("if", filter, &values)?) {
+                kept.push(item);
+            }
+        }
+        Ok(kept)
+    }
+
+    /// Wertet die Bindungen neu aus, die von Lua abhängen (`:name`, `{{= }}`).
+    ///
+    /// Ein Ausdruck wird nur neu berechnet, wenn sich einer der gelesenen `state` Schlüssel
+    /// geändert hat. Liest er andere Globals (siehe `state::note_global_read`), läuft er in
+    /// jedem Frame. Nach dem Einfügen von Teilbäumen, die Änderungen verpasst haben können,
+    /// laufen alle Ausdrücke.
+    pub fn update_bindings(&self) -> std::result::Result<(), String> {
+        let vdom = get_vdom(&self.lua).map_err(|e| format!("bindings failed: {}", e))?;
+        let dirty = state::take_dirty(&self.lua).map_err(|e| format!("bindings failed: {}", e))?;
+
+        // der Cache wird beim Auswerten erweitert, daher vorher die sauberen Ausdrücke sammeln
+        let clean: HashSet<String> = self.lua
+            .app_data_ref::<TemplateExprCache>()
+            .map(|cache| {
+                cache.0.iter()
+                    .filter(|(_, entry)| !entry.untracked && entry.deps.is_disjoint(&dirty))
+                    .map(|(expr, _)| expr.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let eval = |expr: &str, values: &TemplateValues| eval_template_expr(&self.lua, expr, values);
+        vdom.borrow_mut().render_lua_bindings(&eval, &|expr| !clean.contains(expr));
+        Ok(())
+    }
+
+    pub fn register_function<A, T, F>(&self, name: &str, function: F) -> Result<()>
+    where
+        A: mlua::FromLuaMulti,
+        T: mlua::IntoLuaMulti,
+        F: Fn(&Lua, A) -> Result<T> + 'static,
+    {
+        self.lua.globals().set(name, self.lua.create_function(function)?)
+    }
+
+    /// Legt die Tabelle als Global und in `package.loaded` ab, falls `package` geladen ist.
+    pub fn register_module<F>(&self, name: &str, build: F) -> Result<()>
+    where
+        F: FnOnce(&Lua, &mlua::Table) -> Result<()>,
+    {
+        let module = self.lua.create_table()?;
+        build(&self.lua, &module)?;
+        let globals = self.lua.globals();
+        if let Some(package) = globals.get::<Option<mlua::Table>>("package")? {
+            package.get::<mlua::Table>("loaded")?.set(name, module.clone())?;
+        }
+        globals.set(name, module)
+    }
+
+    pub fn register_global(&self, name: &str, value: impl mlua::IntoLua) -> Result<()> {
+        self.lua.globals().set(name, value)
+    }
+
+    /// Ersetzt den Speicher hinter `storage`.
+    pub fn set_storage(&self, store: Box<dyn Storage>) -> Result<()> {
+        storage::set_storage(&self.lua, store)
+    }
+
+    /// Schreibt die im Frame geänderten `storage` Werte.
+    pub fn flush_storage(&mut self) -> std::result::Result<(), String> {
+        match storage::flush_storage(&self.lua) {
+            Ok(()) => Ok(()),
+            Err(e) => self.report_lua(&e, "storage".to_string(), None),
+        }
+    }
+
+    /// Stellt eine Nachricht für die `on_message` Handler zu, sie laufen mit `deliver_messages`.
+    pub fn post_message(&self, message: Message) -> Result<()> {
+        messages::post_message(&self.lua, message)
+    }
+
+    pub fn deliver_messages(&mut self) -> std::result::Result<(), String> {
+        match messages::deliver_messages(&self.lua) {
+            Ok(()) => Ok(()),
+            Err(e) => self.report_lua(&e, "on_message".to_string(), None),
+        }
+    }
+
+    /// Die mit `emit` erzeugten Ereignisse seit dem letzten Aufruf.
+    pub fn drain_events(&self) -> Result<Vec<Message>> {
+        messages::drain_events(&self.lua)
+    }
+
+    /// Ruft die fälligen `set_timeout`, `set_interval`, `every` und `at` Jobs auf.
+    pub fn run_timers(&mut self) -> std::result::Result<(), String> {
+        match timer::run_timers(&self.lua) {
+            Ok(()) => Ok(()),
+            Err(e) => self.report_lua(&e, "timer".to_string(), None),
+        }
+    }
+
+    /// Ruft die Callbacks der inzwischen beantworteten `fetch` Anfragen auf.
+    pub fn poll_fetches(&mut self) -> std::result::Result<(), String> {
+        match fetch::poll_fetches(&self.lua) {
+            Ok(()) => Ok(()),
+            Err(e) => self.report_lua(&e, "fetch callback".to_string(), None),
+        }
+    }
+
+    /// Ruft `on_mount` / `on_unmount` der Template-Instanzen auf, die seit dem letzten Frame
+    /// eingefügt oder entfernt wurden.
+    pub fn update_instances(&mut self) -> std::result::Result<(), String> {
+        match instance::update_instances(&self.lua) {
+            Ok(()) => Ok(()),
+            Err(e) => self.report_lua(&e, "on_mount / on_unmount".to_string(), None),
+        }
+    }
+
+    /// Schreibt den aktuellen Stand aller laufenden `animate` Aufrufe in den VDOM.
+    pub fn tick_animations(&mut self) -> std::result::Result<(), String> {
+        match sandbox::limited(&self.lua, || animation::tick_animations(&self.lua)) {
+            Ok(()) => Ok(()),
+            Err(e) => self.report_lua(&e, "animate".to_string(), None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vdom::texts;
+
+    fn test_engine() -> Engine {
+        Engine::with_options(&EngineOptions::default(), Rc::new(crate::clock::SystemClock::default())).unwrap()
+    }
+
+    #[test]
+    fn test_parse_xml_and_csv() {
+        let root = parse_xml_tree(r#"<?xml version="1.0"?>
+            <board station="Hbf"><dep line="S1" time="07:02">Flughafen &amp; Messe</dep><dep line="U2"/><note><![CDATA[<b>Bauarbeiten</b>]]></note></board>"#).unwrap();
+        assert_eq!(root.tag, "board");
+        assert_eq!(root.attr("station"), Some("Hbf"));
+        assert_eq!(root.children.len(), 3);
+        assert_eq!(root.children[0].text, "Flughafen & Messe");
+        assert_eq!(root.children[2].text, "<b>Bauarbeiten</b>");
+        assert!(parse_xml_tree("<a><b></a>").is_err());
+        let mixed = parse_xml_tree("<desc>\n  Gleis 3 <![CDATA[<b>neu</b>]]> ab 7 Uhr\n</desc>").unwrap();
+        assert_eq!(mixed.text, "Gleis 3 <b>neu</b> ab 7 Uhr");
+
+        let lua = Lua::new();
+        lua.globals().set("parse_csv", lua.create_function(parse_csv).unwrap()).unwrap();
+        let result: String = lua.load(r#"
+            local rows = parse_csv("name;temp\nBerlin;21\nHamburg;18;extra\n", { delimiter = ";" })
+            local plain = parse_csv("a,b\n", { header = false })
+            return rows[2].name .. rows[2].temp .. rows[2][3] .. #rows .. plain[1][2]
+        "#).eval().unwrap();
+        assert_eq!(result, "Hamburg18extra2b");
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let lua = Lua::new();
+        lua.globals().set("parse_json", lua.create_function(parse_json).unwrap()).unwrap();
+        init_json_methods(&lua).unwrap();
+        let result: String = lua.load(r#"
+            local data = parse_json('{"id": 7, "temp": 21.5, "tags": [], "meta": {}, "note": null}')
+            assert(math.type(data.id) == "integer" and data.note == json.null)
+            return to_json(data)
+        "#).eval().unwrap();
+        assert_eq!(result, r#"{"id":7,"meta":{},"note":null,"tags":[],"temp":21.5}"#);
+
+        let result: String = lua.load(r#"
+            return to_json({ list = { 1, 2 }, empty = json.array(), map = json.object({ "a" }), [3] = true }, { pretty = false })
+        "#).eval().unwrap();
+        assert_eq!(result, r#"{"3":true,"empty":[],"list":[1,2],"map":{"1":"a"}}"#);
+
+        assert!(lua.load("local t = {} t.self = t return to_json(t)").exec().is_err());
+        assert!(lua.load("return to_json({ f = print })").exec().is_err());
+        let (value, error): (Value, Option<String>) = lua.load("return parse_json('{')").eval().unwrap();
+        assert!(value.is_nil() && error.is_some());
+    }
+
+    #[test]
+    fn test_scripts_after_a_failed_one_still_load() {
+        let mut engine = test_engine();
+        let html = "<html><body><script>a = 1</script><script>error('boom')</script><script>b = 2</script></body></html>";
+        let error = engine.load_scripts(crate::parser::load_lua_scripts(html).unwrap()).unwrap_err();
+        assert!(error.contains("boom"));
+        let (a, b): (i64, i64) = engine.lua.load("return a, b").eval().unwrap();
+        assert_eq!((a, b), (1, 2));
+        assert_eq!(engine.take_errors().len(), 1);
+    }
+
+    #[test]
+    fn test_register_host_api() {
+        let engine = test_engine();
+        engine.register_function("double", |_, x: i64| Ok(x * 2)).unwrap();
+        engine.register_module("device", |lua, module| {
+            module.set("name", "player-1")?;
+            module.set("volume", lua.create_function(|_, ()| Ok(80))?)
+        }).unwrap();
+        engine.register_global("debug_mode", true).unwrap();
+        let result: String = engine.lua.load(r#"
+            local device = require("device")
+            return double(21) .. device.name .. device.volume() .. tostring(debug_mode)
+        "#).eval().unwrap();
+        assert_eq!(result, "42player-180true");
+    }
+
+    #[test]
+    fn test_query_selector_from_lua() {
+        let engine = test_engine();
+        let vdom = document::VDom::new(r#"
+            <html><body>
+                <ul id="days"><li>Mo</li><li class="today">Di</li><li>Mi</li></ul>
+            </body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        let result: String = engine.lua.load(r#"
+            local today = query_selector("#days .today")
+            local list = today:parent()
+            assert(#list:children() == 3 and #list:query_selector_all("li") == 3)
+            assert(list:query_selector("ul") == nil and today:next_sibling():next_sibling() == nil)
+            return list:id() .. today:prev_sibling():tag() .. #query_selector_all("body li, ul")
+        "#).eval().unwrap();
+        assert_eq!(result, "daysli4");
+        assert!(engine.lua.load(r#"query_selector("li::after")"#).exec().is_err());
+    }
+
+    #[test]
+    fn test_attributes_from_lua() {
+        let engine = test_engine();
+        let before = document::VDom::new(r#"
+            <html><body><p id="temp" class="value" data-unit="°C">21</p></body></html>
+        "#).unwrap();
+        engine.begin(&before).unwrap();
+        let result: String = engine.lua.load(r#"
+            local p = get_element_by_id("temp")
+            p:set_attr("class", "value warm")
+            p:data("updated", 1200)
+            p:data("unit", nil)
+            p:set_attr("style", "color: red")
+            p:set_attr("id", "current")
+            assert(p:data().updated == "1200" and p:data("unit") == nil)
+            assert(query_selector(".warm"):id() == "current" and get_element_by_id("temp") == nil)
+            return p:get_attr("class") .. "|" .. p:get_attr("style")
+        "#).eval().unwrap();
+        assert_eq!(result, "value warm|color: red");
+        assert!(engine.lua.load(r#"query_selector("p"):set_attr("title", {})"#).exec().is_err());
+
+        let new = engine.commit().unwrap();
+        let Some(vdom::DiffOp::Composite(ops)) = vdom::diff_vnode(&before.root, &new.root) else {
+            panic!("expected a diff");
+        };
+        fn changed_attrs(ops: &[vdom::DiffOp], out: &mut Vec<String>) {
+            for op in ops {
+                match op {
+                    vdom::DiffOp::ChangeAttributes { changes, .. } => out.extend(changes.iter().map(|c| c.0.clone())),
+                    vdom::DiffOp::PatchChild(_, op) => changed_attrs(std::slice::from_ref(op.as_ref()), out),
+                    vdom::DiffOp::Composite(ops) => changed_attrs(ops, out),
+                    _ => {}
+                }
+            }
+        }
+        let mut changed = Vec::new();
+        changed_attrs(&ops, &mut changed);
+        changed.sort();
+        changed.dedup();
+        assert_eq!(changed, vec!["class", "data-unit", "data-updated", "id"]);
+    }
+
+    #[test]
+    fn test_nested_lists_from_lua() {
+        let mut engine = test_engine();
+        let vdom = document::VDom::new(r#"
+            <html><body>
+                <div for-each="weather_data" as="w" key="city">
+                    <h2>{{ w.city }}</h2>
+                    <span if="w.city == 'Berlin'">Hauptstadt</span>
+                    <p for-each="w.days" as="d">{{ d.name }}</p>
+                </div>
+            </body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        engine.lua.load(r#"
+            weather_data = {
+                { city = "Berlin", days = { { name = "Mo" }, { name = "Di" } } },
+                { city = "Hamburg", days = { { name = "Mi" } } },
+            }
+        "#).exec().unwrap();
+        engine.update_conditionals().unwrap();
+        engine.update_lists().unwrap();
+
+        let found = texts(&engine.commit().unwrap().root);
+        assert_eq!(found, vec!["Berlin", "Hauptstadt", "Mo", "Di", "Hamburg", "Mi"]);
+    }
+
+    #[test]
+    fn test_json_null_is_falsy_in_templates() {
+        let mut engine = test_engine();
+        let vdom = document::VDom::new(r#"
+            <html><body>
+                <p if="weather.note">Hinweis</p>
+                <ul><li for-each="alerts" as="a" if="a.text">{{ a.id }}{{ a.text }}</li></ul>
+            </body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        engine.lua.load(r#"
+            weather = parse_json('{"city": "Berlin", "note": null}')
+            alerts = parse_json('[{"id": 1, "text": null}, {"id": 2, "text": "Sturm"}]')
+        "#).exec().unwrap();
+        engine.update_conditionals().unwrap();
+        engine.update_lists().unwrap();
+
+        let found = texts(&engine.commit().unwrap().root);
+        assert_eq!(found.concat(), "2Sturm");
+    }
+
+    #[test]
+    fn test_typed_template_values() {
+        let mut engine = test_engine();
+        let vdom = document::VDom::new(r#"
+            <html><body>
+                <p id="now">{{= temp > 30 and "heiß" or "mild" }} {{= temp + 0.5 }}</p>
+                <ul><li for-each="weather_data" as="w"><b if="w.temp > 30">!</b>{{ w.city }} {{= w.temp * 2 }} {{= w.open }}</li></ul>
+            </body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        engine.lua.load(r#"
+            weather_data = { { city = "Berlin", temp = 31, open = true }, { city = "Hamburg", temp = 9.5, open = false } }
+            get_element_by_id("now"):set_text("temp", 32)
+        "#).exec().unwrap();
+        engine.update_lists().unwrap();
+
+        let found = texts(&engine.commit().unwrap().root);
+        assert_eq!(found, vec!["heiß 32.5", "!", "Berlin 62 true", "Hamburg 19 false"]);
+    }
+
+    #[test]
+    fn test_bindings_track_all_reads() {
+        let mut engine = test_engine();
+        let vdom = document::VDom::new(r#"
+            <html><body>
+                <p>{{= state.temp }} {{= label }}</p>
+                <p if="state.show">{{= state.temp }}</p>
+            </body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        let mut frame = |script: &str| {
+            engine.lua.load(script).exec().unwrap();
+            engine.update_conditionals().unwrap();
+            engine.update_bindings().unwrap();
+            texts(&engine.commit().unwrap().root)
+        };
+
+        assert_eq!(frame(r#"state.temp = 20 label = "A""#), vec!["20 A"]);
+        // `label` ist kein `state`, der Ausdruck läuft trotzdem neu
+        assert_eq!(frame(r#"label = "B""#), vec!["20 B"]);
+        // der Zweig hat die Änderung außerhalb des Baums verpasst
+        assert_eq!(frame("state.show = true"), vec!["20 B", "20"]);
+        assert_eq!(frame("state.show = false state.temp = 25"), vec!["25 B"]);
+        assert_eq!(frame("state.show = true"), vec!["25 B", "25"]);
+    }
+
+    #[test]
+    fn test_hidden_branch_handles() {
+        let mut engine = test_engine();
+        let vdom = document::VDom::new(r#"
+            <html><body>
+                <p id="alert" if="show_alert">Warnung: {{ text }}</p>
+                <ul><li for-each="cities" as="c" if="c.name ~= 'Köln'">{{ c.name }}</li></ul>
+            </body></html>
+        "#).unwrap();
+        engine.begin(&vdom).unwrap();
+        engine.lua.load(r#"
+            cities = { { name = "Berlin" }, { name = "Köln" }, { name = "Hamburg" } }
+            local alert = get_element_by_id("alert")
+            alert:set_text("text", "Sturm")
+            alert:set_attr("class", "warn")
+        "#).exec().unwrap();
+        engine.update_conditionals().unwrap();
+        engine.update_lists().unwrap();
+
+        let found = texts(&engine.commit().unwrap().root);
+        assert_eq!(found, vec!["Berlin", "Hamburg"]);
+
+        // die Änderungen am ausgeblendeten Element erscheinen mit dem Zweig
+        engine.lua.load("show_alert = true").exec().unwrap();
+        engine.update_conditionals().unwrap();
+        let vdom = engine.commit().unwrap();
+        let found = texts(&vdom.root);
+        assert_eq!(found, vec!["Warnung: Sturm", "Berlin", "Hamburg"]);
+        let Some(VNode::Element(alert)) = vdom.find_element_by_id("alert") else {
+            panic!("alert not found");
+        };
+        assert_eq!(alert.attrs.get("class").map(String::as_str), Some("warn"));
+    }
+
+    #[test]
+    fn test_parse_feeds() {
+        let lua = Lua::new();
+        lua.globals().set("parse_feed", lua.create_function(parse_feed).unwrap()).unwrap();
+        lua.globals().set("rss", r#"<rss version="2.0" xmlns:dc="http://purl.org/dc/elements/1.1/"><channel>
+            <title>News</title><link>https://example.com</link>
+            <item><title>Eins</title><link>https://example.com/1</link><description>Text</description>
+                <pubDate>Mon, 07 Jun 2021 07:00:00 GMT</pubDate><dc:creator>Anna</dc:creator>
+                <enclosure url="https://example.com/1.jpg" type="image/jpeg"/></item>
+        </channel></rss>"#).unwrap();
+        lua.globals().set("atom", r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Blog</title>
+            <link rel="self" href="https://example.com/feed"/><link href="https://example.com/"/>
+            <entry><id>urn:1</id><title>Zwei</title><link href="https://example.com/2"/>
+                <summary>Kurz</summary><updated>2021-06-07T07:00:00Z</updated><author><name>Ben</name></author></entry>
+        </feed>"#).unwrap();
+        let result: String = lua.load(r#"
+            local r, a = parse_feed(rss), parse_feed(atom)
+            local i, e = r.items[1], a.items[1]
+            return table.concat({ r.title, i.title, i.link, i.id, i.summary, i.author, i.image,
+                a.title, a.link, e.id, e.title, e.link, e.summary, e.published, e.author }, "|")
+        "#).eval().unwrap();
+        assert_eq!(
+            result,
+            "News|Eins|https://example.com/1|https://example.com/1|Text|Anna|https://example.com/1.jpg|\
+             Blog|https://example.com/|urn:1|Zwei|https://example.com/2|Kurz|2021-06-07T07:00:00Z|Ben"
+        );
+        let (feed, error): (Option<mlua::Table>, Option<String>) = lua.load("return parse_feed('<html></html>')").eval().unwrap();
+        assert!(feed.is_none() && error.is_some());
+    }
+}
@@ -0,0 +1,196 @@
+//! # Sandbox
+//!
+//! Schränkt den Lua-State für Inhalte ein, denen nicht voll vertraut wird: nur ausgewählte
+//! Teile der Standardbibliothek, eine Speichergrenze und ein Budget an Instruktionen und
+//! Laufzeit für jeden Aufruf aus dem Host (`on:load`, `on:update`, Ausdrücke, Hooks).
+//!
+//! Ist das Budget aufgebraucht, bricht der Aufruf mit einem Lua-Fehler ab, statt hängen zu
+//! bleiben. Mit `continue_on_error` (Standard in `sandboxed()`) trifft das nur diesen Handler,
+//! sonst liefert `run_frame` den Fehler.
+
+use std::{path::PathBuf, time::{Duration, Instant}};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, VmState};
+
+use super::storage::default_storage_dir;
+
+/// Nach so vielen Instruktionen prüft der Hook das Budget.
+const HOOK_INTERVAL: u32 = 1000;
+
+/// Einstellungen für den Lua-State einer `Dynamite` Instanz.
+///
+/// `Default` entspricht dem bisherigen Verhalten ohne Einschränkungen,
+/// `EngineOptions::sandboxed()` ist für fremde Inhalte gedacht.
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    /// Geladene Bibliotheken: `coroutine`, `table`, `io`, `os`, `string`, `utf8`, `math`, `package`.
+    /// Ohne `io` fehlen auch `dofile` und `loadfile`.
+    pub stdlib: Vec<String>,
+    /// Maximale Anzahl Instruktionen je Aufruf, auf ganze Tausend genau.
+    pub instruction_limit: Option<u64>,
+    /// Maximale Laufzeit je Aufruf.
+    pub time_limit: Option<Duration>,
+    /// Maximaler Speicher des Lua-States in Bytes.
+    pub memory_limit: Option<usize>,
+    /// Nach einem fehlgeschlagenen Handler weiterrendern, statt `run_frame` abzubrechen.
+    /// Die Fehler liefert dann `Dynamite::take_script_errors`.
+    pub continue_on_error: bool,
+    /// Erlaubt `fetch` und `get_webdata`, ohne liefern beide einen Fehler.
+    pub allow_network: bool,
+    /// Basis für `fetch` URLs, die mit `/` beginnen, z. B. ein lokaler Mock-Server in Tests.
+    pub fetch_base_url: Option<String>,
+    /// Verzeichnis für den HTTP-Cache von `fetch` und `get_webdata`, siehe `http_cache`.
+    pub http_cache_dir: Option<PathBuf>,
+    /// Verzeichnis für `storage`, Standard ist `default_storage_dir()`. Ohne bleiben die Werte
+    /// nur im Speicher, so in Tests und in `sandboxed()`.
+    pub storage_dir: Option<PathBuf>,
+    /// Namespace für `storage`, ohne Angabe der `<title>` des Dokuments.
+    pub storage_namespace: Option<String>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            stdlib: ["coroutine", "table", "io", "os", "string", "utf8", "math", "package"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            instruction_limit: None,
+            time_limit: None,
+            memory_limit: None,
+            continue_on_error: false,
+            allow_network: true,
+            fetch_base_url: None,
+            http_cache_dir: None,
+            storage_dir: if cfg!(test) { None } else { default_storage_dir() },
+            storage_namespace: None,
+        }
+    }
+}
+
+impl EngineOptions {
+    /// Nur rechnende Bibliotheken, 10 Mio. Instruktionen bzw. 50 ms je Aufruf, 64 MiB Speicher,
+    /// kein Netzwerk. Ein Handler, der sein Budget überschreitet, hält die übrigen nicht auf.
+    pub fn sandboxed() -> Self {
+        Self {
+            stdlib: ["coroutine", "table", "string", "utf8", "math"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            instruction_limit: Some(10_000_000),
+            time_limit: Some(Duration::from_millis(50)),
+            memory_limit: Some(64 * 1024 * 1024),
+            continue_on_error: true,
+            allow_network: false,
+            fetch_base_url: None,
+            http_cache_dir: None,
+            storage_dir: None,
+            storage_namespace: None,
+        }
+    }
+
+    fn libs(&self) -> Result<StdLib, String> {
+        let mut libs = StdLib::NONE;
+        for name in &self.stdlib {
+            libs |= match name.as_str() {
+                "coroutine" => StdLib::COROUTINE,
+                "table" => StdLib::TABLE,
+                "io" => StdLib::IO,
+                "os" => StdLib::OS,
+                "string" => StdLib::STRING,
+                "utf8" => StdLib::UTF8,
+                "math" => StdLib::MATH,
+                "package" => StdLib::PACKAGE,
+                _ => return Err(format!("unknown or unsafe Lua library: {}", name)),
+            };
+        }
+        Ok(libs)
+    }
+}
+
+/// Verbrauch des laufenden Aufrufs, als App-Data am Lua-State.
+struct Budget {
+    /// Verschachtelungstiefe, nur der äußerste Aufruf setzt das Budget zurück.
+    depth: usize,
+    started: Instant,
+    instructions: u64,
+}
+
+/// Erzeugt einen Lua-State nach `options`.
+pub fn create_lua(options: &EngineOptions) -> Result<Lua, String> {
+    let lua = Lua::new_with(options.libs()?, LuaOptions::default())
+        .map_err(|e| format!("creating Lua state failed: {}", e))?;
+
+    if !options.stdlib.iter().any(|lib| lib == "io") {
+        let globals = lua.globals();
+        for name in ["dofile", "loadfile"] {
+            globals.raw_set(name, mlua::Value::Nil).map_err(|e| e.to_string())?;
+        }
+    }
+
+    if let Some(limit) = options.memory_limit {
+        lua.set_memory_limit(limit).map_err(|e| format!("setting memory limit failed: {}", e))?;
+    }
+
+    if options.instruction_limit.is_some() || options.time_limit.is_some() {
+        lua.set_app_data(Budget { depth: 0, started: Instant::now(), instructions: 0 });
+        let instruction_limit = options.instruction_limit;
+        let time_limit = options.time_limit;
+        lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |lua, _debug| {
+            let Some(mut budget) = lua.app_data_mut::<Budget>() else {
+                return Ok(VmState::Continue);
+            };
+            budget.instructions += HOOK_INTERVAL as u64;
+            if instruction_limit.is_some_and(|limit| budget.instructions > limit) {
+                return Err(mlua::Error::runtime("instruction limit exceeded"));
+            }
+            if time_limit.is_some_and(|limit| budget.started.elapsed() > limit) {
+                return Err(mlua::Error::runtime("time limit exceeded"));
+            }
+            Ok(VmState::Continue)
+        });
+    }
+    Ok(lua)
+}
+
+/// Führt einen Aufruf aus dem Host mit frischem Budget aus.
+/// Verschachtelte Aufrufe, z. B. Ausdrücke während `set_text`, zählen zum äußeren.
+pub fn limited<R>(lua: &Lua, f: impl FnOnce() -> mlua::Result<R>) -> mlua::Result<R> {
+    if let Some(mut budget) = lua.app_data_mut::<Budget>() {
+        if budget.depth == 0 {
+            budget.started = Instant::now();
+            budget.instructions = 0;
+        }
+        budget.depth += 1;
+    }
+    let result = f();
+    if let Some(mut budget) = lua.app_data_mut::<Budget>() {
+        budget.depth -= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runaway_loop_is_stopped() {
+        let options = EngineOptions { time_limit: None, ..EngineOptions::sandboxed() };
+        let lua = create_lua(&options).unwrap();
+        let result = limited(&lua, || lua.load("while true do end").exec());
+        assert!(result.unwrap_err().to_string().contains("instruction limit exceeded"));
+
+        // der nächste Aufruf hat wieder das volle Budget
+        let sum: i64 = limited(&lua, || lua.load("local s = 0 for i = 1, 1000 do s = s + i end return s").eval()).unwrap();
+        assert_eq!(sum, 500500);
+    }
+
+    #[test]
+    fn test_sandbox_stdlib() {
+        let lua = create_lua(&EngineOptions::sandboxed()).unwrap();
+        let missing: bool = lua.load("return io == nil and os == nil and require == nil and dofile == nil").eval().unwrap();
+        assert!(missing);
+        assert!(create_lua(&EngineOptions { stdlib: vec!["debug".to_string()], ..Default::default() }).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! # Reaktiver Zustand
+//!
+//! Stellt die globale Tabelle `state` bereit. Schreibzugriffe auf `state.key` merken sich
+//! den geänderten Schlüssel, Lesezugriffe während einer Template-Auswertung werden als
+//! Abhängigkeit des Ausdrucks erfasst. So rendert `run_frame` nur die Bindungen neu,
+//! die einen geänderten Schlüssel gelesen haben.
+//!
+//! Verfolgt werden nur Schlüssel der obersten Ebene: `state.weather.temp = 5` löst nichts aus,
+//! `state.weather = data` schon. Tabellen gelten bei jeder Zuweisung als geändert.
+//!
+//! Liest ein Ausdruck andere Globals, deren Änderungen niemand meldet, läuft er weiter
+//! in jedem Frame, siehe `Reads::untracked`.
+
+use std::collections::HashSet;
+
+use mlua::{AnyUserData, Lua, Table, Value};
+
+use super::lua_value_to_string;
+
+/// Globals, deren Lesen einen Ausdruck nicht von Änderungen außerhalb von `state` abhängig
+/// macht: `state` selbst und Bibliotheken ohne Zufall oder Uhrzeit.
+const TRACKED_GLOBALS: &[&str] = &[
+    "state", "string", "table", "utf8", "tostring", "tonumber", "type", "pairs", "ipairs", "select", "next",
+];
+
+/// Die Lesezugriffe einer Auswertung.
+#[derive(Default)]
+pub struct Reads {
+    /// Gelesene Schlüssel aus `state`.
+    pub keys: HashSet<String>,
+    /// Es wurden andere Globals gelesen, der Ausdruck kann sich also jederzeit ändern.
+    pub untracked: bool,
+}
+
+/// Wird als `_state` in den Lua-Globals abgelegt.
+#[derive(Default)]
+pub struct StateContext {
+    /// Seit dem letzten Rendern geänderte Schlüssel.
+    dirty: HashSet<String>,
+    /// Lesezugriffe je laufender Auswertung, verschachtelte Auswertungen liegen oben.
+    reads: Vec<Reads>,
+}
+impl mlua::UserData for StateContext {}
+
+fn with_state<R>(lua: &Lua, f: impl FnOnce(&mut StateContext) -> R) -> mlua::Result<R> {
+    let ctx_ud: AnyUserData = lua.globals().get("_state")?;
+    let mut ctx = ctx_ud.borrow_mut::<StateContext>()?;
+    Ok(f(&mut ctx))
+}
+
+/// Beginnt die Erfassung der gelesenen Schlüssel für eine Auswertung.
+pub fn begin_tracking(lua: &Lua) -> mlua::Result<()> {
+    with_state(lua, |ctx| ctx.reads.push(Reads::default()))
+}
+
+/// Beendet die Erfassung und liefert die Lesezugriffe.
+pub fn end_tracking(lua: &Lua) -> mlua::Result<Reads> {
+    with_state(lua, |ctx| ctx.reads.pop().unwrap_or_default())
+}
+
+/// Vermerkt, dass die laufende Auswertung das Global `name` gelesen hat.
+pub fn note_global_read(lua: &Lua, name: &str) -> mlua::Result<()> {
+    if TRACKED_GLOBALS.contains(&name) {
+        return Ok(());
+    }
+    with_state(lua, |ctx| {
+        if let Some(reads) = ctx.reads.last_mut() {
+            reads.untracked = true;
+        }
+    })
+}
+
+/// Liefert die geänderten Schlüssel und setzt sie zurück.
+pub fn take_dirty(lua: &Lua) -> mlua::Result<HashSet<String>> {
+    with_state(lua, |ctx| std::mem::take(&mut ctx.dirty))
+}
+
+pub fn init_state_methods(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    globals.set("_state", lua.create_userdata(StateContext::default())?)?;
+
+    // die eigentlichen Werte liegen in `values`, `state` selbst bleibt leer,
+    // damit jeder Zugriff über die Metamethoden läuft
+    let values = lua.create_table()?;
+    let meta = lua.create_table()?;
+
+    let read_values = values.clone();
+    meta.set("__index", lua.create_function(move |lua, (_, key): (Table, Value)| {
+        if let Some(name) = lua_value_to_string(&key) {
+            with_state(lua, |ctx| {
+                if let Some(reads) = ctx.reads.last_mut() {
+                    reads.keys.insert(name);
+                }
+            })?;
+        }
+        read_values.raw_get::<Value>(key)
+    })?)?;
+
+    let write_values = values.clone();
+    meta.set("__newindex", lua.create_function(move |lua, (_, key, value): (Table, Value, Value)| {
+        let old = write_values.raw_get::<Value>(key.clone())?;
+        let changed = matches!(value, Value::Table(_)) || old != value;
+        write_values.raw_set(key.clone(), value)?;
+        if changed {
+            if let Some(name) = lua_value_to_string(&key) {
+                with_state(lua, |ctx| {
+                    ctx.dirty.insert(name);
+                })?;
+            }
+        }
+        Ok(())
+    })?)?;
+
+    let pairs_values = values.clone();
+    meta.set("__pairs", lua.create_function(move |lua, _: Table| {
+        let next: mlua::Function = lua.globals().get("next")?;
+        Ok((next, pairs_values.clone(), Value::Nil))
+    })?)?;
+
+    let state = lua.create_table()?;
+    state.set_metatable(Some(meta));
+    globals.set("state", state)?;
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! # Storage
+//!
+//! Dauerhafte Werte für Skripte, ähnlich `localStorage`:
+//!
+//! ```lua
+//! local count = storage.get("visits", 0) + 1
+//! storage.set("visits", count)
+//! storage.set("selection", { city = "Berlin", days = { 1, 2 } })
+//! storage.remove("selection")           -- wie storage.set("selection", nil)
+//! for _, key in ipairs(storage.keys()) do print(key) end
+//! ```
+//!
+//! Werte sind alles, was `to_json` kodieren kann. Jedes Dokument hat seinen eigenen Namespace:
+//! `EngineOptions::storage_namespace`, sonst der `<title>` des Dokuments.
+//!
+//! Die Werte landen als `<namespace>.json` in `EngineOptions::storage_dir`, standardmäßig
+//! `default_storage_dir()`. Ohne Verzeichnis (in Tests und `EngineOptions::sandboxed()`)
+//! bleiben sie nur im Speicher. Geschrieben wird höchstens einmal je Frame, nicht bei jedem
+//! `set`. Eigene Speicher implementieren `Storage` und werden mit `Dynamite::set_storage` gesetzt.
+
+use std::{cell::RefCell, collections::{BTreeMap, HashMap, HashSet}, fmt::Write, fs, path::PathBuf, rc::Rc};
+
+use log::warn;
+use mlua::{AnyUserData, Lua, Value};
+use serde_json::Value as JsonValue;
+
+use super::{json_to_lua, lua_to_json, EngineOptions};
+
+/// Ein Speicher für `storage`, die Werte sind nach Namespace getrennt.
+pub trait Storage {
+    fn get(&mut self, namespace: &str, key: &str) -> Result<Option<JsonValue>, String>;
+    fn set(&mut self, namespace: &str, key: &str, value: JsonValue) -> Result<(), String>;
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<(), String>;
+    /// Sortierte Schlüssel des Namespace.
+    fn keys(&mut self, namespace: &str) -> Result<Vec<String>, String>;
+
+    /// Schreibt gepufferte Änderungen, `run_frame` ruft das einmal je Frame auf.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Standardverzeichnis für `storage`: `$XDG_DATA_HOME/dynamite` bzw. `~/.local/share/dynamite`,
+/// unter macOS `~/Library/Application Support/dynamite`, unter Windows `%APPDATA%\dynamite`.
+pub fn default_storage_dir() -> Option<PathBuf> {
+    let env = |name: &str| std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let base = if cfg!(windows) {
+        env("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        env("XDG_DATA_HOME").or_else(|| env("HOME").map(|home| home.join(".local/share")))
+    };
+    base.map(|dir| dir.join("dynamite"))
+}
+
+type Entries = BTreeMap<String, JsonValue>;
+
+/// Hält die Werte nur im Speicher. Klone teilen sich die Werte, z. B. um sie in Tests zu prüfen.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    namespaces: Rc<RefCell<HashMap<String, Entries>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, namespace: &str, key: &str) -> Result<Option<JsonValue>, String> {
+        Ok(self.namespaces.borrow().get(namespace).and_then(|entries| entries.get(key).cloned()))
+    }
+
+    fn set(&mut self, namespace: &str, key: &str, value: JsonValue) -> Result<(), String> {
+        self.namespaces.borrow_mut().entry(namespace.to_string()).or_default().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<(), String> {
+        if let Some(entries) = self.namespaces.borrow_mut().get_mut(namespace) {
+            entries.remove(key);
+        }
+        Ok(())
+    }
+
+    fn keys(&mut self, namespace: &str) -> Result<Vec<String>, String> {
+        Ok(self.namespaces.borrow().get(namespace).map(|entries| entries.keys().cloned().collect()).unwrap_or_default())
+    }
+}
+
+/// Eine JSON-Datei je Namespace, gelesen beim ersten Zugriff. Änderungen werden mit `flush`
+/// geschrieben, spätestens wenn der Speicher verworfen wird.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    loaded: HashMap<String, Entries>,
+    /// Namespaces mit Änderungen seit dem letzten `flush`.
+    dirty: HashSet<String>,
+}
+
+impl FileStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, loaded: HashMap::new(), dirty: HashSet::new() }
+    }
+
+    /// Der Namespace percent-kodiert, damit z. B. `Wetter/Berlin` und `Wetter Berlin`
+    /// verschiedene Dateien bekommen.
+    fn path(&self, namespace: &str) -> PathBuf {
+        let mut name = String::new();
+        for byte in namespace.bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                name.push(byte as char);
+            } else {
+                let _ = write!(name, "%{:02X}", byte);
+            }
+        }
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn entries(&mut self, namespace: &str) -> Result<&mut Entries, String> {
+        if !self.loaded.contains_key(namespace) {
+            let path = self.path(namespace);
+            let entries = match fs::read_to_string(&path) {
+                Ok(data) => serde_json::from_str(&data).map_err(|e| format!("{} is corrupt: {}", path.display(), e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Entries::new(),
+                Err(e) => return Err(format!("reading {} failed: {}", path.display(), e)),
+            };
+            self.loaded.insert(namespace.to_string(), entries);
+        }
+        Ok(self.loaded.get_mut(namespace).expect("namespace loaded above"))
+    }
+
+    /// Schreibt über eine temporäre Datei, ein Absturz hinterlässt keine halbe Datei.
+    fn save(&mut self, namespace: &str) -> Result<(), String> {
+        let path = self.path(namespace);
+        let data = serde_json::to_string_pretty(self.entries(namespace)?).map_err(|e| e.to_string())?;
+        let temp = path.with_extension("json.tmp");
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&temp, data))
+            .and_then(|_| fs::rename(&temp, &path))
+            .map_err(|e| format!("writing {} failed: {}", path.display(), e))
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&mut self, namespace: &str, key: &str) -> Result<Option<JsonValue>, String> {
+        Ok(self.entries(namespace)?.get(key).cloned())
+    }
+
+    fn set(&mut self, namespace: &str, key: &str, value: JsonValue) -> Result<(), String> {
+        self.entries(namespace)?.insert(key.to_string(), value);
+        self.dirty.insert(namespace.to_string());
+        Ok(())
+    }
+
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<(), String> {
+        if self.entries(namespace)?.remove(key).is_some() {
+            self.dirty.insert(namespace.to_string());
+        }
+        Ok(())
+    }
+
+    fn keys(&mut self, namespace: &str) -> Result<Vec<String>, String> {
+        Ok(self.entries(namespace)?.keys().cloned().collect())
+    }
+
+    /// Nicht geschriebene Namespaces bleiben für den nächsten Versuch markiert.
+    fn flush(&mut self) -> Result<(), String> {
+        let mut first_error = None;
+        for namespace in std::mem::take(&mut self.dirty) {
+            if let Err(e) = self.save(&namespace) {
+                self.dirty.insert(namespace);
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FileStorage {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("storage: {}", e);
+        }
+    }
+}
+
+/// Wird als `_storage` in den Lua-Globals abgelegt.
+pub struct StorageContext {
+    store: Box<dyn Storage>,
+    namespace: String,
+}
+impl mlua::UserData for StorageContext {}
+
+fn with_storage<R>(lua: &Lua, f: impl FnOnce(&mut dyn Storage, &str) -> Result<R, String>) -> mlua::Result<R> {
+    let ctx_ud: AnyUserData = lua.globals().get("_storage")?;
+    let mut ctx = ctx_ud.borrow_mut::<StorageContext>()?;
+    let ctx = &mut *ctx;
+    f(ctx.store.as_mut(), &ctx.namespace).map_err(|e| mlua::Error::runtime(format!("storage: {}", e)))
+}
+
+/// Schreibt die Änderungen des Frames, siehe `Storage::flush`.
+pub fn flush_storage(lua: &Lua) -> mlua::Result<()> {
+    with_storage(lua, |store, _| store.flush())
+}
+
+/// Ersetzt den Speicher, der Namespace bleibt.
+pub fn set_storage(lua: &Lua, store: Box<dyn Storage>) -> mlua::Result<()> {
+    let ctx_ud: AnyUserData = lua.globals().get("_storage")?;
+    ctx_ud.borrow_mut::<StorageContext>()?.store = store;
+    Ok(())
+}
+
+pub fn init_storage_methods(lua: &Lua, options: &EngineOptions) -> mlua::Result<()> {
+    let store: Box<dyn Storage> = match &options.storage_dir {
+        Some(dir) => Box::new(FileStorage::new(dir.clone())),
+        None => Box::new(MemoryStorage::default()),
+    };
+    let namespace = options.storage_namespace.clone().unwrap_or_else(|| "default".to_string());
+    let globals = lua.globals();
+    globals.set("_storage", lua.create_userdata(StorageContext { store, namespace })?)?;
+
+    let storage = lua.create_table()?;
+    storage.set("get", lua.create_function(|lua, (key, default): (String, Value)| {
+        match with_storage(lua, |store, namespace| store.get(namespace, &key))? {
+            Some(value) => json_to_lua(lua, &value),
+            None => Ok(default),
+        }
+    })?)?;
+    storage.set("set", lua.create_function(|lua, (key, value): (String, Value)| {
+        if value.is_nil() {
+            return with_storage(lua, |store, namespace| store.remove(namespace, &key));
+        }
+        let value = lua_to_json(lua, &value, 0)?;
+        with_storage(lua, |store, namespace| store.set(namespace, &key, value))
+    })?)?;
+    storage.set("remove", lua.create_function(|lua, key: String| {
+        with_storage(lua, |store, namespace| store.remove(namespace, &key))
+    })?)?;
+    storage.set("keys", lua.create_function(|lua, ()| {
+        with_storage(lua, |store, namespace| store.keys(namespace))
+    })?)?;
+    globals.set("storage", storage)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripting::init_json_methods;
+
+    #[test]
+    fn test_storage_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("dynamite-storage-{}", ulid::Ulid::new()));
+        let options = EngineOptions {
+            storage_dir: Some(dir.clone()),
+            storage_namespace: Some("Wetter/Berlin".to_string()),
+            ..Default::default()
+        };
+        let script = r#"
+            storage.set("visits", storage.get("visits", 0) + 1)
+            storage.set("selection", { city = "Berlin" })
+            storage.set("tmp", true)
+            storage.remove("tmp")
+        "#;
+        let file = dir.join("Wetter%2FBerlin.json");
+        for run in 0..2 {
+            let lua = Lua::new();
+            init_json_methods(&lua).unwrap();
+            init_storage_methods(&lua, &options).unwrap();
+            lua.load(script).exec().unwrap();
+            // geschrieben wird erst mit dem Frame
+            assert_eq!(file.exists(), run > 0);
+            flush_storage(&lua).unwrap();
+        }
+
+        let lua = Lua::new();
+        init_json_methods(&lua).unwrap();
+        init_storage_methods(&lua, &options).unwrap();
+        let result: String = lua
+            .load(r#"return storage.get("visits") .. storage.get("selection").city .. table.concat(storage.keys(), ",")"#)
+            .eval()
+            .unwrap();
+        assert_eq!(result, "2Berlinselection,visits");
+        assert!(file.exists());
+        let files = FileStorage::new(dir.clone());
+        assert_ne!(files.path("Wetter/Berlin"), files.path("Wetter Berlin"));
+        let _ = fs::remove_dir_all(&dir);
+
+        // eigener Speicher, gleicher Namespace
+        let mut memory = MemoryStorage::default();
+        set_storage(&lua, Box::new(memory.clone())).unwrap();
+        lua.load(r#"storage.set("visits", 1)"#).exec().unwrap();
+        assert_eq!(memory.get("Wetter/Berlin", "visits").unwrap(), Some(JsonValue::from(1)));
+        assert_eq!(memory.keys("other").unwrap(), Vec::<String>::new());
+    }
+}
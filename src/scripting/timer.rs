@@ -1,20 +1,40 @@
-use std::time::Instant;
-use mlua::{AnyUserData,Lua, UserDataMethods};
+//! # Timer
+//!
+//! `create_timer()` misst Zeit für eigene Abfragen in `on:update`. Für geplante Aufrufe gibt es:
+//!
+//! ```lua
+//! local id = set_interval(refresh, 30)        -- alle 30 Sekunden
+//! set_timeout(function() hide("banner") end, 5)
+//! clear_timer(id)
+//! every("5m", reload_feed)                    -- um :00, :05, :10, … Uhr
+//! at("07:00", function() state.mode = "day" end)
+//! ```
+//!
+//! Fällige Jobs laufen im nächsten `run_frame`, nach `on:update`. Die Zeit liefert die `Clock`
+//! der Engine, Uhrzeiten gelten in deren lokaler Zeit – bei `SystemClock` die Zeitzone des
+//! Systems samt Sommerzeit.
+
+use std::{rc::Rc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use mlua::{AnyUserData, Function, Lua, UserDataMethods};
+
+use crate::clock::{Clock, SystemClock};
+
+use super::sandbox::limited;
 
 #[derive(Clone)]
 pub struct TimerContext {
-    pub timer: Instant,  
+    pub timer: Instant,
 }
 
 impl mlua::UserData for TimerContext {
     fn add_methods<'lua, M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("elapsed", |_, this, _: ()| {
-            let elapsed = this.timer.elapsed();
+        methods.add_method("elapsed", |lua, this, _: ()| {
+            let elapsed = clock(lua).instant().saturating_duration_since(this.timer);
             Ok(elapsed.as_secs_f64())
         });
 
-        methods.add_method_mut("reset", |_, this, _: ()| {
-            this.timer = Instant::now();
+        methods.add_method_mut("reset", |lua, this, _: ()| {
+            this.timer = clock(lua).instant();
             Ok(())
         });
 
@@ -23,13 +43,355 @@ impl mlua::UserData for TimerContext {
 
 pub fn create_instant_timer(lua: &Lua, _: ()) -> Result<AnyUserData, mlua::Error> {
     let timer = TimerContext {
-        timer: Instant::now(),
+        timer: clock(lua).instant(),
     };
     lua.create_userdata(timer)
 }
 
-pub fn init_timer_methods(lua: &Lua) -> Result<(), mlua::Error> {
+/// Die Uhr der Engine, als App-Data am Lua-State.
+pub fn clock(lua: &Lua) -> Rc<dyn Clock> {
+    match lua.app_data_ref::<Rc<dyn Clock>>() {
+        Some(clock) => clock.clone(),
+        None => Rc::new(SystemClock::default()),
+    }
+}
+
+
+
+/// Wiederholung eines Jobs.
+#[derive(Debug, Clone, Copy)]
+enum Repeat {
+    Once,
+    /// `set_interval`, gezählt ab dem Start.
+    Interval(Duration),
+    /// `every`, ausgerichtet an der lokalen Uhrzeit, `5m` läuft um :00, :05, :10, …
+    Aligned(Duration),
+    /// `at`, Sekunden seit Mitternacht in lokaler Zeit.
+    Daily(u64),
+}
+
+/// Fälligkeit eines Jobs.
+#[derive(Debug, Clone, Copy)]
+enum Due {
+    /// `set_timeout` und `set_interval`, nach der monotonen Zeit.
+    After(Instant),
+    /// `every` und `at`, nach der Uhrzeit.
+    At(SystemTime),
+}
+
+impl Due {
+    /// Zeit bis zur Fälligkeit, 0 wenn der Job fällig ist.
+    fn remaining(self, instant: Instant, now: SystemTime) -> Duration {
+        match self {
+            Due::After(next) => next.saturating_duration_since(instant),
+            Due::At(next) => next.duration_since(now).unwrap_or_default(),
+        }
+    }
+}
+
+struct Job {
+    id: u64,
+    next: Due,
+    repeat: Repeat,
+    callback: Function,
+}
+
+/// Wird als `_scheduler` in den Lua-Globals abgelegt.
+#[derive(Default)]
+pub struct SchedulerContext {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+impl mlua::UserData for SchedulerContext {}
+
+impl SchedulerContext {
+    /// `delay` gilt nur für `Once` und `Interval`, die übrigen richten sich nach der Uhrzeit.
+    fn add(&mut self, clock: &dyn Clock, repeat: Repeat, delay: Duration, callback: Function) -> mlua::Result<u64> {
+        let next = match repeat {
+            Repeat::Once | Repeat::Interval(_) => clock
+                .instant()
+                .checked_add(delay)
+                .map(Due::After)
+                .ok_or_else(|| mlua::Error::runtime(format!("delay too large: {}s", delay.as_secs_f64())))?,
+            _ => Due::At(next_run(repeat, clock.now(), clock)),
+        };
+        self.next_id += 1;
+        self.jobs.push(Job { id: self.next_id, next, repeat, callback });
+        Ok(self.next_id)
+    }
+}
+
+fn with_scheduler<R>(lua: &Lua, f: impl FnOnce(&mut SchedulerContext) -> R) -> mlua::Result<R> {
+    let ctx_ud: AnyUserData = lua.globals().get("_scheduler")?;
+    let mut ctx = ctx_ud.borrow_mut::<SchedulerContext>()?;
+    Ok(f(&mut ctx))
+}
+
+/// Nächster Lauf eines an der Uhrzeit ausgerichteten Jobs, immer nach `now`.
+fn next_run(repeat: Repeat, now: SystemTime, clock: &dyn Clock) -> SystemTime {
+    const DAY: i64 = 86400;
+    let utc = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let utc_offset = clock.utc_offset(now);
+    let local = utc + utc_offset;
+    let midnight = local - local.rem_euclid(DAY);
+    let since_midnight = local - midnight;
+    let next = match repeat {
+        Repeat::Aligned(interval) => {
+            // länger als ein Tag läuft ohnehin täglich um Mitternacht
+            let period = i64::try_from(interval.as_secs()).unwrap_or(DAY).clamp(1, DAY);
+            // jeden Tag ab Mitternacht neu, auch wenn die Dauer den Tag nicht teilt
+            let next = since_midnight - since_midnight % period + period;
+            midnight + next.min(DAY)
+        }
+        Repeat::Daily(seconds) => {
+            let seconds = seconds as i64;
+            midnight + if seconds > since_midnight { seconds } else { seconds + DAY }
+        }
+        Repeat::Once | Repeat::Interval(_) => return now,
+    };
+    // liegt eine Zeitumstellung dazwischen, gilt der Abstand zu UTC am Ziel
+    let guess = UNIX_EPOCH + Duration::from_secs((next - utc_offset).max(0) as u64);
+    let next = next - clock.utc_offset(guess);
+    UNIX_EPOCH + Duration::from_secs(next.max(utc + 1).max(0) as u64)
+}
+
+/// Dauer wie `90`, `30s`, `5m`, `1h30m` oder `1d`, Zahlen ohne Einheit sind Sekunden.
+fn parse_duration(spec: &str) -> Option<Duration> {
+    let spec = spec.trim();
+    if let Ok(seconds) = spec.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok().filter(|duration| !duration.is_zero());
+    }
+    let mut total = 0.0;
+    let mut number = String::new();
+    for c in spec.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1.0,
+            'm' => 60.0,
+            'h' => 3600.0,
+            'd' => 86400.0,
+            _ => return None,
+        };
+        total += number.parse::<f64>().ok()? * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Duration::try_from_secs_f64(total).ok().filter(|duration| !duration.is_zero())
+}
+
+/// Uhrzeit `HH:MM` oder `HH:MM:SS` als Sekunden seit Mitternacht.
+fn parse_time_of_day(spec: &str) -> Option<u64> {
+    let parts = spec.trim().split(':').map(|part| part.parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?;
+    match parts.as_slice() {
+        [h, m] if *h < 24 && *m < 60 => Some(h * 3600 + m * 60),
+        [h, m, s] if *h < 24 && *m < 60 && *s < 60 => Some(h * 3600 + m * 60 + s),
+        _ => None,
+    }
+}
+
+fn delay_seconds(seconds: f64) -> mlua::Result<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| mlua::Error::runtime(format!("invalid delay: {}", seconds)))
+}
+
+/// `set_timeout(fn, seconds)`, ruft `fn` einmal nach `seconds` Sekunden auf.
+fn set_timeout(lua: &Lua, (callback, seconds): (Function, f64)) -> mlua::Result<u64> {
+    let delay = delay_seconds(seconds)?;
+    let clock = clock(lua);
+    with_scheduler(lua, |ctx| ctx.add(clock.as_ref(), Repeat::Once, delay, callback))?
+}
+
+/// `set_interval(fn, seconds)`, ruft `fn` alle `seconds` Sekunden auf.
+fn set_interval(lua: &Lua, (callback, seconds): (Function, f64)) -> mlua::Result<u64> {
+    let interval = delay_seconds(seconds)?;
+    if interval.is_zero() {
+        return Err(mlua::Error::runtime("interval must be greater than 0"));
+    }
+    let clock = clock(lua);
+    with_scheduler(lua, |ctx| ctx.add(clock.as_ref(), Repeat::Interval(interval), interval, callback))?
+}
+
+/// `every("5m", fn)`, läuft zu jedem Vielfachen der Dauer seit Mitternacht.
+fn every(lua: &Lua, (spec, callback): (String, Function)) -> mlua::Result<u64> {
+    let interval = parse_duration(&spec).ok_or_else(|| mlua::Error::runtime(format!("invalid duration: {}", spec)))?;
+    let clock = clock(lua);
+    with_scheduler(lua, |ctx| ctx.add(clock.as_ref(), Repeat::Aligned(interval), Duration::ZERO, callback))?
+}
+
+/// `at("07:00", fn)`, läuft täglich zur angegebenen lokalen Uhrzeit.
+fn at(lua: &Lua, (spec, callback): (String, Function)) -> mlua::Result<u64> {
+    let seconds = parse_time_of_day(&spec).ok_or_else(|| mlua::Error::runtime(format!("invalid time: {}", spec)))?;
+    let clock = clock(lua);
+    with_scheduler(lua, |ctx| ctx.add(clock.as_ref(), Repeat::Daily(seconds), Duration::ZERO, callback))?
+}
+
+/// `clear_timer(id)`, liefert `false`, wenn es den Timer nicht (mehr) gibt.
+fn clear_timer(lua: &Lua, id: u64) -> mlua::Result<bool> {
+    with_scheduler(lua, |ctx| {
+        let before = ctx.jobs.len();
+        ctx.jobs.retain(|job| job.id != id);
+        ctx.jobs.len() != before
+    })
+}
+
+/// Ruft alle fälligen Jobs auf, in der Reihenfolge ihrer Fälligkeit. Ein Job läuft höchstens
+/// einmal je Aufruf, verpasste Intervalle werden nicht nachgeholt.
+/// Schlägt ein Job fehl, laufen die übrigen trotzdem, gemeldet wird der erste Fehler.
+pub fn run_timers(lua: &Lua) -> mlua::Result<()> {
+    let clock = clock(lua);
+    let due: Vec<Function> = with_scheduler(lua, |ctx| {
+        let now = clock.now();
+        let instant = clock.instant();
+        ctx.jobs.sort_by_key(|job| (job.next.remaining(instant, now), job.id));
+        let mut due = Vec::new();
+        ctx.jobs.retain_mut(|job| {
+            if !job.next.remaining(instant, now).is_zero() {
+                return true;
+            }
+            due.push(job.callback.clone());
+            match (job.repeat, job.next) {
+                (Repeat::Once, _) => return false,
+                (Repeat::Interval(interval), Due::After(next)) => {
+                    let next = next
+                        .checked_add(interval)
+                        .filter(|next| *next > instant)
+                        .or_else(|| instant.checked_add(interval));
+                    match next {
+                        Some(next) => job.next = Due::After(next),
+                        // kein weiterer Lauf mehr darstellbar
+                        None => return false,
+                    }
+                }
+                _ => job.next = Due::At(next_run(job.repeat, now, clock.as_ref())),
+            }
+            true
+        });
+        due
+    })?;
+
+    let mut first_error = None;
+    for callback in due {
+        if let Err(e) = limited(lua, || callback.call::<()>(())) {
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub fn init_timer_methods(lua: &Lua, clock: Rc<dyn Clock>) -> Result<(), mlua::Error> {
+    lua.set_app_data(clock);
     let globals = lua.globals();
     globals.set("create_timer", lua.create_function(create_instant_timer)?)?;
+    globals.set("_scheduler", lua.create_userdata(SchedulerContext::default())?)?;
+    globals.set("set_timeout", lua.create_function(set_timeout)?)?;
+    globals.set("set_interval", lua.create_function(set_interval)?)?;
+    globals.set("clear_timer", lua.create_function(clear_timer)?)?;
+    globals.set("every", lua.create_function(every)?)?;
+    globals.set("at", lua.create_function(at)?)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+
+    use super::*;
+
+    #[test]
+    fn test_timers_with_manual_clock() {
+        let lua = Lua::new();
+        // Montag, 7. Juni 2021, 06:58 Uhr lokal bei UTC+2
+        let clock = Rc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_623_041_880)).with_utc_offset(7200));
+        init_timer_methods(&lua, clock.clone()).unwrap();
+        lua.load(r#"
+            log = {}
+            stopwatch = create_timer()
+            set_timeout(function() table.insert(log, "timeout") end, 1.5)
+            local id = set_interval(function() table.insert(log, "interval") end, 1)
+            every("2m", function() table.insert(log, "every") end)
+            at("07:00", function() table.insert(log, "at") end)
+            cancelled = set_timeout(function() table.insert(log, "cancelled") end, 1)
+            stop = function() clear_timer(id) end
+        "#).exec().unwrap();
+        let log = || -> Vec<String> {
+            let entries: Vec<String> = lua.load("return table.concat(log, ',')").eval::<String>().unwrap()
+                .split(',').filter(|s| !s.is_empty()).map(String::from).collect();
+            lua.load("log = {}").exec().unwrap();
+            entries
+        };
+
+        run_timers(&lua).unwrap();
+        assert!(log().is_empty());
+        assert!(lua.load("return clear_timer(cancelled)").eval::<bool>().unwrap());
+
+        clock.advance(Duration::from_secs(1));
+        run_timers(&lua).unwrap();
+        assert_eq!(log(), vec!["interval"]);
+        assert_eq!(lua.load("return stopwatch:elapsed()").eval::<f64>().unwrap(), 1.0);
+
+        // verpasste Intervalle laufen nur einmal
+        clock.advance(Duration::from_secs(10));
+        run_timers(&lua).unwrap();
+        assert_eq!(log(), vec!["timeout", "interval"]);
+
+        lua.load("stop()").exec().unwrap();
+        clock.advance(Duration::from_secs(109));
+        run_timers(&lua).unwrap();
+        assert_eq!(log(), vec!["every", "at"]);
+
+        clock.advance(Duration::from_secs(120));
+        run_timers(&lua).unwrap();
+        assert_eq!(log(), vec!["every"]);
+    }
+
+    #[test]
+    fn test_parse_schedules() {
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5x"), None);
+        assert_eq!(parse_duration("5m3"), None);
+        assert_eq!(parse_time_of_day("07:00"), Some(25200));
+        assert_eq!(parse_time_of_day("24:00"), None);
+        assert_eq!(parse_duration("1e30"), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+
+        let lua = Lua::new();
+        init_timer_methods(&lua, Rc::new(ManualClock::new(UNIX_EPOCH))).unwrap();
+        for call in ["set_timeout(print, 1e30)", "set_interval(print, 1e30)", "every('1e30', print)", "set_timeout(print, 1e19)", "set_timeout(print, 0 / 0)"] {
+            assert!(lua.load(call).exec().is_err(), "{}", call);
+        }
+    }
+
+    /// MEZ, ab 28. März 2021 01:00 UTC MESZ.
+    struct BerlinClock;
+
+    impl Clock for BerlinClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH
+        }
+
+        fn instant(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn utc_offset(&self, at: SystemTime) -> i64 {
+            if at < UNIX_EPOCH + Duration::from_secs(1_616_893_200) { 3600 } else { 7200 }
+        }
+    }
+
+    #[test]
+    fn test_daily_job_follows_dst() {
+        // Samstag, 27. März 2021, 13:00 Uhr MEZ
+        let now = UNIX_EPOCH + Duration::from_secs(1_616_846_400);
+        let next = next_run(Repeat::Daily(7 * 3600), now, &BerlinClock);
+        // Sonntag 07:00 Uhr MESZ
+        assert_eq!(next, UNIX_EPOCH + Duration::from_secs(1_616_907_600));
+    }
+}
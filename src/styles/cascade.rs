@@ -0,0 +1,412 @@
+//! # Kaskade
+//!
+//! Berechnet den finalen `Style` jedes Elements aus den passenden Stylesheet-Regeln,
+//! dem Inline-Style und den vererbten Werten des Elternelements.
+//! Text-Knoten übernehmen den berechneten Style ihres Elternelements.
+//!
+//! Custom Properties (`--name`) werden immer vererbt und in Werten über
+//! `var(--name, fallback)` aufgelöst, bevor die Deklarationen geparst werden.
+//!
+//! `::before` / `::after` Regeln mit einer `content` Deklaration landen als
+//! `GeneratedContent` im Style des Elements; das Layout erzeugt daraus eigene Text-Knoten.
+
+use std::collections::{HashMap, HashSet};
+
+use ulid::Ulid;
+
+use crate::{document::ElementState, vdom::{ElementNode, VNode}};
+
+use super::{selector::{element_count, ElementPath, PseudoElement}, stylesheet::StyleSheet, GeneratedContent, Style};
+
+pub struct CascadeContext<'a> {
+    pub stylesheet: &'a StyleSheet,
+    /// Ergebnis von `StyleSheet::evaluate_media` für die aktuelle Viewport-Größe.
+    pub media_state: &'a [bool],
+    /// Globale Custom Properties, gelten wie Inline-Deklarationen am Root-Element.
+    pub variables: &'a HashMap<String, String>,
+    /// Zustands-Flags für `:hover`, `:active` und `:focus`.
+    pub states: &'a HashMap<Ulid, ElementState>,
+}
+
+/// Maximale Verschachtelungstiefe von `var()`, schützt vor zyklischen Definitionen.
+const MAX_VAR_DEPTH: usize = 16;
+
+/// Sucht die zur öffnenden Klammer passende schließende Klammer ab `start` (hinter der Klammer).
+fn find_closing_paren(input: &str, start: usize) -> Option<usize> {
+    let mut depth = 1;
+    for (i, c) in input[start..].char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(start + i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Trennt `--name, fallback` am ersten Komma außerhalb von Klammern.
+fn split_var_arguments(args: &str) -> (&str, Option<&str>) {
+    let mut depth = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => return (args[..i].trim(), Some(args[i + 1..].trim())),
+            _ => {}
+        }
+    }
+    (args.trim(), None)
+}
+
+fn resolve_with_depth(value: &str, variables: &HashMap<String, String>, depth: usize) -> Option<String> {
+    if depth > MAX_VAR_DEPTH {
+        return None;
+    }
+
+    let mut output = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("var(") {
+        output.push_str(&rest[..start]);
+        let args_start = start + 4;
+        let end = find_closing_paren(rest, args_start)?;
+        let (name, fallback) = split_var_arguments(&rest[args_start..end]);
+
+        let replacement = variables
+            .get(name)
+            .and_then(|v| resolve_with_depth(v, variables, depth + 1))
+            .or_else(|| fallback.and_then(|f| resolve_with_depth(f, variables, depth + 1)))?;
+        output.push_str(&replacement);
+
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Some(output)
+}
+
+/// Ersetzt alle `var(--name, fallback)` Ausdrücke in `value`.
+///
+/// Gibt `None` zurück, wenn eine Variable weder definiert ist noch einen Fallback hat;
+/// die Deklaration ist dann ungültig und wird ignoriert.
+pub fn resolve_vars(value: &str, variables: &HashMap<String, String>) -> Option<String> {
+    resolve_with_depth(value, variables, 0)
+}
+
+/// Normalisiert den Namen einer Custom Property, `accent` und `--accent` sind gleichwertig.
+pub fn custom_property_name(name: &str) -> String {
+    let name = name.trim();
+    if name.starts_with("--") { name.to_string() } else { format!("--{}", name) }
+}
+
+/// Liest einen String in Anführungszeichen inklusive CSS-Escapes (`\"`, `\f101`).
+fn parse_quoted(chars: &mut std::iter::Peekable<std::str::Chars>, quote: char, output: &mut String) {
+    while let Some(c) = chars.next() {
+        match c {
+            c if c == quote => return,
+            '\\' => {
+                let mut hex = String::new();
+                while hex.len() < 6 {
+                    match chars.peek() {
+                        Some(h) if h.is_ascii_hexdigit() => hex.push(*h),
+                        _ => break,
+                    }
+                    chars.next();
+                }
+                if hex.is_empty() {
+                    if let Some(escaped) = chars.next() {
+                        output.push(escaped);
+                    }
+                } else {
+                    // ein Leerzeichen nach dem Escape gehört zum Escape
+                    if chars.peek() == Some(&' ') {
+                        chars.next();
+                    }
+                    let code = u32::from_str_radix(&hex, 16).unwrap_or(0xFFFD);
+                    output.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                }
+            }
+            c => output.push(c),
+        }
+    }
+}
+
+/// Wertet eine `content` Deklaration aus. Unterstützt Strings und `attr(name)`,
+/// andere Funktionen wie `counter()` werden übersprungen.
+/// `none` und `normal` erzeugen keinen Inhalt.
+pub fn parse_content(value: &str, element: &ElementNode) -> Option<String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("none") || value.eq_ignore_ascii_case("normal") {
+        return None;
+    }
+
+    let mut output = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '"' | '\'' => {
+                chars.next();
+                parse_quoted(&mut chars, c, &mut output);
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut token = String::new();
+                let mut depth = 0;
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        c if c.is_whitespace() && depth == 0 => break,
+                        _ => {}
+                    }
+                    token.push(c);
+                    chars.next();
+                    if depth == 0 && c == ')' {
+                        break;
+                    }
+                }
+
+                if let Some(name) = token.strip_prefix("attr(").and_then(|t| t.strip_suffix(')')) {
+                    let name = name.trim();
+                    let attr = if name == "id" {
+                        element.id.clone()
+                    } else {
+                        element.attrs.get(name).cloned()
+                    };
+                    output.push_str(&attr.unwrap_or_default());
+                }
+            }
+        }
+    }
+    Some(output)
+}
+
+/// Ersetzt `var()` in allen Deklarationen, ungültige Deklarationen fallen weg.
+fn resolve_declarations(
+    declarations: HashMap<String, String>,
+    custom_properties: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    declarations
+        .into_iter()
+        .filter_map(|(key, value)| {
+            if value.contains("var(") {
+                resolve_vars(&value, custom_properties).map(|v| (key, v))
+            } else {
+                Some((key, value))
+            }
+        })
+        .collect()
+}
+
+/// Berechnet den generierten Inhalt eines Pseudo-Elements. Ohne gültiges `content`
+/// entsteht wie im Browser kein Pseudo-Element.
+fn compute_generated_content(
+    path: &ElementPath,
+    element_style: &Style,
+    pseudo_element: PseudoElement,
+    ctx: &CascadeContext,
+) -> Option<GeneratedContent> {
+    let declarations = ctx.stylesheet.matching_pseudo_declarations(path, ctx.media_state, Some(pseudo_element));
+    if declarations.is_empty() {
+        return None;
+    }
+
+    let declarations = resolve_declarations(declarations, &element_style.custom_properties);
+    let text = parse_content(declarations.get("content")?, path.element)?;
+
+    let mut style = Style::from_hashmap(&declarations);
+    style.custom_properties = element_style.custom_properties.clone();
+    style.inherit_from(element_style);
+    Some(GeneratedContent { text, style })
+}
+
+/// Berechnet den Style eines einzelnen Elements.
+pub fn compute_style(path: &ElementPath, parent_style: Option<&Style>, ctx: &CascadeContext) -> Style {
+    let mut declarations = ctx.stylesheet.matching_declarations(path, ctx.media_state);
+    declarations.extend(path.element.inline_style.iter().map(|(k, v)| (k.clone(), v.clone())));
+    if path.parent.is_none() {
+        declarations.extend(ctx.variables.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    let (own_properties, declarations): (HashMap<String, String>, HashMap<String, String>) =
+        declarations.into_iter().partition(|(key, _)| key.starts_with("--"));
+
+    let mut custom_properties = parent_style
+        .map(|p| p.custom_properties.clone())
+        .unwrap_or_default();
+    custom_properties.extend(own_properties);
+
+    let custom_properties: HashMap<String, String> = custom_properties
+        .iter()
+        .filter_map(|(key, value)| resolve_vars(value, &custom_properties).map(|v| (key.clone(), v)))
+        .collect();
+
+    let declarations = resolve_declarations(declarations, &custom_properties);
+
+    let mut style = Style::from_hashmap(&declarations);
+    style.custom_properties = custom_properties;
+    if let Some(parent) = parent_style {
+        style.inherit_from(parent);
+    }
+    style.before = compute_generated_content(path, &style, PseudoElement::Before, ctx).map(Box::new);
+    style.after = compute_generated_content(path, &style, PseudoElement::After, ctx).map(Box::new);
+    style
+}
+
+/// Legt fest, welche Teile des Baums neu gestylt werden.
+pub enum RestyleScope {
+    All,
+    /// Nur die Teilbäume unter `roots`. `ancestors` enthält die Vorfahren der Roots,
+    /// über die der Baum zu ihnen durchlaufen werden muss.
+    Subtrees {
+        roots: HashSet<Ulid>,
+        ancestors: HashSet<Ulid>,
+    },
+}
+
+impl RestyleScope {
+    pub fn subtrees(root: &VNode, roots: HashSet<Ulid>) -> Self {
+        let ancestors = roots
+            .iter()
+            .filter_map(|id| root.ancestors_of(id))
+            .flatten()
+            .collect();
+        RestyleScope::Subtrees { roots, ancestors }
+    }
+
+    fn is_root(&self, id: &Ulid) -> bool {
+        match self {
+            RestyleScope::All => true,
+            RestyleScope::Subtrees { roots, .. } => roots.contains(id),
+        }
+    }
+
+    fn is_ancestor(&self, id: &Ulid) -> bool {
+        match self {
+            RestyleScope::All => true,
+            RestyleScope::Subtrees { ancestors, .. } => ancestors.contains(id),
+        }
+    }
+}
+
+fn restyle_element(
+    node: &mut VNode,
+    parent: Option<&ElementPath>,
+    parent_style: Option<&Style>,
+    position: (usize, usize),
+    ctx: &CascadeContext,
+    scope: &RestyleScope,
+    recompute: bool,
+) {
+    let VNode::Element(el) = node else {
+        return;
+    };
+
+    let recompute = recompute || scope.is_root(&el.internal_id);
+    if !recompute && !scope.is_ancestor(&el.internal_id) {
+        return;
+    }
+
+    // Kopie für die Selektoren, die Kinder werden dank im::Vector nur geteilt
+    let snapshot = el.clone();
+    let state = ctx.states.get(&el.internal_id).copied().unwrap_or_default();
+    let path = ElementPath::new(&snapshot, parent)
+        .with_position(position.0, position.1)
+        .with_state(state);
+
+    if recompute {
+        let mut style = compute_style(&path, parent_style, ctx);
+        // Animierte Werte gehen vor und werden vor den Kindern gesetzt, damit sie vererbt werden
+        style.overlay(&el.animated);
+        if el.style != style {
+            el.style = style;
+        }
+    }
+
+    let style = el.style.clone();
+    let count = element_count(&el.children);
+    let mut index = 0;
+    for child in el.children.iter_mut() {
+        match child {
+            VNode::Element(_) => {
+                restyle_element(child, Some(&path), Some(&style), (index, count), ctx, scope, recompute);
+                index += 1;
+            }
+            VNode::Text(text) => {
+                if recompute && text.style != style {
+                    text.style = style.clone();
+                }
+            }
+        }
+    }
+}
+
+/// Berechnet die Styles des Baums im angegebenen Umfang neu.
+pub fn restyle(root: &mut VNode, ctx: &CascadeContext, scope: &RestyleScope) {
+    restyle_element(root, None, None, (0, 1), ctx, scope, false);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_vars() {
+        let mut vars = HashMap::new();
+        vars.insert("--accent".to_string(), "#ff0000ff".to_string());
+        vars.insert("--size".to_string(), "var(--base)".to_string());
+        vars.insert("--base".to_string(), "12px".to_string());
+
+        assert_eq!(resolve_vars("var(--accent)", &vars).as_deref(), Some("#ff0000ff"));
+        assert_eq!(resolve_vars("var( --size ) 4px", &vars).as_deref(), Some("12px 4px"));
+        assert_eq!(resolve_vars("var(--missing, var(--base, 1px))", &vars).as_deref(), Some("12px"));
+        assert_eq!(resolve_vars("var(--missing)", &vars), None);
+    }
+
+    #[test]
+    fn test_resolve_cyclic_vars() {
+        let mut vars = HashMap::new();
+        vars.insert("--a".to_string(), "var(--b)".to_string());
+        vars.insert("--b".to_string(), "var(--a)".to_string());
+
+        assert_eq!(resolve_vars("var(--a)", &vars), None);
+        assert_eq!(resolve_vars("var(--a, 3px)", &vars).as_deref(), Some("3px"));
+    }
+
+    #[test]
+    fn test_parse_content() {
+        let mut attrs = HashMap::new();
+        attrs.insert("data-unit".to_string(), "°C".to_string());
+        let element = ElementNode {
+            internal_id: Ulid::new(),
+            id: Some("temp".to_string()),
+            tag: "span".to_string(),
+            attrs,
+            inline_style: HashMap::new(),
+            static_style: HashMap::new(),
+            bindings: HashMap::new(),
+            scope: Default::default(),
+            style: Style::default(),
+            animated: Style::default(),
+            children: im::Vector::new(),
+        };
+
+        assert_eq!(parse_content("\"» \"", &element).as_deref(), Some("» "));
+        assert_eq!(parse_content("' ' attr(data-unit)", &element).as_deref(), Some(" °C"));
+        assert_eq!(parse_content("attr(id) \":\"", &element).as_deref(), Some("temp:"));
+        assert_eq!(parse_content("\"\\2022 x\"", &element).as_deref(), Some("•x"));
+        assert_eq!(parse_content("attr(missing) counter(item)", &element).as_deref(), Some(""));
+        assert_eq!(parse_content("none", &element), None);
+    }
+
+    #[test]
+    fn test_custom_property_name() {
+        assert_eq!(custom_property_name("accent"), "--accent");
+        assert_eq!(custom_property_name("--accent"), "--accent");
+    }
+}
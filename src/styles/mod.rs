@@ -1,4 +1,7 @@
 mod taffy;
+pub mod selector;
+pub mod stylesheet;
+pub mod cascade;
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::num::ParseFloatError;
@@ -23,9 +26,22 @@ pub struct Style {
     pub gap: Option<Dimension>,
     pub align_items: Option<AlignItems>,
     pub justify_content: Option<AlignContent>,
+    pub opacity: Option<f32>,
+    /// Berechnete Custom Properties (`--name`), inklusive der vom Elternelement geerbten.
+    pub custom_properties: HashMap<String, String>,
+    /// Generierter Inhalt aus `::before` bzw. `::after` Regeln.
+    pub before: Option<Box<GeneratedContent>>,
+    pub after: Option<Box<GeneratedContent>>,
     // Weitere Eigenschaften können hier ergänzt werden.
 }
 
+/// Inhalt eines Pseudo-Elements: der aufgelöste `content` Text und sein eigener Style.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedContent {
+    pub text: String,
+    pub style: Style,
+}
+
 /// Repräsentiert Werte für margin oder padding. Unterstützt CSS-Shorthand (1 bis 4 Werte).
 #[derive(Debug, Clone, PartialEq)]
 pub struct EdgeValues {
@@ -322,6 +338,10 @@ impl Style {
 
         let gap = map.get("gap")
             .and_then(|s| s.parse::<Dimension>().ok());
+
+        let opacity = map.get("opacity")
+            .and_then(|s| s.trim().parse::<f32>().ok())
+            .map(|o| o.clamp(0.0, 1.0));
         
         Self {
             margin,
@@ -336,6 +356,10 @@ impl Style {
             gap,
             justify_content,
             align_items,
+            opacity,
+            custom_properties: HashMap::new(),
+            before: None,
+            after: None,
         }
     }
 
@@ -343,6 +367,39 @@ impl Style {
         let parsed_styles = parse_styles(&value);
         Self::from_hashmap(&parsed_styles)
     }
+
+    /// Übernimmt die vererbbaren Eigenschaften (`color`, `font-size`) vom Elternelement,
+    /// sofern sie nicht selbst gesetzt sind.
+    pub fn inherit_from(&mut self, parent: &Style) {
+        if self.color.is_none() {
+            self.color = parent.color;
+        }
+        if self.font_size.is_none() {
+            self.font_size = parent.font_size;
+        }
+    }
+
+    /// Überschreibt alle Eigenschaften, die in `layer` gesetzt sind.
+    pub fn overlay(&mut self, layer: &Style) {
+        fn set<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                target.clone_from(value);
+            }
+        }
+        set(&mut self.margin, &layer.margin);
+        set(&mut self.padding, &layer.padding);
+        set(&mut self.width, &layer.width);
+        set(&mut self.height, &layer.height);
+        set(&mut self.display, &layer.display);
+        set(&mut self.flex_direction, &layer.flex_direction);
+        set(&mut self.font_size, &layer.font_size);
+        set(&mut self.background_color, &layer.background_color);
+        set(&mut self.color, &layer.color);
+        set(&mut self.gap, &layer.gap);
+        set(&mut self.align_items, &layer.align_items);
+        set(&mut self.justify_content, &layer.justify_content);
+        set(&mut self.opacity, &layer.opacity);
+    }
 }
 
 
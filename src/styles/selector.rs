@@ -0,0 +1,514 @@
+//! # Selektoren
+//!
+//! Ein kleiner CSS-Selektor-Parser und -Matcher für den VDOM.
+//! Unterstützt werden Typ-, ID-, Klassen- und Attribut-Selektoren (`div.card#main[lang=de]`),
+//! der Universal-Selektor `*` sowie die Kombinatoren Nachfahre (` `) und Kind (`>`).
+//!
+//! Pseudoklassen: strukturell `:first-child`, `:last-child`, `:nth-child(an+b)`, `:empty`
+//! und zustandsbasiert `:hover`, `:active`, `:focus` (siehe `ElementState` im VDOM).
+//! Am Ende eines Selektors darf ein Pseudo-Element `::before` / `::after` stehen.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::{document::{ElementState, StateFlag}, vdom::{ElementNode, VNode}};
+
+/// Spezifität eines Selektors als (IDs, Klassen/Attribute, Typen).
+pub type Specificity = (u32, u32, u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Combinator {
+    /// `a b` – b ist ein beliebiger Nachfahre von a
+    Descendant,
+    /// `a > b` – b ist ein direktes Kind von a
+    Child,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeSelector {
+    Exists(String),
+    Equals(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PseudoClass {
+    FirstChild,
+    LastChild,
+    /// `:nth-child(an+b)`, gespeichert als (a, b)
+    NthChild(i32, i32),
+    Empty,
+    State(StateFlag),
+}
+
+impl PseudoClass {
+    fn parse(name: &str, argument: Option<&str>) -> Option<Self> {
+        match (name.to_lowercase().as_str(), argument) {
+            ("first-child", None) => Some(PseudoClass::FirstChild),
+            ("last-child", None) => Some(PseudoClass::LastChild),
+            ("empty", None) => Some(PseudoClass::Empty),
+            ("nth-child", Some(arg)) => parse_nth(arg).map(|(a, b)| PseudoClass::NthChild(a, b)),
+            (name, None) => StateFlag::from_str(name).map(PseudoClass::State),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, path: &ElementPath) -> bool {
+        match self {
+            PseudoClass::FirstChild => path.index == 0,
+            PseudoClass::LastChild => path.index + 1 == path.count,
+            PseudoClass::NthChild(a, b) => {
+                let position = path.index as i32 + 1;
+                if *a == 0 {
+                    position == *b
+                } else {
+                    let offset = position - b;
+                    offset % a == 0 && offset / a >= 0
+                }
+            }
+            PseudoClass::Empty => path.element.children.is_empty(),
+            PseudoClass::State(flag) => path.state.get(*flag),
+        }
+    }
+}
+
+/// Parst das Argument von `:nth-child`, z. B. `odd`, `even`, `3`, `2n+1`, `-n+3`.
+fn parse_nth(input: &str) -> Option<(i32, i32)> {
+    let input: String = input.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+    match input.as_str() {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+
+    match input.split_once('n') {
+        Some((a, b)) => {
+            let a = match a {
+                "" | "+" => 1,
+                "-" => -1,
+                a => a.parse().ok()?,
+            };
+            let b = if b.is_empty() { 0 } else { b.parse().ok()? };
+            Some((a, b))
+        }
+        None => input.parse().ok().map(|b| (0, b)),
+    }
+}
+
+/// Pseudo-Elemente für generierten Inhalt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PseudoElement {
+    Before,
+    After,
+}
+
+impl PseudoElement {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "before" => Some(PseudoElement::Before),
+            "after" => Some(PseudoElement::After),
+            _ => None,
+        }
+    }
+}
+
+/// Ein zusammengesetzter Selektor ohne Kombinator, z. B. `div.card#main:first-child`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Compound {
+    pub tag: Option<String>,
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub attributes: Vec<AttributeSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
+}
+
+/// Ein vollständiger Selektor. `combinators[i]` verbindet `compounds[i]` mit `compounds[i + 1]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub compounds: Vec<Compound>,
+    pub combinators: Vec<Combinator>,
+    /// Gesetzt, wenn der Selektor ein `::before` / `::after` des Elements beschreibt.
+    pub pseudo_element: Option<PseudoElement>,
+}
+
+/// Position eines Elements im Baum, wird beim Durchlaufen des VDOM auf dem Stack aufgebaut.
+/// Über `parent` können Kombinatoren die Vorfahren prüfen.
+pub struct ElementPath<'a> {
+    pub element: &'a ElementNode,
+    pub parent: Option<&'a ElementPath<'a>>,
+    /// Position unter den Element-Geschwistern (0-basiert).
+    pub index: usize,
+    /// Anzahl der Element-Geschwister inklusive des Elements selbst.
+    pub count: usize,
+    pub state: ElementState,
+}
+
+impl<'a> ElementPath<'a> {
+    pub fn new(element: &'a ElementNode, parent: Option<&'a ElementPath<'a>>) -> Self {
+        Self { element, parent, index: 0, count: 1, state: ElementState::default() }
+    }
+
+    pub fn with_position(mut self, index: usize, count: usize) -> Self {
+        self.index = index;
+        self.count = count;
+        self
+    }
+
+    pub fn with_state(mut self, state: ElementState) -> Self {
+        self.state = state;
+        self
+    }
+}
+
+/// Zählt die Element-Kinder, Text-Knoten zählen für `:nth-child` nicht mit.
+pub fn element_count(children: &im::Vector<VNode>) -> usize {
+    children.iter().filter(|c| matches!(c, VNode::Element(_))).count()
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+fn parse_ident(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut ident = String::new();
+    while let Some(&c) = chars.peek() {
+        if is_ident_char(c) {
+            ident.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    if ident.is_empty() { None } else { Some(ident) }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) -> bool {
+    let mut skipped = false;
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+        skipped = true;
+    }
+    skipped
+}
+
+fn parse_attribute(chars: &mut Peekable<Chars>) -> Option<AttributeSelector> {
+    let mut content = String::new();
+    loop {
+        match chars.next()? {
+            ']' => break,
+            c => content.push(c),
+        }
+    }
+
+    match content.split_once('=') {
+        Some((name, value)) => {
+            let name = name.trim();
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            if name.is_empty() || !name.chars().all(is_ident_char) {
+                return None;
+            }
+            Some(AttributeSelector::Equals(name.to_string(), value.to_string()))
+        }
+        None => {
+            let name = content.trim();
+            if name.is_empty() || !name.chars().all(is_ident_char) {
+                return None;
+            }
+            Some(AttributeSelector::Exists(name.to_string()))
+        }
+    }
+}
+
+fn parse_compound(chars: &mut Peekable<Chars>, pseudo_element: &mut Option<PseudoElement>) -> Option<Compound> {
+    let mut compound = Compound::default();
+    let mut empty = true;
+
+    while let Some(&c) = chars.peek() {
+        if pseudo_element.is_some() {
+            // nach einem Pseudo-Element darf nichts mehr folgen
+            return None;
+        }
+
+        match c {
+            '*' if empty => {
+                chars.next();
+            }
+            '#' => {
+                chars.next();
+                compound.id = Some(parse_ident(chars)?);
+            }
+            '.' => {
+                chars.next();
+                compound.classes.push(parse_ident(chars)?);
+            }
+            '[' => {
+                chars.next();
+                compound.attributes.push(parse_attribute(chars)?);
+            }
+            ':' => {
+                chars.next();
+                if chars.peek() == Some(&':') {
+                    chars.next();
+                    *pseudo_element = Some(PseudoElement::from_name(&parse_ident(chars)?)?);
+                    empty = false;
+                    continue;
+                }
+                let name = parse_ident(chars)?;
+                // alte Schreibweise `:before` / `:after`
+                if let Some(pseudo) = PseudoElement::from_name(&name) {
+                    *pseudo_element = Some(pseudo);
+                    empty = false;
+                    continue;
+                }
+                let argument = if chars.peek() == Some(&'(') {
+                    chars.next();
+                    let mut argument = String::new();
+                    loop {
+                        match chars.next()? {
+                            ')' => break,
+                            c => argument.push(c),
+                        }
+                    }
+                    Some(argument)
+                } else {
+                    None
+                };
+                compound.pseudo_classes.push(PseudoClass::parse(&name, argument.as_deref())?);
+            }
+            c if is_ident_char(c) && empty => {
+                compound.tag = Some(parse_ident(chars)?.to_lowercase());
+            }
+            _ => break,
+        }
+        empty = false;
+    }
+
+    if empty { None } else { Some(compound) }
+}
+
+impl Selector {
+    /// Parst einen einzelnen Selektor, z. B. `#list > .card h2`.
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut chars = input.trim().chars().peekable();
+        let mut compounds = Vec::new();
+        let mut combinators = Vec::new();
+        let mut pseudo_element = None;
+
+        loop {
+            compounds.push(parse_compound(&mut chars, &mut pseudo_element)?);
+
+            let had_whitespace = skip_whitespace(&mut chars);
+            match chars.peek() {
+                None => break,
+                Some(_) if pseudo_element.is_some() => return None,
+                Some('>') => {
+                    chars.next();
+                    skip_whitespace(&mut chars);
+                    combinators.push(Combinator::Child);
+                }
+                Some(_) if had_whitespace => combinators.push(Combinator::Descendant),
+                Some(_) => return None,
+            }
+        }
+
+        Some(Self { compounds, combinators, pseudo_element })
+    }
+
+    /// Parst eine kommaseparierte Selektorliste. Ist ein Selektor ungültig, ist es die ganze Liste.
+    pub fn parse_list(input: &str) -> Option<Vec<Self>> {
+        input.split(',').map(Self::parse).collect()
+    }
+
+    pub fn specificity(&self) -> Specificity {
+        let initial = (0, 0, self.pseudo_element.is_some() as u32);
+        self.compounds.iter().fold(initial, |(a, b, c), compound| {
+            (
+                a + compound.id.is_some() as u32,
+                b + (compound.classes.len() + compound.attributes.len() + compound.pseudo_classes.len()) as u32,
+                c + compound.tag.is_some() as u32,
+            )
+        })
+    }
+
+    /// Prüft, ob der Selektor auf das Element am Ende von `path` passt.
+    pub fn matches(&self, path: &ElementPath) -> bool {
+        self.matches_at(self.compounds.len() - 1, path)
+    }
+
+    fn matches_at(&self, index: usize, path: &ElementPath) -> bool {
+        if !self.compounds[index].matches(path) {
+            return false;
+        }
+        if index == 0 {
+            return true;
+        }
+
+        match self.combinators[index - 1] {
+            Combinator::Child => path.parent.is_some_and(|parent| self.matches_at(index - 1, parent)),
+            Combinator::Descendant => {
+                let mut ancestor = path.parent;
+                while let Some(current) = ancestor {
+                    if self.matches_at(index - 1, current) {
+                        return true;
+                    }
+                    ancestor = current.parent;
+                }
+                false
+            }
+        }
+    }
+}
+
+impl Compound {
+    fn matches(&self, path: &ElementPath) -> bool {
+        let element = path.element;
+        if let Some(tag) = &self.tag {
+            if !tag.eq_ignore_ascii_case(&element.tag) {
+                return false;
+            }
+        }
+
+        if let Some(id) = &self.id {
+            if element.id.as_ref() != Some(id) {
+                return false;
+            }
+        }
+
+        if !self.classes.is_empty() {
+            let classes = element.attrs.get("class").map(|c| c.as_str()).unwrap_or("");
+            if !self.classes.iter().all(|class| classes.split_whitespace().any(|c| c == class)) {
+                return false;
+            }
+        }
+
+        let attributes_match = self.attributes.iter().all(|attr| match attr {
+            AttributeSelector::Exists(name) => element.attrs.contains_key(name) || (name == "id" && element.id.is_some()),
+            AttributeSelector::Equals(name, value) => {
+                if name == "id" {
+                    element.id.as_ref() == Some(value)
+                } else {
+                    element.attrs.get(name) == Some(value)
+                }
+            }
+        });
+
+        attributes_match && self.pseudo_classes.iter().all(|p| p.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use im::Vector;
+    use ulid::Ulid;
+
+    use super::*;
+    use crate::styles::Style;
+
+    fn element(tag: &str, id: Option<&str>, class: Option<&str>) -> ElementNode {
+        let mut attrs = HashMap::new();
+        if let Some(class) = class {
+            attrs.insert("class".to_string(), class.to_string());
+        }
+        ElementNode {
+            internal_id: Ulid::new(),
+            id: id.map(|s| s.to_string()),
+            tag: tag.to_string(),
+            attrs,
+            inline_style: HashMap::new(),
+            static_style: HashMap::new(),
+            bindings: HashMap::new(),
+            scope: Default::default(),
+            style: Style::default(),
+            animated: Style::default(),
+            children: Vector::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_compound_selector() {
+        let selector = Selector::parse("div#main.card.big[lang=\"de\"]").unwrap();
+        assert_eq!(selector.compounds.len(), 1);
+        let compound = &selector.compounds[0];
+        assert_eq!(compound.tag.as_deref(), Some("div"));
+        assert_eq!(compound.id.as_deref(), Some("main"));
+        assert_eq!(compound.classes, vec!["card".to_string(), "big".to_string()]);
+        assert_eq!(compound.attributes, vec![AttributeSelector::Equals("lang".into(), "de".into())]);
+        assert_eq!(selector.specificity(), (1, 3, 1));
+    }
+
+    #[test]
+    fn test_parse_invalid_selector() {
+        assert!(Selector::parse("").is_none());
+        assert!(Selector::parse("div >").is_none());
+        assert!(Selector::parse_list("div, ").is_none());
+    }
+
+    #[test]
+    fn test_match_combinators() {
+        let body = element("body", None, None);
+        let list = element("div", Some("list"), Some("cards"));
+        let card = element("div", None, Some("card warm"));
+
+        let body_path = ElementPath::new(&body, None);
+        let list_path = ElementPath::new(&list, Some(&body_path));
+        let card_path = ElementPath::new(&card, Some(&list_path));
+
+        assert!(Selector::parse(".card").unwrap().matches(&card_path));
+        assert!(Selector::parse("body .warm").unwrap().matches(&card_path));
+        assert!(Selector::parse("#list > .card.warm").unwrap().matches(&card_path));
+        assert!(!Selector::parse("body > .card").unwrap().matches(&card_path));
+        assert!(!Selector::parse(".cold").unwrap().matches(&card_path));
+    }
+
+    #[test]
+    fn test_parse_nth() {
+        assert_eq!(parse_nth("odd"), Some((2, 1)));
+        assert_eq!(parse_nth("even"), Some((2, 0)));
+        assert_eq!(parse_nth("3"), Some((0, 3)));
+        assert_eq!(parse_nth("2n + 1"), Some((2, 1)));
+        assert_eq!(parse_nth("-n+3"), Some((-1, 3)));
+        assert_eq!(parse_nth("n"), Some((1, 0)));
+        assert_eq!(parse_nth("foo"), None);
+    }
+
+    #[test]
+    fn test_match_pseudo_classes() {
+        let list = element("div", Some("list"), None);
+        let card = element("div", None, Some("card"));
+        let list_path = ElementPath::new(&list, None);
+
+        let first = ElementPath::new(&card, Some(&list_path)).with_position(0, 3);
+        let second = ElementPath::new(&card, Some(&list_path)).with_position(1, 3);
+        let last = ElementPath::new(&card, Some(&list_path)).with_position(2, 3);
+
+        let first_child = Selector::parse(".card:first-child").unwrap();
+        assert!(first_child.matches(&first) && !first_child.matches(&second));
+        assert!(Selector::parse(".card:last-child").unwrap().matches(&last));
+        let odd = Selector::parse(":nth-child(odd)").unwrap();
+        assert!(odd.matches(&first) && !odd.matches(&second) && odd.matches(&last));
+        let first_two = Selector::parse(":nth-child(-n+2)").unwrap();
+        assert!(first_two.matches(&second) && !first_two.matches(&last));
+        assert!(Selector::parse(".card:empty").unwrap().matches(&first));
+        assert_eq!(first_child.specificity(), (0, 2, 0));
+
+        let hovered = ElementPath::new(&card, Some(&list_path))
+            .with_state(ElementState { hover: true, ..Default::default() });
+        assert!(Selector::parse("#list .card:hover").unwrap().matches(&hovered));
+        assert!(!Selector::parse(".card:hover").unwrap().matches(&first));
+        assert!(Selector::parse(".card:unknown").is_none());
+    }
+
+    #[test]
+    fn test_parse_pseudo_elements() {
+        let selector = Selector::parse(".card h2::before").unwrap();
+        assert_eq!(selector.pseudo_element, Some(PseudoElement::Before));
+        assert_eq!(selector.specificity(), (0, 1, 2));
+
+        assert_eq!(Selector::parse("p:after").unwrap().pseudo_element, Some(PseudoElement::After));
+        assert_eq!(Selector::parse("p").unwrap().pseudo_element, None);
+        assert!(Selector::parse("p::before span").is_none());
+        assert!(Selector::parse("p::before.card").is_none());
+        assert!(Selector::parse("p::marker").is_none());
+    }
+}
@@ -0,0 +1,350 @@
+//! # Stylesheets
+//!
+//! Parst den Inhalt von `<style>` Blöcken in eine Liste von Regeln.
+//! `@media` Blöcke werden unterstützt, ihre Regeln gelten nur, solange die Media Query
+//! für die aktuelle Viewport-Größe zutrifft. Andere `@`-Regeln werden ignoriert.
+
+use std::collections::HashMap;
+
+use crate::parser::parse_styles;
+
+use super::selector::{ElementPath, PseudoElement, Selector, Specificity};
+
+/// Ein Feature innerhalb einer Media Query, z. B. `(min-width: 800px)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaFeature {
+    MinWidth(f32),
+    MaxWidth(f32),
+    MinHeight(f32),
+    MaxHeight(f32),
+    Portrait,
+    Landscape,
+    AspectRatio(f32),
+    MinAspectRatio(f32),
+    MaxAspectRatio(f32),
+}
+
+/// Eine einzelne Media Query, z. B. `screen and (orientation: portrait)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaQuery {
+    pub negated: bool,
+    /// `false` für Medientypen wie `print`, die auf einem Player nie zutreffen.
+    pub media_type_matches: bool,
+    pub features: Vec<MediaFeature>,
+}
+
+/// Kommaseparierte Liste von Media Queries, trifft zu sobald eine Query zutrifft.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaQueryList(pub Vec<MediaQuery>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub selectors: Vec<Selector>,
+    pub declarations: HashMap<String, String>,
+    /// Index in `StyleSheet::media`, falls die Regel in einem `@media` Block steht.
+    pub media: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StyleSheet {
+    pub rules: Vec<Rule>,
+    pub media: Vec<MediaQueryList>,
+}
+
+/// Parst eine Länge in px für Media Features (`800px`, `800`).
+fn parse_media_length(value: &str) -> Option<f32> {
+    value.trim().trim_end_matches("px").trim().parse::<f32>().ok()
+}
+
+/// Parst ein Seitenverhältnis (`16/9`, `1.5`).
+fn parse_ratio(value: &str) -> Option<f32> {
+    match value.split_once('/') {
+        Some((w, h)) => {
+            let w = w.trim().parse::<f32>().ok()?;
+            let h = h.trim().parse::<f32>().ok()?;
+            if h == 0.0 { None } else { Some(w / h) }
+        }
+        None => value.trim().parse::<f32>().ok(),
+    }
+}
+
+impl MediaFeature {
+    fn parse(input: &str) -> Option<Self> {
+        let (name, value) = input.split_once(':')?;
+        let value = value.trim();
+        match name.trim().to_lowercase().as_str() {
+            "min-width" => parse_media_length(value).map(MediaFeature::MinWidth),
+            "max-width" => parse_media_length(value).map(MediaFeature::MaxWidth),
+            "min-height" => parse_media_length(value).map(MediaFeature::MinHeight),
+            "max-height" => parse_media_length(value).map(MediaFeature::MaxHeight),
+            "orientation" => match value.to_lowercase().as_str() {
+                "portrait" => Some(MediaFeature::Portrait),
+                "landscape" => Some(MediaFeature::Landscape),
+                _ => None,
+            },
+            "aspect-ratio" => parse_ratio(value).map(MediaFeature::AspectRatio),
+            "min-aspect-ratio" => parse_ratio(value).map(MediaFeature::MinAspectRatio),
+            "max-aspect-ratio" => parse_ratio(value).map(MediaFeature::MaxAspectRatio),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, width: f32, height: f32) -> bool {
+        let ratio = if height > 0.0 { width / height } else { 0.0 };
+        match *self {
+            MediaFeature::MinWidth(v) => width >= v,
+            MediaFeature::MaxWidth(v) => width <= v,
+            MediaFeature::MinHeight(v) => height >= v,
+            MediaFeature::MaxHeight(v) => height <= v,
+            MediaFeature::Portrait => height >= width,
+            MediaFeature::Landscape => width > height,
+            MediaFeature::AspectRatio(v) => (ratio - v).abs() < 0.01,
+            MediaFeature::MinAspectRatio(v) => ratio >= v,
+            MediaFeature::MaxAspectRatio(v) => ratio <= v,
+        }
+    }
+}
+
+impl MediaQuery {
+    /// Parst eine Query wie `not screen and (min-width: 800px) and (orientation: landscape)`.
+    /// Unbekannte Features machen die Query ungültig, sie trifft dann nie zu.
+    fn parse(input: &str) -> Self {
+        let invalid = MediaQuery { negated: false, media_type_matches: false, features: Vec::new() };
+
+        let mut negated = false;
+        let mut media_type_matches = true;
+        let mut features = Vec::new();
+
+        let lowered = input.trim().to_lowercase();
+        for (i, part) in lowered.split(" and ").enumerate() {
+            let part = part.trim();
+            if let Some(inner) = part.strip_prefix('(').and_then(|p| p.strip_suffix(')')) {
+                match MediaFeature::parse(inner) {
+                    Some(feature) => features.push(feature),
+                    None => return invalid,
+                }
+            } else if i == 0 {
+                let mut words = part.split_whitespace();
+                let mut media_type = words.next().unwrap_or("all");
+                if media_type == "not" || media_type == "only" {
+                    negated = media_type == "not";
+                    media_type = words.next().unwrap_or("all");
+                }
+                media_type_matches = matches!(media_type, "all" | "screen");
+            } else {
+                return invalid;
+            }
+        }
+
+        MediaQuery { negated, media_type_matches, features }
+    }
+
+    fn matches(&self, width: f32, height: f32) -> bool {
+        let result = self.media_type_matches && self.features.iter().all(|f| f.matches(width, height));
+        result != self.negated
+    }
+}
+
+impl MediaQueryList {
+    pub fn parse(input: &str) -> Self {
+        MediaQueryList(input.split(',').map(MediaQuery::parse).collect())
+    }
+
+    /// Prüft die Query-Liste gegen die Viewport-Größe. Ohne Viewport trifft keine Query zu.
+    pub fn matches(&self, viewport: Option<(f32, f32)>) -> bool {
+        match viewport {
+            Some((width, height)) => self.0.iter().any(|q| q.matches(width, height)),
+            None => false,
+        }
+    }
+}
+
+/// Entfernt `/* ... */` Kommentare.
+fn strip_comments(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("/*") {
+        output.push_str(&rest[..start]);
+        match rest[start + 2..].find("*/") {
+            Some(end) => rest = &rest[start + 2 + end + 2..],
+            None => {
+                rest = "";
+                break;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Sucht die zur öffnenden Klammer an `open` passende schließende Klammer.
+fn find_block_end(input: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in input[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+impl StyleSheet {
+    pub fn parse(css: &str) -> Self {
+        let mut sheet = StyleSheet::default();
+        sheet.append(css);
+        sheet
+    }
+
+    /// Hängt die Regeln eines weiteren `<style>` Blocks an.
+    pub fn append(&mut self, css: &str) {
+        let css = strip_comments(css);
+        self.parse_block(&css, None);
+    }
+
+    fn parse_block(&mut self, css: &str, media: Option<usize>) {
+        let mut rest = css;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+
+            let Some(open) = rest.find('{') else {
+                break;
+            };
+            let prelude = rest[..open].trim();
+
+            // @-Regeln ohne Block, z. B. @import ...;
+            if prelude.starts_with('@') && prelude.contains(';') {
+                let end = rest.find(';').unwrap();
+                rest = &rest[end + 1..];
+                continue;
+            }
+
+            let Some(close) = find_block_end(rest, open) else {
+                break;
+            };
+            let body = &rest[open + 1..close];
+
+            if let Some(query) = prelude.strip_prefix("@media") {
+                // verschachtelte @media Blöcke werden nicht kombiniert, der innerste gilt
+                self.media.push(MediaQueryList::parse(query));
+                let index = self.media.len() - 1;
+                self.parse_block(body, Some(index));
+            } else if !prelude.starts_with('@') {
+                if let Some(selectors) = Selector::parse_list(prelude) {
+                    let declarations = parse_styles(body)
+                        .into_iter()
+                        .filter(|(key, _)| !key.is_empty())
+                        .map(|(key, value)| {
+                            let value = value.trim_end_matches("!important").trim().to_string();
+                            // Custom Properties sind case-sensitiv
+                            let key = if key.starts_with("--") { key } else { key.to_lowercase() };
+                            (key, value)
+                        })
+                        .collect();
+                    self.rules.push(Rule { selectors, declarations, media });
+                }
+            }
+
+            rest = &rest[close + 1..];
+        }
+    }
+
+    /// Liefert für jede Media-Query-Liste, ob sie für die Viewport-Größe zutrifft.
+    pub fn evaluate_media(&self, viewport: Option<(f32, f32)>) -> Vec<bool> {
+        self.media.iter().map(|m| m.matches(viewport)).collect()
+    }
+
+    /// Sammelt die Deklarationen aller passenden Regeln, sortiert nach Spezifität
+    /// und Reihenfolge im Stylesheet. Spätere Einträge überschreiben frühere.
+    pub fn matching_declarations(&self, path: &ElementPath, media_state: &[bool]) -> HashMap<String, String> {
+        self.matching_pseudo_declarations(path, media_state, None)
+    }
+
+    /// Wie `matching_declarations`, berücksichtigt aber nur Selektoren für das angegebene
+    /// Pseudo-Element (`None` für das Element selbst).
+    pub fn matching_pseudo_declarations(
+        &self,
+        path: &ElementPath,
+        media_state: &[bool],
+        pseudo_element: Option<PseudoElement>,
+    ) -> HashMap<String, String> {
+        let mut matched: Vec<(Specificity, usize, &Rule)> = Vec::new();
+
+        for (order, rule) in self.rules.iter().enumerate() {
+            if let Some(media) = rule.media {
+                if !media_state.get(media).copied().unwrap_or(false) {
+                    continue;
+                }
+            }
+
+            let specificity = rule.selectors
+                .iter()
+                .filter(|s| s.pseudo_element == pseudo_element && s.matches(path))
+                .map(|s| s.specificity())
+                .max();
+
+            if let Some(specificity) = specificity {
+                matched.push((specificity, order, rule));
+            }
+        }
+
+        matched.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        let mut declarations = HashMap::new();
+        for (_, _, rule) in matched {
+            declarations.extend(rule.declarations.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        declarations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules_and_media() {
+        let sheet = StyleSheet::parse(r#"
+            /* Karten */
+            .card { width: 200px; padding: 10px }
+            @media (min-width: 1000px) and (orientation: landscape) {
+                .card { width: 400px; }
+            }
+            @import url("foo.css");
+            h2, p { color: #ff0000ff !important; }
+        "#);
+
+        assert_eq!(sheet.rules.len(), 3);
+        assert_eq!(sheet.media.len(), 1);
+        assert_eq!(sheet.rules[1].media, Some(0));
+        assert_eq!(sheet.rules[2].selectors.len(), 2);
+        assert_eq!(sheet.rules[2].declarations.get("color").map(|s| s.as_str()), Some("#ff0000ff"));
+    }
+
+    #[test]
+    fn test_media_queries() {
+        let query = MediaQueryList::parse("(min-width: 1000px) and (orientation: landscape)");
+        assert!(query.matches(Some((1920.0, 1080.0))));
+        assert!(!query.matches(Some((1080.0, 1920.0))));
+        assert!(!query.matches(None));
+
+        let query = MediaQueryList::parse("(max-width: 800px), (orientation: portrait)");
+        assert!(query.matches(Some((640.0, 480.0))));
+        assert!(query.matches(Some((1080.0, 1920.0))));
+        assert!(!query.matches(Some((1920.0, 1080.0))));
+
+        assert!(MediaQueryList::parse("(aspect-ratio: 16/9)").matches(Some((3840.0, 2160.0))));
+        assert!(!MediaQueryList::parse("print").matches(Some((1920.0, 1080.0))));
+        assert!(MediaQueryList::parse("not print").matches(Some((1920.0, 1080.0))));
+        assert!(!MediaQueryList::parse("(hover: hover)").matches(Some((1920.0, 1080.0))));
+    }
+}
@@ -0,0 +1,457 @@
+//! # Templates
+//!
+//! Kompiliert den Text eines `TextNode` mit `{{ }}` Platzhaltern einmalig in Segmente.
+//!
+//! - `{{ city }}`, `{{ entry.city }}` – Wert aus dem Kontext, Pfade mit Punkten
+//! - `{{ temp | round(1) }}`, `{{ name | upper }}`, `{{ ts | date("%H:%M") }}` – Filter
+//! - `{{ city | default("unbekannt") }}` – Ersatz für fehlende oder leere Werte
+//! - `{{ "Text" }}` – Literal
+//! - `{{= temp * 1.8 + 32 | round }}` – Lua-Ausdruck, nur wenn ein Auswerter übergeben wird
+//! - `{{ state.temp }}` – Pfade in den reaktiven `state` werden wie Lua-Ausdrücke ausgewertet
+//!
+//! Filter werden mit `|` außerhalb von Klammern und Strings getrennt.
+//!
+//! Attribute können ebenfalls Platzhalter enthalten (`src="{{ icon }}"`) oder als
+//! `:name="lua_expr"` komplett an einen Lua-Ausdruck gebunden werden, siehe `AttrBinding`.
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use log::{debug, warn};
+
+/// Ein Wert für die Platzhalter. Zahlen und Wahrheitswerte behalten ihren Typ, damit
+/// Lua-Ausdrücke wie `{{= temp > 30 }}` mit ihnen rechnen können.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateValue {
+    Text(String),
+    Integer(i64),
+    Number(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for TemplateValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateValue::Text(text) => f.write_str(text),
+            TemplateValue::Integer(i) => write!(f, "{}", i),
+            // ganze Zahlen ohne `.0`, wie beim Umwandeln aus Lua
+            TemplateValue::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            TemplateValue::Number(n) => write!(f, "{}", n),
+            TemplateValue::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl From<&str> for TemplateValue {
+    fn from(text: &str) -> Self {
+        TemplateValue::Text(text.to_string())
+    }
+}
+
+impl From<String> for TemplateValue {
+    fn from(text: String) -> Self {
+        TemplateValue::Text(text)
+    }
+}
+
+/// Template-Werte nach Pfad, z. B. `entry.city`.
+pub type TemplateValues = HashMap<String, TemplateValue>;
+
+/// Wertet den Lua-Ausdruck aus `{{= ... }}` mit den Werten des Kontexts aus.
+pub type LuaEval<'a> = &'a dyn Fn(&str, &TemplateValues) -> Option<TemplateValue>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Path(String),
+    Literal(String),
+    Lua(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Upper,
+    Lower,
+    Trim,
+    Round(usize),
+    Truncate(usize),
+    /// Unix-Zeitstempel in Sekunden formatieren, Format und Versatz in Stunden zu UTC.
+    Date(String, i64),
+    Default(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Expr {
+    source: Source,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Expr(Expr),
+}
+
+/// Ein kompiliertes Template, wird im `TextNode` zwischengespeichert.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledTemplate {
+    segments: Vec<Segment>,
+}
+
+/// Bindung eines Attributs an Template-Werte.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttrBinding {
+    /// Attributwert mit `{{ }}` Platzhaltern.
+    Template(Rc<CompiledTemplate>),
+    /// `:name="lua_expr"`, `nil` und `false` entfernen das Attribut.
+    Lua(String),
+}
+
+impl AttrBinding {
+    /// Erzeugt die Bindung für ein Attribut aus dem HTML, falls es eine ist.
+    /// Gibt den Namen des gebundenen Attributs mit zurück.
+    pub fn parse(name: &str, value: &str) -> Option<(String, Self)> {
+        if let Some(name) = name.strip_prefix(':') {
+            return Some((name.to_string(), AttrBinding::Lua(value.trim().to_string())));
+        }
+        if value.contains("{{") {
+            return Some((name.to_string(), AttrBinding::Template(Rc::new(CompiledTemplate::parse(value)))));
+        }
+        None
+    }
+
+    /// Die Lua-Ausdrücke, von denen der Wert abhängt.
+    pub fn lua_exprs(&self) -> Vec<&str> {
+        match self {
+            AttrBinding::Template(template) => template.lua_exprs(),
+            AttrBinding::Lua(expr) => vec![expr.as_str()],
+        }
+    }
+
+    /// Berechnet den Attributwert, `Some(None)` entfernt das Attribut. Das passiert nur, wenn
+    /// eine Lua-Bindung `nil` oder `false` ergibt, der Text `"false"` bleibt stehen.
+    /// Ohne `eval` bleibt eine Lua-Bindung unausgewertet (`None`).
+    pub fn evaluate(&self, values: &TemplateValues, eval: Option<LuaEval>) -> Option<Option<String>> {
+        match self {
+            AttrBinding::Template(template) => Some(Some(template.render(values, eval))),
+            AttrBinding::Lua(expr) => {
+                let eval = eval?;
+                Some(eval(expr, values).filter(|v| *v != TemplateValue::Bool(false)).map(|v| v.to_string()))
+            }
+        }
+    }
+}
+
+/// Teilt `input` an `separator`, aber nicht innerhalb von Strings oder Klammern.
+fn split_top_level(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth -= 1,
+            (None, c) if c == separator && depth == 0 => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Entfernt die Anführungszeichen eines String-Literals.
+fn unquote(input: &str) -> Option<&str> {
+    let input = input.trim();
+    ["\"", "'"]
+        .iter()
+        .find_map(|q| input.strip_prefix(q).and_then(|s| s.strip_suffix(q)))
+}
+
+fn is_path(input: &str) -> bool {
+    !input.is_empty()
+        && input.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_'))
+}
+
+impl Filter {
+    fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        let (name, args) = match input.split_once('(') {
+            Some((name, rest)) => (name.trim(), split_top_level(rest.strip_suffix(')')?, ',')),
+            None => (input, Vec::new()),
+        };
+        let arg = |i: usize| args.get(i).map(|a| a.trim()).filter(|a| !a.is_empty());
+        let string_arg = |i: usize| arg(i).map(|a| unquote(a).unwrap_or(a).to_string());
+        let number_arg = |i: usize| arg(i).and_then(|a| a.parse::<i64>().ok());
+
+        match name {
+            "upper" => Some(Filter::Upper),
+            "lower" => Some(Filter::Lower),
+            "trim" => Some(Filter::Trim),
+            "round" => Some(Filter::Round(number_arg(0).unwrap_or(0).max(0) as usize)),
+            "truncate" => Some(Filter::Truncate(number_arg(0)?.max(0) as usize)),
+            "date" => Some(Filter::Date(
+                string_arg(0).unwrap_or_else(|| "%Y-%m-%d %H:%M".to_string()),
+                number_arg(1).unwrap_or(0),
+            )),
+            "default" => Some(Filter::Default(string_arg(0).unwrap_or_default())),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, value: Option<String>) -> Option<String> {
+        match self {
+            Filter::Default(default) => match value {
+                Some(v) if !v.is_empty() => Some(v),
+                _ => Some(default.clone()),
+            },
+            Filter::Upper => value.map(|v| v.to_uppercase()),
+            Filter::Lower => value.map(|v| v.to_lowercase()),
+            Filter::Trim => value.map(|v| v.trim().to_string()),
+            Filter::Truncate(len) => value.map(|v| {
+                if v.chars().count() > *len {
+                    let mut short: String = v.chars().take(*len).collect();
+                    short.push('…');
+                    short
+                } else {
+                    v
+                }
+            }),
+            // Werte, die keine Zahl sind, bleiben unverändert
+            Filter::Round(digits) => value.map(|v| match v.trim().parse::<f64>() {
+                Ok(n) => format!("{:.*}", digits, n),
+                Err(_) => v,
+            }),
+            // außerhalb von i64 bleibt der Wert unverändert
+            Filter::Date(format, offset_hours) => value.map(|v| {
+                let timestamp = v.trim().parse::<f64>().ok().and_then(|ts| {
+                    let offset = offset_hours.checked_mul(3600)?;
+                    (ts as i64).checked_add(offset)
+                });
+                match timestamp {
+                    Some(timestamp) => format_timestamp(timestamp, format),
+                    None => v,
+                }
+            }),
+        }
+    }
+}
+
+impl Expr {
+    fn parse(input: &str) -> Option<Self> {
+        let (input, lua) = match input.strip_prefix('=') {
+            Some(rest) => (rest, true),
+            None => (input, false),
+        };
+        let mut parts = split_top_level(input, '|').into_iter();
+        let head = parts.next()?.trim();
+
+        let source = if lua {
+            Source::Lua(head.to_string())
+        } else if let Some(literal) = unquote(head) {
+            Source::Literal(literal.to_string())
+        } else if head.starts_with("state.") && is_path(head) {
+            Source::Lua(head.to_string())
+        } else if is_path(head) {
+            Source::Path(head.to_string())
+        } else {
+            return None;
+        };
+
+        let mut filters = Vec::new();
+        for part in parts {
+            match Filter::parse(part) {
+                Some(filter) => filters.push(filter),
+                None => warn!("unknown template filter: {}", part.trim()),
+            }
+        }
+        Some(Expr { source, filters })
+    }
+
+    fn evaluate(&self, values: &TemplateValues, eval: Option<LuaEval>) -> String {
+        let value = match &self.source {
+            Source::Path(path) => values.get(path).map(|v| v.to_string()),
+            Source::Literal(literal) => Some(literal.clone()),
+            Source::Lua(expr) => eval.and_then(|eval| eval(expr, values)).map(|v| v.to_string()),
+        };
+        let value = self.filters.iter().fold(value, |value, filter| filter.apply(value));
+        value.unwrap_or_else(|| {
+            debug!("template value missing: {:?}", self.source);
+            String::new()
+        })
+    }
+}
+
+impl CompiledTemplate {
+    pub fn parse(source: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            let Some(len) = rest[start + 2..].find("}}") else {
+                break;
+            };
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let inner = &rest[start + 2..start + 2 + len];
+            match Expr::parse(inner.trim()) {
+                Some(expr) => segments.push(Segment::Expr(expr)),
+                // ungültige Platzhalter bleiben als Text stehen
+                None => segments.push(Segment::Text(rest[start..start + 4 + len].to_string())),
+            }
+            rest = &rest[start + 4 + len..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+        Self { segments }
+    }
+
+    /// Ein Template ohne Platzhalter, z. B. für generierten Inhalt.
+    pub fn literal(text: &str) -> Self {
+        Self { segments: vec![Segment::Text(text.to_string())] }
+    }
+
+    /// Die `{{= }}` Ausdrücke des Templates, sie können von Lua-Globals abhängen.
+    pub fn lua_exprs(&self) -> Vec<&str> {
+        self.segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Expr(Expr { source: Source::Lua(expr), .. }) => Some(expr.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn render(&self, values: &TemplateValues, eval: Option<LuaEval>) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Expr(expr) => expr.evaluate(values, eval),
+            })
+            .collect()
+    }
+}
+
+/// Tage seit 1970-01-01 in (Jahr, Monat, Tag), nach Howard Hinnant.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Formatiert einen Unix-Zeitstempel mit einer Teilmenge von strftime:
+/// `%Y %y %m %d %e %H %M %S %%`.
+fn format_timestamp(timestamp: i64, format: &str) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    let (hour, minute, second) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+    let mut output = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => output.push_str(&year.to_string()),
+            Some('y') => output.push_str(&format!("{:02}", year.rem_euclid(100))),
+            Some('m') => output.push_str(&format!("{:02}", month)),
+            Some('d') => output.push_str(&format!("{:02}", day)),
+            Some('e') => output.push_str(&day.to_string()),
+            Some('H') => output.push_str(&format!("{:02}", hour)),
+            Some('M') => output.push_str(&format!("{:02}", minute)),
+            Some('S') => output.push_str(&format!("{:02}", second)),
+            Some('%') => output.push('%'),
+            Some(other) => {
+                output.push('%');
+                output.push(other);
+            }
+            None => output.push('%'),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> TemplateValues {
+        let mut values = HashMap::new();
+        values.insert("city".to_string(), "Berlin".into());
+        values.insert("entry.temp".to_string(), TemplateValue::Number(21.456));
+        values.insert("ts".to_string(), TemplateValue::Integer(1700000000));
+        values
+    }
+
+    #[test]
+    fn test_render_paths_and_filters() {
+        let template = CompiledTemplate::parse("{{ city | upper }}: {{ entry.temp | round(1) }}°C");
+        assert_eq!(template.render(&values(), None), "BERLIN: 21.5°C");
+
+        let template = CompiledTemplate::parse("{{ ts | date(\"%d.%m.%Y %H:%M\") }} / {{ ts | date('%H:%M', 1) }}");
+        assert_eq!(template.render(&values(), None), "14.11.2023 22:13 / 23:13");
+
+        let mut values = values();
+        values.insert("huge".to_string(), TemplateValue::Number(1e300));
+        let template = CompiledTemplate::parse("{{ huge | date('%Y', 1) }}|{{ ts | date('%Y', 9223372036854775807) }}");
+        let raw = CompiledTemplate::parse("{{ huge }}|{{ ts }}");
+        assert_eq!(template.render(&values, None), raw.render(&values, None));
+    }
+
+    #[test]
+    fn test_defaults_and_missing_values() {
+        let template = CompiledTemplate::parse("{{ missing | default(\"n/a\") }}|{{ missing }}|{{ \"x | y\" }}");
+        assert_eq!(template.render(&values(), None), "n/a||x | y");
+
+        // ungültige Platzhalter bleiben stehen
+        let template = CompiledTemplate::parse("{{ not a path }} {{ city");
+        assert_eq!(template.render(&values(), None), "{{ not a path }} {{ city");
+    }
+
+    #[test]
+    fn test_lua_expressions() {
+        let template = CompiledTemplate::parse("{{= temp * 2 | round }}");
+        let eval = |expr: &str, _: &TemplateValues| {
+            assert_eq!(expr, "temp * 2");
+            Some(TemplateValue::Number(42.4))
+        };
+        assert_eq!(template.render(&values(), Some(&eval)), "42");
+        assert_eq!(template.render(&values(), None), "");
+        assert_eq!(template.lua_exprs(), vec!["temp * 2"]);
+        assert_eq!(CompiledTemplate::parse("{{ state.temp | round }}").lua_exprs(), vec!["state.temp"]);
+    }
+
+    #[test]
+    fn test_attr_bindings() {
+        let (name, binding) = AttrBinding::parse("src", "icons/{{ city | lower }}.png").unwrap();
+        assert_eq!(name, "src");
+        assert!(binding.lua_exprs().is_empty());
+        assert_eq!(binding.evaluate(&values(), None), Some(Some("icons/berlin.png".to_string())));
+
+        let (name, binding) = AttrBinding::parse(":class", "is_warm and 'hot' or 'cold'").unwrap();
+        assert_eq!(name, "class");
+        assert_eq!(binding.evaluate(&values(), None), None);
+        let eval = |_: &str, _: &TemplateValues| Some(TemplateValue::Bool(false));
+        assert_eq!(binding.evaluate(&values(), Some(&eval)), Some(None));
+        let eval = |_: &str, _: &TemplateValues| Some(TemplateValue::from("false"));
+        assert_eq!(binding.evaluate(&values(), Some(&eval)), Some(Some("false".to_string())));
+
+        assert!(AttrBinding::parse("class", "card").is_none());
+    }
+}
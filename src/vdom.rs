@@ -1,8 +1,8 @@
 use im::Vector;
 use ulid::Ulid;
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, rc::Rc};
 
-use crate::{document::{FindBy, FindByIdMut}, layout::NodeContext, styles::Style};
+use crate::{component::Scope, document::{FindBy, FindByIdMut}, layout::NodeContext, styles::{selector::PseudoElement, Style}, parser::parse_styles, template::{AttrBinding, CompiledTemplate, LuaEval, TemplateValues}};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum VNode {
@@ -16,7 +16,19 @@ pub struct ElementNode {
     pub id: Option<String>,
     pub tag: String,
     pub attrs: HashMap<String, String>,
+    /// Deklarationen aus dem `style` Attribut, Grundlage für die Kaskade.
+    pub inline_style: HashMap<String, String>,
+    /// Die Deklarationen aus dem HTML, wenn es auch eine `:style` Bindung gibt. Deren Werte
+    /// kommen bei jedem Auswerten hinzu.
+    pub static_style: HashMap<String, String>,
+    /// Attribute mit `{{ }}` Platzhaltern oder `:name` Lua-Bindung, nach Attributname.
+    pub bindings: HashMap<String, AttrBinding>,
+    /// Props einer Komponenten-Instanz und Herkunft von Slot-Inhalt, siehe `component`.
+    pub scope: Scope,
     pub style: Style,
+    /// Werte aus `animate()`. Sie gelten nach der Kaskade und bleiben auch nach dem Ende
+    /// der Animation stehen, siehe `Style::overlay`.
+    pub animated: Style,
     pub children: Vector<VNode>,
 }
 
@@ -27,8 +39,142 @@ pub struct TextNode {
     pub attrs: HashMap<String, String>,
     pub style: Style,
     pub template: String,
+    /// Kompilierte Form von `template`, Klone des Knotens teilen sie sich.
+    pub compiled: Rc<CompiledTemplate>,
     pub rendered: String,
+    /// Bei eingesetztem Slot-Inhalt die Anzahl der Komponenten, deren Werte übersprungen werden.
+    pub slot_depth: usize,
+}
+
+/// Masken für die IDs der Pseudo-Elemente. Der Zeitstempel-Anteil der ULID bleibt erhalten,
+/// nur der Zufallsanteil wird verändert.
+const BEFORE_ID_MASK: u128 = 0x0000_0000_0000_b3f0_4e00_0000_0000_0001;
+const AFTER_ID_MASK: u128 = 0x0000_0000_0000_af7e_4000_0000_0000_0002;
+
+/// Stabile ID des `::before` / `::after` Knotens eines Elements.
+pub fn pseudo_element_id(element_id: &Ulid, pseudo_element: PseudoElement) -> Ulid {
+    let mask = match pseudo_element {
+        PseudoElement::Before => BEFORE_ID_MASK,
+        PseudoElement::After => AFTER_ID_MASK,
+    };
+    Ulid(element_id.0 ^ mask)
+}
+
+/// Die gerenderten Texte des Baums in Dokumentreihenfolge.
+#[cfg(test)]
+pub(crate) fn texts(node: &VNode) -> Vec<String> {
+    match node {
+        VNode::Text(t) => vec![t.rendered.clone()],
+        VNode::Element(el) => el.children.iter().flat_map(texts).collect(),
+    }
+}
+
+impl ElementNode {
+    /// Wertet die Attribut-Bindungen aus, für die `filter` zutrifft.
+    /// Eine Bindung von `style` ergänzt die Deklarationen aus dem HTML und überschreibt sie
+    /// bei gleichem Namen, mit `set_css_var` gesetzte Custom Properties bleiben.
+    ///
+    /// Gibt `true` zurück, wenn sich ein Wert geändert hat und neu gestylt werden muss.
+    pub fn render_bindings(
+        &mut self,
+        ctx: &TemplateValues,
+        eval: Option<LuaEval>,
+        filter: impl Fn(&AttrBinding) -> bool,
+    ) -> bool {
+        let mut changed = false;
+        for (name, binding) in &self.bindings {
+            if !filter(binding) {
+                continue;
+            }
+            let Some(value) = binding.evaluate(ctx, eval) else {
+                continue;
+            };
+
+            if name == "style" {
+                let mut inline_style = self.static_style.clone();
+                inline_style.extend(parse_styles(&value.unwrap_or_default()));
+                for (key, value) in &self.inline_style {
+                    if key.starts_with("--") && !inline_style.contains_key(key) {
+                        inline_style.insert(key.clone(), value.clone());
+                    }
+                }
+                if inline_style != self.inline_style {
+                    self.inline_style = inline_style;
+                    changed = true;
+                }
+            } else if self.attrs.get(name) != value.as_ref() {
+                match value {
+                    Some(value) => self.attrs.insert(name.clone(), value),
+                    None => self.attrs.remove(name),
+                };
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Wert eines Attributs. `style` liefert die Inline-Deklarationen, sortiert nach Name.
+    pub fn get_attr(&self, name: &str) -> Option<String> {
+        match name {
+            "id" => self.id.clone(),
+            "style" if self.inline_style.is_empty() => None,
+            "style" => {
+                let mut declarations: Vec<String> = self.inline_style
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                declarations.sort();
+                Some(declarations.join("; "))
+            }
+            _ => self.attrs.get(name).cloned(),
+        }
+    }
+
+    /// Setzt ein Attribut, `None` entfernt es. Eine Bindung des Attributs entfällt dabei.
+    /// `style` ersetzt die Inline-Deklarationen, `id` auch die HTML-ID des Elements.
+    ///
+    /// Gibt `true` zurück, wenn sich ein Wert geändert hat und neu gestylt werden muss.
+    pub fn set_attr(&mut self, name: &str, value: Option<String>) -> bool {
+        self.bindings.remove(name);
+        if name == "style" {
+            self.static_style.clear();
+            let inline_style = parse_styles(value.as_deref().unwrap_or_default());
+            let changed = inline_style != self.inline_style;
+            self.inline_style = inline_style;
+            return changed;
+        }
+        if name == "id" {
+            self.id = value.clone();
+        }
+        if self.attrs.get(name) == value.as_ref() {
+            return false;
+        }
+        match value {
+            Some(value) => self.attrs.insert(name.to_string(), value),
+            None => self.attrs.remove(name),
+        };
+        true
+    }
 
+    /// Erzeugt den Text-Knoten für ein Pseudo-Element aus dem berechneten Style.
+    /// Der Knoten existiert nur für Layout und Rendering, im VDOM taucht er nicht auf
+    /// und ist damit auch nicht über `get_element_by_id` erreichbar.
+    pub fn pseudo_node(&self, pseudo_element: PseudoElement) -> Option<VNode> {
+        let generated = match pseudo_element {
+            PseudoElement::Before => self.style.before.as_ref(),
+            PseudoElement::After => self.style.after.as_ref(),
+        }?;
+        Some(VNode::Text(TextNode {
+            internal_id: pseudo_element_id(&self.internal_id, pseudo_element),
+            id: None,
+            attrs: HashMap::new(),
+            style: generated.style.clone(),
+            template: generated.text.clone(),
+            compiled: Rc::new(CompiledTemplate::literal(&generated.text)),
+            rendered: generated.text.clone(),
+            slot_depth: 0,
+        }))
+    }
 }
 
 #[derive(Debug)]
@@ -38,8 +184,11 @@ pub enum DiffOp {
         tag: String,
         changes: Vec<(String, Option<String>, Option<String>)>,
     },
+    ChangeStyle(Style),
     AddChild(usize, VNode),
     RemoveChild(usize),
+    /// Verschiebt ein Kind, beide Positionen beziehen sich auf die aktuelle Liste.
+    MoveChild { from: usize, to: usize },
     PatchChild(usize, Box<DiffOp>),
     Composite(Vec<DiffOp>),
 }
@@ -72,29 +221,11 @@ pub fn diff_vnode(old: &VNode, new: &VNode) -> Option<DiffOp> {
                 }
             }
 
-            let mut child_diffs = vec![];
+            let child_diffs = diff_children(&a.children, &b.children);
 
-            let max_len = a.children.len().max(b.children.len());
-            for i in 0..max_len {
-                let old_child = a.children.get(i);
-                let new_child = b.children.get(i);
-                match (old_child, new_child) {
-                    (Some(oc), Some(nc)) => {
-                        if let Some(diff) = diff_vnode(oc, nc) {
-                            child_diffs.push(DiffOp::PatchChild(i, Box::new(diff)));
-                        }
-                    }
-                    (None, Some(nc)) => {
-                        child_diffs.push(DiffOp::AddChild(i, nc.clone()));
-                    }
-                    (Some(_), None) => {
-                        child_diffs.push(DiffOp::RemoveChild(i));
-                    }
-                    (None, None) => {}
-                }
-            }
+            let style_changed = a.style != b.style;
 
-            if attr_changes.is_empty() && child_diffs.is_empty() {
+            if attr_changes.is_empty() && child_diffs.is_empty() && !style_changed {
                 None
             } else {
                 let mut ops = vec![];
@@ -105,6 +236,10 @@ pub fn diff_vnode(old: &VNode, new: &VNode) -> Option<DiffOp> {
                         changes: attr_changes,
                     });
                 }
+
+                if style_changed {
+                    ops.push(DiffOp::ChangeStyle(b.style.clone()));
+                }
             
                 ops.extend(child_diffs);
             
@@ -115,6 +250,83 @@ pub fn diff_vnode(old: &VNode, new: &VNode) -> Option<DiffOp> {
     }
 }
 
+/// Vergleicht die Kinder über ihre internen IDs. Knoten, die nur ihren Platz wechseln, z. B.
+/// umsortierte oder vorne eingefügte Listen-Instanzen, werden verschoben statt ersetzt.
+///
+/// Reihenfolge der Operationen: zuerst `PatchChild` mit den alten Positionen, dann
+/// `RemoveChild` von hinten nach vorn, dann `MoveChild` und `AddChild` nacheinander
+/// auf der jeweils aktuellen Liste.
+fn diff_children(old: &Vector<VNode>, new: &Vector<VNode>) -> Vec<DiffOp> {
+    let old_positions: HashMap<&Ulid, usize> = old
+        .iter()
+        .enumerate()
+        .map(|(i, child)| (child.get_internal_id(), i))
+        .collect();
+    let new_ids: HashSet<&Ulid> = new.iter().map(|child| child.get_internal_id()).collect();
+    // doppelte IDs lassen sich nicht zuordnen
+    if old_positions.len() != old.len() || new_ids.len() != new.len() {
+        return diff_children_by_position(old, new);
+    }
+
+    let mut ops = Vec::new();
+    for child in new {
+        if let Some(&i) = old_positions.get(child.get_internal_id()) {
+            if let Some(diff) = diff_vnode(&old[i], child) {
+                ops.push(DiffOp::PatchChild(i, Box::new(diff)));
+            }
+        }
+    }
+
+    let mut current: Vec<&Ulid> = old.iter().map(|child| child.get_internal_id()).collect();
+    for i in (0..current.len()).rev() {
+        if !new_ids.contains(current[i]) {
+            ops.push(DiffOp::RemoveChild(i));
+            current.remove(i);
+        }
+    }
+
+    for (i, child) in new.iter().enumerate() {
+        let id = child.get_internal_id();
+        if current.get(i) == Some(&id) {
+            continue;
+        }
+        match current.iter().position(|other| *other == id) {
+            Some(from) => {
+                ops.push(DiffOp::MoveChild { from, to: i });
+                let moved = current.remove(from);
+                current.insert(i, moved);
+            }
+            None => {
+                ops.push(DiffOp::AddChild(i, child.clone()));
+                current.insert(i, id);
+            }
+        }
+    }
+    ops
+}
+
+/// Vergleicht die Kinder Position für Position.
+fn diff_children_by_position(old: &Vector<VNode>, new: &Vector<VNode>) -> Vec<DiffOp> {
+    let mut ops = vec![];
+    let max_len = old.len().max(new.len());
+    for i in 0..max_len {
+        match (old.get(i), new.get(i)) {
+            (Some(oc), Some(nc)) => {
+                if let Some(diff) = diff_vnode(oc, nc) {
+                    ops.push(DiffOp::PatchChild(i, Box::new(diff)));
+                }
+            }
+            (None, Some(nc)) => ops.push(DiffOp::AddChild(i, nc.clone())),
+            _ => {}
+        }
+    }
+    // von hinten, damit die Positionen der übrigen Kinder gültig bleiben
+    for i in (new.len()..old.len()).rev() {
+        ops.push(DiffOp::RemoveChild(i));
+    }
+    ops
+}
+
 /// Wendet ein DiffOp auf einen VNode an.
 pub fn apply_patch(node: &VNode, op: &DiffOp) -> VNode {
     match op {
@@ -137,13 +349,27 @@ pub fn apply_patch(node: &VNode, op: &DiffOp) -> VNode {
                     id: elem.id.clone(),
                     tag: tag.clone(),
                     attrs: new_attrs,
+                    inline_style: elem.inline_style.clone(),
+                    static_style: elem.static_style.clone(),
+                    bindings: elem.bindings.clone(),
+                    scope: elem.scope.clone(),
                     style: elem.style.clone(),
+                    animated: elem.animated.clone(),
                     children: elem.children.clone(),
                 })
             } else {
                 node.clone()
             }
         }
+        DiffOp::ChangeStyle(style) => {
+            match node {
+                VNode::Element(elem) => VNode::Element(ElementNode {
+                    style: style.clone(),
+                    ..elem.clone()
+                }),
+                VNode::Text(_) => node.clone(),
+            }
+        }
         DiffOp::AddChild(index, child) => {
             if let VNode::Element(elem) = node {
                 let mut new_children = elem.children.clone();
@@ -153,7 +379,12 @@ pub fn apply_patch(node: &VNode, op: &DiffOp) -> VNode {
                     id: elem.id.clone(),
                     tag: elem.tag.clone(),
                     attrs: elem.attrs.clone(),
+                    inline_style: elem.inline_style.clone(),
+                    static_style: elem.static_style.clone(),
+                    bindings: elem.bindings.clone(),
+                    scope: elem.scope.clone(),
                     style: elem.style.clone(),
+                    animated: elem.animated.clone(),
                     children: new_children,
                 })
             } else {
@@ -169,13 +400,33 @@ pub fn apply_patch(node: &VNode, op: &DiffOp) -> VNode {
                     id: elem.id.clone(),
                     tag: elem.tag.clone(),
                     attrs: elem.attrs.clone(),
+                    inline_style: elem.inline_style.clone(),
+                    static_style: elem.static_style.clone(),
+                    bindings: elem.bindings.clone(),
+                    scope: elem.scope.clone(),
                     style: elem.style.clone(),
+                    animated: elem.animated.clone(),
                     children: new_children,
                 })
             } else {
                 node.clone()
             }
         }
+        DiffOp::MoveChild { from, to } => {
+            if let VNode::Element(elem) = node {
+                let mut new_children = elem.children.clone();
+                if *from < new_children.len() {
+                    let child = new_children.remove(*from);
+                    new_children.insert((*to).min(new_children.len()), child);
+                }
+                VNode::Element(ElementNode {
+                    children: new_children,
+                    ..elem.clone()
+                })
+            } else {
+                node.clone()
+            }
+        }
         DiffOp::PatchChild(index, subop) => {
             if let VNode::Element(elem) = node {
                 let mut new_children = elem.children.clone();
@@ -188,7 +439,12 @@ pub fn apply_patch(node: &VNode, op: &DiffOp) -> VNode {
                     id: elem.id.clone(),
                     tag: elem.tag.clone(),
                     attrs: elem.attrs.clone(),
+                    inline_style: elem.inline_style.clone(),
+                    static_style: elem.static_style.clone(),
+                    bindings: elem.bindings.clone(),
+                    scope: elem.scope.clone(),
                     style: elem.style.clone(),
+                    animated: elem.animated.clone(),
                     children: new_children,
                 })
             } else {
@@ -252,6 +508,27 @@ impl VNode {
         }
     }
 
+    /// Liefert die internen IDs aller Vorfahren des Knotens `id`, beginnend bei `self`.
+    pub fn ancestors_of(&self, id: &Ulid) -> Option<Vec<Ulid>> {
+        match self {
+            VNode::Element(el) => {
+                if &el.internal_id == id {
+                    return Some(Vec::new());
+                }
+                for child in &el.children {
+                    if let Some(mut path) = child.ancestors_of(id) {
+                        path.insert(0, el.internal_id);
+                        return Some(path);
+                    }
+                }
+                None
+            }
+            VNode::Text(t) => {
+                if &t.internal_id == id { Some(Vec::new()) } else { None }
+            }
+        }
+    }
+
     pub fn get_node_context(&self) -> NodeContext {
         match self {
             VNode::Element(_el) => NodeContext::Element,
@@ -267,6 +544,164 @@ impl VNode {
     }
 }
 
+/// Template-Werte beim Rendern, `outer` sind die Werte außerhalb der aktuellen Komponente.
+#[derive(Clone, Copy)]
+struct RenderScope<'a> {
+    values: &'a TemplateValues,
+    outer: Option<&'a RenderScope<'a>>,
+}
+
+impl RenderScope<'_> {
+    /// Eingesetzter Slot-Inhalt sieht die Werte `depth` Komponenten weiter außen.
+    fn escape(self, depth: usize) -> Self {
+        let mut scope = self;
+        for _ in 0..depth {
+            match scope.outer {
+                Some(outer) => scope = *outer,
+                None => break,
+            }
+        }
+        scope
+    }
+}
+
+/// Rendert die `{{ }}` Platzhalter in allen Text-Knoten und Attribut-Bindungen des Teilbaums neu,
+/// siehe `template` für die unterstützten Ausdrücke. `{{= }}` Ausdrücke und `:name` Bindungen
+/// werden nur mit `eval` ausgewertet. Komponenten-Instanzen im Teilbaum rendern mit ihren Props.
+///
+/// Gibt `true` zurück, wenn sich dabei ein Attribut geändert hat.
+pub fn render_texts_in_subtree(
+    node: &mut VNode,
+    ctx: &TemplateValues,
+    eval: Option<LuaEval>,
+) -> bool {
+    render_scoped(node, RenderScope { values: ctx, outer: None }, eval)
+}
+
+fn render_scoped(node: &mut VNode, scope: RenderScope<'_>, eval: Option<LuaEval>) -> bool {
+    match node {
+        VNode::Text(text) => {
+            let scope = scope.escape(text.slot_depth);
+            text.rendered = text.compiled.render(scope.values, eval);
+            false
+        }
+        VNode::Element(el) => {
+            let scope = scope.escape(el.scope.slot_depth);
+            match el.scope.props.clone() {
+                Some(props) => {
+                    let values = props.resolve(scope.values, eval);
+                    render_element(el, RenderScope { values: &values, outer: Some(&scope) }, eval)
+                }
+                None => render_element(el, scope, eval),
+            }
+        }
+    }
+}
+
+fn render_element(el: &mut ElementNode, scope: RenderScope<'_>, eval: Option<LuaEval>) -> bool {
+    let mut changed = el.render_bindings(scope.values, eval, |_| true);
+    for child in el.children.iter_mut() {
+        changed |= render_scoped(child, scope, eval);
+    }
+    changed
+}
+
+/// Prüft, ob im Teilbaum ein Platzhalter, eine Bindung oder ein Prop einen der Ausdrücke nutzt,
+/// für die `needs` zutrifft.
+fn subtree_needs(node: &VNode, needs: &dyn Fn(&str) -> bool) -> bool {
+    match node {
+        VNode::Text(text) => text.compiled.lua_exprs().into_iter().any(needs),
+        VNode::Element(el) => {
+            el.bindings.values().any(|b| b.lua_exprs().into_iter().any(needs))
+                || el.scope.props.as_ref().is_some_and(|props| props.lua_exprs().into_iter().any(needs))
+                || el.children.iter().any(|child| subtree_needs(child, needs))
+        }
+    }
+}
+
+/// Wertet die von Lua abhängigen Platzhalter und Bindungen im Teilbaum neu aus, sofern einer
+/// ihrer Ausdrücke laut `needs` neu berechnet werden muss.
+///
+/// `contexts` enthält die zuletzt gerenderten Template-Werte je Teilbaum, darunter gilt jeweils
+/// der Kontext des nächsten Vorfahren. Elemente mit geänderten Attributen landen in `changed`.
+pub fn render_lua_bindings(
+    node: &mut VNode,
+    contexts: &HashMap<Ulid, TemplateValues>,
+    ctx: &TemplateValues,
+    eval: LuaEval,
+    needs: &dyn Fn(&str) -> bool,
+    changed: &mut Vec<Ulid>,
+) {
+    render_lua_scoped(node, contexts, RenderScope { values: ctx, outer: None }, eval, needs, changed);
+}
+
+fn render_lua_scoped(
+    node: &mut VNode,
+    contexts: &HashMap<Ulid, TemplateValues>,
+    scope: RenderScope<'_>,
+    eval: LuaEval,
+    needs: &dyn Fn(&str) -> bool,
+    changed: &mut Vec<Ulid>,
+) {
+    match node {
+        VNode::Text(text) => {
+            if text.compiled.lua_exprs().into_iter().any(needs) {
+                let scope = scope.escape(text.slot_depth);
+                let rendered = text.compiled.render(scope.values, Some(eval));
+                if rendered != text.rendered {
+                    text.rendered = rendered;
+                }
+            }
+        }
+        VNode::Element(el) => {
+            let mut scope = scope.escape(el.scope.slot_depth);
+            if let Some(values) = contexts.get(&el.internal_id) {
+                scope = RenderScope { values, outer: scope.outer };
+            }
+            let Some(props) = el.scope.props.clone() else {
+                render_lua_element(el, contexts, scope, eval, needs, changed);
+                return;
+            };
+
+            let values = props.resolve(scope.values, Some(eval));
+            let inner = RenderScope { values: &values, outer: Some(&scope) };
+            if props.lua_exprs().into_iter().any(needs) {
+                // Props speisen auch normale Platzhalter, die Instanz wird vollständig neu gerendert
+                if render_element(el, inner, Some(eval)) {
+                    changed.push(el.internal_id);
+                }
+            } else {
+                render_lua_element(el, contexts, inner, eval, needs, changed);
+            }
+        }
+    }
+}
+
+fn render_lua_element(
+    el: &mut ElementNode,
+    contexts: &HashMap<Ulid, TemplateValues>,
+    scope: RenderScope<'_>,
+    eval: LuaEval,
+    needs: &dyn Fn(&str) -> bool,
+    changed: &mut Vec<Ulid>,
+) {
+    if el.render_bindings(scope.values, Some(eval), |b| b.lua_exprs().into_iter().any(needs)) {
+        changed.push(el.internal_id);
+    }
+    // nur betroffene Kinder veränderlich ausleihen, sonst kopiert im::Vector den Baum
+    let indices: Vec<usize> = el.children
+        .iter()
+        .enumerate()
+        .filter(|(_, child)| subtree_needs(child, needs))
+        .map(|(i, _)| i)
+        .collect();
+    for index in indices {
+        if let Some(child) = el.children.get_mut(index) {
+            render_lua_scoped(child, contexts, scope, eval, needs, changed);
+        }
+    }
+}
+
 impl FindByIdMut for VNode {
     fn find_by_internal_id_mut(&mut self, target: &Ulid) -> Option<&mut VNode> {
         if let VNode::Text(t) = self {